@@ -0,0 +1,122 @@
+use crate::order_book::*;
+use crate::types::*;
+use crate::utils::*;
+
+use ic_certified_map::{AsHashTree, RbTree};
+use ic_kit::{
+    candid::{encode_one, CandidType, Nat, Principal},
+    ic,
+};
+use serde::Serialize;
+
+// Listings, floors and balances are mirrored in a merkle tree whose root hash is the canister's
+// certified data, so a frontend can check a query answer against the subnet's signature before
+// prompting its user to sign. The tree is keyed by
+//
+// * `listing/{collection}/{token}` - the candid encoded `Listing`
+// * `floor/{collection}` - the candid encoded floor price
+// * `balance/{fungible}/{user}` - the candid encoded balance held by marketplace
+//
+// and has no leaf for an absent listing, floor or balance. The witness returned by a certified
+// query reveals the leaf at its key, or proves there is none. The tree is derived from the state,
+// and rebuilt on upgrade.
+
+pub(crate) fn listing_key(nft_canister_id: &Principal, token_id: &Nat) -> Vec<u8> {
+    format!("listing/{}/{}", nft_canister_id, token_id.0).into_bytes()
+}
+
+pub(crate) fn floor_key(nft_canister_id: &Principal) -> Vec<u8> {
+    format!("floor/{}", nft_canister_id).into_bytes()
+}
+
+pub(crate) fn balance_key(fungible_canister_id: &Principal, user: &Principal) -> Vec<u8> {
+    format!("balance/{}/{}", fungible_canister_id, user).into_bytes()
+}
+
+/// set or delete a leaf, without updating the certified data
+fn certify_value<T: CandidType>(key: Vec<u8>, value: Option<T>) {
+    certified_tree_mut(|tree| match value {
+        Some(value) => {
+            tree.insert(key, encode_one(value).unwrap());
+        }
+        None => {
+            tree.delete(&key);
+        }
+    });
+}
+
+fn commit_certified_data() {
+    let root_hash = certified_tree(|tree| tree.root_hash());
+    ic::set_certified_data(&root_hash);
+}
+
+/// certify the current listing of a token, and the floor of its collection
+pub(crate) fn certify_listing(nft_canister_id: &Principal, token_id: &Nat) {
+    let listing = marketplace(|mp| {
+        mp.listings
+            .get(nft_canister_id)
+            .and_then(|listings| listings.get(token_id))
+            .filter(|listing| listing.status != ListingStatus::Uninitialized)
+            .cloned()
+    });
+
+    certify_value(listing_key(nft_canister_id, token_id), listing);
+    certify_value(floor_key(nft_canister_id), best_ask(nft_canister_id));
+    commit_certified_data();
+}
+
+/// certify the current balance of a user
+pub(crate) fn certify_balance(fungible_canister_id: &Principal, user: &Principal) {
+    let balance = balances(|balances| {
+        balances
+            .balances
+            .get(&(*fungible_canister_id, *user))
+            .cloned()
+    });
+
+    certify_value(balance_key(fungible_canister_id, user), balance);
+    commit_certified_data();
+}
+
+/// rebuild the tree from the current listings and balances
+pub(crate) fn certify_all() {
+    certified_tree_mut(|tree| *tree = RbTree::new());
+
+    let listings: Vec<(Principal, Nat, Listing)> = marketplace(|mp| {
+        mp.listings
+            .iter()
+            .flat_map(|(nft_canister_id, listings)| {
+                listings
+                    .iter()
+                    .filter(|(_, listing)| listing.status != ListingStatus::Uninitialized)
+                    .map(move |(token_id, listing)| {
+                        (*nft_canister_id, token_id.clone(), listing.clone())
+                    })
+            })
+            .collect()
+    });
+    for (nft_canister_id, token_id, listing) in listings {
+        certify_value(listing_key(&nft_canister_id, &token_id), Some(listing));
+        certify_value(floor_key(&nft_canister_id), best_ask(&nft_canister_id));
+    }
+
+    let balances: Vec<((Principal, Principal), Nat)> =
+        balances(|balances| balances.balances.clone().into_iter().collect());
+    for ((fungible_canister_id, user), balance) in balances {
+        certify_value(balance_key(&fungible_canister_id, &user), Some(balance));
+    }
+
+    commit_certified_data();
+}
+
+/// the certificate of the certified data, with a cbor encoded witness of the leaf at `key`
+pub(crate) fn certified(key: Vec<u8>) -> (Option<Vec<u8>>, Vec<u8>) {
+    let witness = certified_tree(|tree| {
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer.self_describe().unwrap();
+        tree.witness(&key).serialize(&mut serializer).unwrap();
+        serializer.into_inner()
+    });
+
+    (ic::data_certificate(), witness)
+}
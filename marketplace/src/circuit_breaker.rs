@@ -0,0 +1,319 @@
+use crate::fungible_proxy::probe_fungible;
+use crate::non_fungible_proxy::probe_non_fungible;
+use crate::types::*;
+use crate::utils::*;
+
+use ic_kit::{candid::Principal, ic};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::future::Future;
+
+// Calls into a stopped, out of cycles or unreachable canister fail after consuming cycles and
+// user time. Every external canister gets a breaker, which opens after `BREAKER_THRESHOLD`
+// consecutive calls failing that way. While it is open calls into the canister fail fast, and
+// trading is paused on the collections using it. After `BREAKER_COOLDOWN` the heartbeat probes
+// the canister again, closing the breaker when it answers.
+//
+// A canister trapping or rejecting on its own is not counted, callers can make a healthy
+// canister do so with their input, eg; a nonexistent token id, and would pause trading for
+// everyone else.
+
+thread_local!(
+    // collections `probe_collections` is probing
+    static PROBING: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+);
+
+/// consecutive unhealthy calls after which a breaker opens
+pub const BREAKER_THRESHOLD: u32 = 5;
+
+/// how long a breaker stays open before its canister is probed again, in nanoseconds
+pub const BREAKER_COOLDOWN: u64 = 10 * 60 * 1_000_000_000;
+
+/// run a call into `canister` through its breaker
+///
+/// only calls showing the canister is unhealthy count as failures, see `is_unhealthy`
+pub(crate) async fn guarded<T>(
+    canister: &Principal,
+    call: impl Future<Output = Result<T, MPApiError>>,
+) -> Result<T, MPApiError> {
+    check_breaker(canister)?;
+
+    let res = call.await;
+    match &res {
+        Err(MPApiError::CanisterCallError { error, .. }) if is_unhealthy(canister, error) => {
+            record_failure(canister, error.clone())
+        }
+        _ => record_success(canister),
+    }
+
+    res
+}
+
+/// whether a failed call shows `canister` itself is unhealthy
+///
+/// system rejects count, and the rejects the system makes for a stopped or out of cycles
+/// canister. Traps and rejects of the canister's own code do not, whatever their input
+pub(crate) fn is_unhealthy(canister: &Principal, error: &CanisterError) -> bool {
+    let (code, message) = match error {
+        CanisterError::Rejected { code, message } => (code, message),
+        _ => return false,
+    };
+
+    match code {
+        RejectionKind::SysFatal
+        | RejectionKind::SysTransient
+        | RejectionKind::DestinationInvalid => true,
+        RejectionKind::CanisterReject | RejectionKind::CanisterError => {
+            // newer replicas prefix rejects with an error code, eg; `IC0508: `
+            let message = match message.split_once(": ") {
+                Some((code, rest)) if code.starts_with("IC") => rest,
+                _ => message.as_str(),
+            };
+
+            match message.strip_prefix(&format!("Canister {} is ", canister)) {
+                Some(state) => ["stopped", "stopping", "out of cycles"]
+                    .iter()
+                    .any(|unhealthy| state.starts_with(unhealthy)),
+                None => false,
+            }
+        }
+        RejectionKind::NoError | RejectionKind::Unknown => false,
+    }
+}
+
+/// fail fast when the breaker of `canister` is not closed
+pub(crate) fn check_breaker(canister: &Principal) -> Result<(), MPApiError> {
+    let status = breakers(|breakers| breakers.breakers.get(canister).map(|b| b.status));
+
+    match status {
+        Some(BreakerStatus::Open) | Some(BreakerStatus::Probing) => {
+            Err(MPApiError::CanisterPaused(*canister))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// fail when trading on a collection is paused, or its canisters were never probed
+pub(crate) fn ensure_trading(collection: &Collection) -> Result<(), MPApiError> {
+    if !collection.capabilities.probed {
+        return Err(MPApiError::CanisterPaused(collection.nft_canister_id));
+    }
+
+    match collection.status {
+        CollectionStatus::Active => Ok(()),
+        CollectionStatus::Paused { canister, .. } => Err(MPApiError::CanisterPaused(canister)),
+    }
+}
+
+/// status of a collection trading `nft_canister_id` for `fungible_canister_id`
+pub(crate) fn collection_status(
+    nft_canister_id: &Principal,
+    fungible_canister_id: &Principal,
+) -> CollectionStatus {
+    breakers(|breakers| {
+        [nft_canister_id, fungible_canister_id]
+            .iter()
+            .find_map(|canister| {
+                breakers
+                    .breakers
+                    .get(*canister)
+                    .filter(|breaker| breaker.status != BreakerStatus::Closed)
+                    .map(|breaker| CollectionStatus::Paused {
+                        canister: **canister,
+                        since: breaker.opened,
+                    })
+            })
+            .unwrap_or(CollectionStatus::Active)
+    })
+}
+
+fn record_failure(canister: &Principal, error: CanisterError) {
+    let tripped = breakers_mut(|breakers| {
+        let breaker = breakers
+            .breakers
+            .entry(*canister)
+            .or_insert_with(|| Breaker::new(BreakerStatus::Closed, 0, None, 0));
+
+        breaker.failures += 1;
+        breaker.last_error = Some(error);
+
+        if breaker.status == BreakerStatus::Closed && breaker.failures >= BREAKER_THRESHOLD {
+            breaker.status = BreakerStatus::Open;
+            breaker.opened = ic::time();
+            return true;
+        }
+
+        false
+    });
+
+    if tripped {
+        refresh_collections(canister);
+    }
+}
+
+fn record_success(canister: &Principal) {
+    breakers_mut(|breakers| {
+        if let Some(breaker) = breakers.breakers.get(canister) {
+            if breaker.status == BreakerStatus::Closed {
+                breakers.breakers.remove(canister);
+            }
+        }
+    });
+}
+
+/// recompute the status of every collection using `canister`
+fn refresh_collections(canister: &Principal) {
+    collections_mut(|collections| {
+        for collection in collections.values_mut().filter(|collection| {
+            collection.nft_canister_id == *canister || collection.fungible_canister_id == *canister
+        }) {
+            collection.status =
+                collection_status(&collection.nft_canister_id, &collection.fungible_canister_id);
+        }
+    });
+}
+
+/// probe the canisters whose breaker cooled down
+pub(crate) async fn reprobe_breakers() {
+    let now = ic::time();
+    let due: Vec<Principal> = breakers_mut(|breakers| {
+        breakers
+            .breakers
+            .iter_mut()
+            .filter(|(_, breaker)| {
+                breaker.status == BreakerStatus::Open
+                    && breaker.opened.saturating_add(BREAKER_COOLDOWN) <= now
+            })
+            .map(|(canister, breaker)| {
+                breaker.status = BreakerStatus::Probing;
+                *canister
+            })
+            .collect()
+    });
+
+    for canister in due {
+        reprobe(canister).await;
+    }
+}
+
+/// probe a canister with its standard, closing its breaker when it answers, reopening otherwise
+async fn reprobe(canister: Principal) {
+    let collection = collections(|collections| {
+        collections
+            .values()
+            .find(|collection| {
+                collection.nft_canister_id == canister
+                    || collection.fungible_canister_id == canister
+            })
+            .cloned()
+    });
+
+    let res = match collection {
+        Some(collection) if collection.nft_canister_id == canister => {
+            probe_non_fungible(&canister, collection.nft_canister_standard)
+                .await
+                .map(|_| ())
+        }
+        Some(collection) => {
+            probe_fungible(&canister, collection.fungible_canister_standard)
+                .await
+                .map(|_| ())
+        }
+        // no collection uses the canister anymore
+        None => Ok(()),
+    };
+
+    match res {
+        Ok(()) => {
+            breakers_mut(|breakers| breakers.breakers.remove(&canister));
+        }
+        Err(e) => open_breaker(&canister, e),
+    }
+
+    refresh_collections(&canister);
+}
+
+/// open the breaker of `canister` after its probe failed
+fn open_breaker(canister: &Principal, e: MPApiError) {
+    breakers_mut(|breakers| {
+        let breaker = breakers
+            .breakers
+            .entry(*canister)
+            .or_insert_with(|| Breaker::new(BreakerStatus::Closed, 0, None, 0));
+
+        breaker.status = BreakerStatus::Open;
+        breaker.opened = ic::time();
+        breaker.last_error = Some(match e {
+            MPApiError::CanisterCallError { error, .. }
+            | MPApiError::StandardMismatch {
+                reason: MismatchReason::Failed(error),
+                ..
+            } => error,
+            other => CanisterError::reply(other),
+        });
+    });
+}
+
+/// probe the canisters of active collections that were never probed, recording their capabilities
+///
+/// collections migrated from the baseline release were registered without a probe, trading on
+/// them is paused until they are probed. A canister failing its probe gets its breaker opened,
+/// until `reprobe_breakers` finds it answering and the collection is probed again
+pub(crate) async fn probe_collections() {
+    let unprobed = |collection: &Collection| {
+        !collection.capabilities.probed && collection.status == CollectionStatus::Active
+    };
+    let due: Vec<Principal> = collections(|collections| {
+        collections
+            .values()
+            .filter(|collection| unprobed(collection))
+            .map(|collection| collection.nft_canister_id)
+            .collect()
+    });
+
+    for nft_canister_id in due {
+        // probes outlast a heartbeat, collections probed or being probed since are skipped
+        let collection = collections(|collections| collections.get(&nft_canister_id).cloned())
+            .filter(|collection| unprobed(collection))
+            .filter(|_| PROBING.with(|probing| probing.borrow_mut().insert(nft_canister_id)));
+
+        if let Some(collection) = collection {
+            probe_collection(collection).await;
+            PROBING.with(|probing| probing.borrow_mut().remove(&nft_canister_id));
+        }
+    }
+}
+
+async fn probe_collection(collection: Collection) {
+    let nft_canister_id = collection.nft_canister_id;
+    let fungible_canister_id = collection.fungible_canister_id;
+
+    let probed = match probe_non_fungible(&nft_canister_id, collection.nft_canister_standard).await
+    {
+        Err(e) => Err((nft_canister_id, e)),
+        Ok((nft_interfaces, approvals)) => {
+            match probe_fungible(&fungible_canister_id, collection.fungible_canister_standard).await
+            {
+                Ok(transfer_fee) => Ok(CollectionCapabilities::new(
+                    nft_interfaces,
+                    approvals,
+                    transfer_fee,
+                    true,
+                )),
+                Err(e) => Err((fungible_canister_id, e)),
+            }
+        }
+    };
+
+    match probed {
+        Ok(capabilities) => collections_mut(|collections| {
+            if let Some(registered) = collections.get_mut(&nft_canister_id) {
+                registered.capabilities = capabilities;
+            }
+        }),
+        Err((canister, e)) => {
+            open_breaker(&canister, e);
+            refresh_collections(&canister);
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! In-memory fake canisters, registered in place of the proxies with `register_fungible_adapter`
+//! and `register_nft_adapter`
+//!
+//! Fakes keep their state behind `RefCell`s, tests keep an `Rc` to the fake they registered to
+//! set it up and inspect it. A rejecting fake rejects every call, the way a stopped canister does.
+
+use crate::fungible_proxy::{Dip20Proxy, FungibleAdapter, IcpProxy, IcrcProxy};
+use crate::non_fungible_proxy::{NonFungibleAdapter, ProbeResult, PropertiesResult};
+use crate::types::*;
+use crate::utils::{account_identifier_bytes, call_error, convert_nat_to_u64, deposit_subaccount};
+use crate::vendor_types::{
+    Account, GenericValue, IcpTransferArgs, IcpTransferError, Tokens, TransferError,
+};
+
+use ic_kit::{
+    candid::{Nat, Principal},
+    ic, RejectionCode,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+fn rejected(contract: &Principal, method: &str) -> MPApiError {
+    call_error(
+        contract,
+        method,
+        (
+            RejectionCode::CanisterError,
+            format!("IC0508: Canister {} is stopped", contract),
+        ),
+    )
+}
+
+/// A fungible ledger of a given standard
+///
+/// Requests are built with the proxy of the standard and applied the way its ledger applies them.
+/// The sender pays the fee on top of what is sent, out of the allowance for DIP20 and ICRC2
+/// payments. ICRC and ICP ledgers reject transfers declaring a fee other than theirs. The ICP
+/// ledger keeps balances by account identifier and has no allowances, users pay from their
+/// deposit account, a subaccount of marketplace
+pub struct FakeLedger {
+    pub standard: FungibleStandard,
+    pub fee: Nat,
+    pub balances: RefCell<HashMap<Principal, Nat>>,
+    // (owner, spender)
+    pub allowances: RefCell<HashMap<(Principal, Principal), Nat>>,
+    // ICP balances, by account identifier
+    pub accounts: RefCell<HashMap<Vec<u8>, Nat>>,
+    pub rejecting: Cell<bool>,
+    // methods called, in order
+    pub calls: RefCell<Vec<&'static str>>,
+}
+
+fn get<K: Eq + Hash>(accounts: &RefCell<HashMap<K, Nat>>, key: &K) -> Nat {
+    accounts.borrow().get(key).cloned().unwrap_or_default()
+}
+
+fn credit<K: Clone + Eq + Hash>(accounts: &RefCell<HashMap<K, Nat>>, key: &K, amount: &Nat) {
+    *accounts.borrow_mut().entry(key.clone()).or_default() += amount.clone();
+}
+
+fn debit<K: Clone + Eq + Hash>(
+    accounts: &RefCell<HashMap<K, Nat>>,
+    key: &K,
+    amount: &Nat,
+) -> Result<(), MPApiError> {
+    let mut accounts = accounts.borrow_mut();
+    let balance = accounts.entry(key.clone()).or_default();
+    if &*balance < amount {
+        return Err(MPApiError::InsufficientFungibleBalance);
+    }
+
+    *balance = balance.clone() - amount.clone();
+    Ok(())
+}
+
+impl FakeLedger {
+    pub fn new(standard: FungibleStandard, fee: u64) -> Self {
+        FakeLedger {
+            standard,
+            fee: Nat::from(fee),
+            balances: RefCell::new(HashMap::new()),
+            allowances: RefCell::new(HashMap::new()),
+            accounts: RefCell::new(HashMap::new()),
+            rejecting: Cell::new(false),
+            calls: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn icp_account(owner: &Principal, subaccount: Option<[u8; 32]>) -> Vec<u8> {
+        account_identifier_bytes(owner, subaccount).to_vec()
+    }
+
+    /// balance of a user's main account
+    pub fn balance(&self, owner: &Principal) -> Nat {
+        match self.standard {
+            FungibleStandard::ICP => get(&self.accounts, &Self::icp_account(owner, None)),
+            _ => get(&self.balances, owner),
+        }
+    }
+
+    pub fn mint(&self, owner: &Principal, amount: u64) {
+        match self.standard {
+            FungibleStandard::ICP => credit(
+                &self.accounts,
+                &Self::icp_account(owner, None),
+                &Nat::from(amount),
+            ),
+            _ => credit(&self.balances, owner, &Nat::from(amount)),
+        }
+    }
+
+    /// approve marketplace to spend `amount` of the owner's funds
+    pub fn approve(&self, owner: &Principal, amount: u64) {
+        self.allowances
+            .borrow_mut()
+            .insert((*owner, ic::id()), Nat::from(amount));
+    }
+
+    fn deposit_account(owner: &Principal) -> Vec<u8> {
+        Self::icp_account(&ic::id(), Some(deposit_subaccount(owner)))
+    }
+
+    /// transfer `amount` to the user's deposit account, as the user would
+    pub fn deposit(&self, owner: &Principal, amount: u64) {
+        credit(
+            &self.accounts,
+            &Self::deposit_account(owner),
+            &Nat::from(amount),
+        );
+    }
+
+    pub fn deposit_of(&self, owner: &Principal) -> Nat {
+        get(&self.accounts, &Self::deposit_account(owner))
+    }
+
+    fn call(&self, contract: &Principal, method: &'static str) -> Result<(), MPApiError> {
+        self.calls.borrow_mut().push(method);
+        if self.rejecting.get() {
+            return Err(rejected(contract, method));
+        }
+
+        Ok(())
+    }
+
+    /// ICRC1 and ICP ledgers reject a transfer declaring another fee than theirs
+    fn check_fee(&self, contract: &Principal, declared: &Nat) -> Result<(), MPApiError> {
+        if *declared != self.fee {
+            return Err(match self.standard {
+                FungibleStandard::ICP => call_error(
+                    contract,
+                    "transfer",
+                    IcpTransferError::BadFee {
+                        expected_fee: Tokens {
+                            e8s: convert_nat_to_u64(self.fee.clone()).unwrap(),
+                        },
+                    },
+                ),
+                _ => call_error(
+                    contract,
+                    "icrc1_transfer",
+                    TransferError::BadFee {
+                        expected_fee: self.fee.clone(),
+                    },
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// the user an ICRC account belongs to
+    fn icrc_owner(&self, account: &Account) -> Result<Principal, MPApiError> {
+        IcrcProxy::principal_of(account)
+            .ok_or_else(|| MPApiError::Other(format!("{:?} is not a user's account", account)))
+    }
+
+    /// the sender pays the fee on top of the value sent
+    fn send(&self, from: &Principal, to: &Principal, value: &Nat) -> NatResult {
+        debit(&self.balances, from, &(value.clone() + self.fee.clone()))?;
+        credit(&self.balances, to, value);
+        Ok(Nat::from(0))
+    }
+
+    fn send_icp(&self, contract: &Principal, args: IcpTransferArgs) -> NatResult {
+        self.check_fee(contract, &Nat::from(args.fee.e8s))?;
+        let from_subaccount = args.from_subaccount.map(|bytes| {
+            let mut subaccount = [0; 32];
+            subaccount.copy_from_slice(&bytes);
+            subaccount
+        });
+
+        debit(
+            &self.accounts,
+            &Self::icp_account(&ic::id(), from_subaccount),
+            &(Nat::from(args.amount.e8s) + Nat::from(args.fee.e8s)),
+        )?;
+        credit(&self.accounts, &args.to, &Nat::from(args.amount.e8s));
+        Ok(Nat::from(0))
+    }
+
+    /// DIP20 and ICRC2 payments, the fee is charged out of the allowance with the amount
+    fn transfer_from_allowance(&self, from: &Principal, to: &Principal, amount: &Nat) -> NatResult {
+        let (from, to, amount) = match self.standard {
+            FungibleStandard::ICRC1 | FungibleStandard::ICRC2 => {
+                let args = IcrcProxy::transfer_from_args(from, to, amount);
+                (
+                    self.icrc_owner(&args.from)?,
+                    self.icrc_owner(&args.to)?,
+                    args.amount,
+                )
+            }
+            _ => (*from, *to, amount.clone()),
+        };
+
+        let spender = ic::id();
+        let allowance = get(&self.allowances, &(from, spender));
+        let charged = amount.clone() + self.fee.clone();
+        if allowance < charged {
+            return Err(MPApiError::InsufficientFungibleAllowance);
+        }
+
+        self.send(&from, &to, &amount)?;
+        self.allowances
+            .borrow_mut()
+            .insert((from, spender), allowance - charged);
+        Ok(Nat::from(0))
+    }
+
+    fn transfer_sync(
+        &self,
+        contract: &Principal,
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+    ) -> NatResult {
+        self.call(contract, "transfer")?;
+
+        match self.standard {
+            FungibleStandard::DIP20 => {
+                let (to, value) = Dip20Proxy::transfer_args(to, amount, fee)?;
+                self.send(&ic::id(), &to, &value)
+            }
+            FungibleStandard::ICRC1 | FungibleStandard::ICRC2 => {
+                let arg = IcrcProxy::transfer_arg(to, amount, fee)?;
+                self.check_fee(contract, &arg.fee.unwrap_or_default())?;
+                self.send(&ic::id(), &self.icrc_owner(&arg.to)?, &arg.amount)
+            }
+            FungibleStandard::ICP => {
+                self.send_icp(contract, IcpProxy::transfer_args(to, amount, fee)?)
+            }
+        }
+    }
+}
+
+impl FungibleAdapter for FakeLedger {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move {
+            self.call(contract, "transfer_from")?;
+            match self.standard {
+                FungibleStandard::ICP => {
+                    let fee = IcpProxy::fee(contract).await?;
+                    self.send_icp(
+                        contract,
+                        IcpProxy::transfer_from_args(from, to, amount, fee)?,
+                    )
+                }
+                _ => self.transfer_from_allowance(from, to, amount),
+            }
+        })
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+        fee: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move { self.transfer_sync(contract, to, amount, fee) })
+    }
+
+    fn balance_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move {
+            self.call(contract, "balance_of")?;
+            match self.standard {
+                FungibleStandard::ICP => Ok(self.deposit_of(owner)),
+                _ => Ok(self.balance(owner)),
+            }
+        })
+    }
+
+    fn allowance<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+        spender: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move {
+            self.call(contract, "allowance")?;
+            match self.standard {
+                FungibleStandard::ICP => Ok(self.deposit_of(owner)),
+                _ => Ok(get(&self.allowances, &(*owner, *spender))),
+            }
+        })
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move {
+            self.call(contract, "probe")?;
+            Ok(self.fee.clone())
+        })
+    }
+
+    fn refund_deposit<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move {
+            if self.standard != FungibleStandard::ICP {
+                return Err(MPApiError::Other(format!(
+                    "{} is not paid through deposit accounts",
+                    contract
+                )));
+            }
+
+            self.call(contract, "refund_deposit")?;
+            let fee = IcpProxy::fee(contract).await?;
+            let deposit = convert_nat_to_u64(self.deposit_of(owner)).map_err(MPApiError::Other)?;
+            self.send_icp(contract, IcpProxy::refund_args(owner, deposit, fee)?)
+        })
+    }
+}
+
+/// An nft canister
+///
+/// `transfer_from` needs marketplace to be the token's operator, and clears the operator, as a
+/// DIP721 approval is used up by the transfer
+pub struct FakeNft {
+    pub owners: RefCell<HashMap<Nat, Principal>>,
+    pub operators: RefCell<HashMap<Nat, Principal>>,
+    pub properties: RefCell<HashMap<Nat, Vec<(String, GenericValue)>>>,
+    pub interfaces: Vec<String>,
+    pub approvals: bool,
+    pub rejecting: Cell<bool>,
+    // methods called, in order
+    pub calls: RefCell<Vec<&'static str>>,
+}
+
+impl FakeNft {
+    pub fn new(interfaces: &[&str], approvals: bool) -> Self {
+        FakeNft {
+            owners: RefCell::new(HashMap::new()),
+            operators: RefCell::new(HashMap::new()),
+            properties: RefCell::new(HashMap::new()),
+            interfaces: interfaces.iter().map(|s| s.to_string()).collect(),
+            approvals,
+            rejecting: Cell::new(false),
+            calls: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn mint(&self, token_id: u64, owner: &Principal) {
+        self.owners.borrow_mut().insert(Nat::from(token_id), *owner);
+    }
+
+    /// approve marketplace for a token, as its owner would
+    pub fn approve(&self, token_id: u64) {
+        self.operators
+            .borrow_mut()
+            .insert(Nat::from(token_id), ic::id());
+    }
+
+    pub fn owner(&self, token_id: u64) -> Option<Principal> {
+        self.owners.borrow().get(&Nat::from(token_id)).cloned()
+    }
+
+    fn call(&self, contract: &Principal, method: &'static str) -> Result<(), MPApiError> {
+        self.calls.borrow_mut().push(method);
+        if self.rejecting.get() {
+            return Err(rejected(contract, method));
+        }
+
+        Ok(())
+    }
+
+    fn transfer_from_sync(
+        &self,
+        contract: &Principal,
+        from: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+    ) -> NatResult {
+        self.call(contract, "transfer_from")?;
+        if self.operators.borrow().get(token_id) != Some(&ic::id()) {
+            return Err(MPApiError::InvalidOperator);
+        }
+        if self.owners.borrow().get(token_id) != Some(from) {
+            return Err(MPApiError::InvalidOwner);
+        }
+
+        self.owners.borrow_mut().insert(token_id.clone(), *to);
+        self.operators.borrow_mut().remove(token_id);
+        Ok(Nat::from(0))
+    }
+
+    fn transfer_sync(&self, contract: &Principal, to: &Principal, token_id: &Nat) -> NatResult {
+        self.call(contract, "transfer")?;
+        if self.owners.borrow().get(token_id) != Some(&ic::id()) {
+            return Err(MPApiError::InvalidOwner);
+        }
+
+        self.owners.borrow_mut().insert(token_id.clone(), *to);
+        Ok(Nat::from(0))
+    }
+}
+
+impl NonFungibleAdapter for FakeNft {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move { self.transfer_from_sync(contract, from, to, token_id) })
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move { self.transfer_sync(contract, to, token_id) })
+    }
+
+    fn owner_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(async move {
+            self.call(contract, "owner_of")?;
+            Ok(self.owners.borrow().get(token_id).cloned())
+        })
+    }
+
+    fn operator_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(async move {
+            self.call(contract, "operator_of")?;
+            Ok(self.operators.borrow().get(token_id).cloned())
+        })
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, ProbeResult> {
+        Box::pin(async move {
+            self.call(contract, "probe")?;
+            Ok((self.interfaces.clone(), self.approvals))
+        })
+    }
+
+    fn properties<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PropertiesResult> {
+        Box::pin(async move {
+            self.call(contract, "properties")?;
+            Ok(self
+                .properties
+                .borrow()
+                .get(token_id)
+                .cloned()
+                .unwrap_or_default())
+        })
+    }
+}
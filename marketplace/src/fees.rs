@@ -0,0 +1,402 @@
+//! Fee arithmetic
+//!
+//! Fees are e2 percentages of the price, so `250` is 2.50%. Rounding follows these rules:
+//!
+//! 1. The total fee is `ceil(price * total rate / 10000)`, capped at the price. Rounding up means
+//!    a trade with a non zero fee schedule never settles with zero fees.
+//! 2. The total fee is allocated to each fee in proportion to its rate, rounding down. What
+//!    rounding leaves over goes to the collection fee, or to the first fee charged when the
+//!    collection charges none, so small prices do not lose the royalty to rounding.
+//! 3. The referral share and the treasury splits of the protocol fee are rounded down. What the
+//!    treasury splits leave over is dust, it is tracked per fungible token and can be swept to
+//!    the marketplace owner by controllers.
+//!
+//! The seller receives the price minus the total fee, so the seller payout, all fee credits and
+//! the dust always add up to the price exactly.
+
+use crate::utils::COLLECTION_FEE_LABEL;
+use crate::utils::PROTOCOL_FEE_LABEL;
+use crate::utils::REFERRAL_FEE_LABEL;
+
+use ic_kit::candid::{Nat, Principal};
+
+pub const FEE_DENOMINATOR: u64 = 10000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeSplit {
+    pub seller_amount: Nat,
+    // (fee purpose, recipient, amount)
+    pub credits: Vec<(String, Principal, Nat)>,
+    pub dust: Nat,
+}
+
+impl FeeSplit {
+    pub fn total_fee(&self) -> Nat {
+        self.credits
+            .iter()
+            .fold(self.dust.clone(), |total, (_, _, amount)| {
+                total + amount.clone()
+            })
+    }
+}
+
+/// split `price` between the seller and the fee recipients
+///
+/// * `fees` - Vec of fees, (string fee purpose, principal of fee recipient, percent (e2))
+/// * `referral` - optional (referrer, share of the protocol fee (e2))
+/// * `treasury_splits` - treasuries the protocol fee is split between, (purpose, principal, share (e2))
+pub fn split_fees(
+    price: &Nat,
+    fees: &[(String, Principal, Nat)],
+    referral: Option<(Principal, Nat)>,
+    treasury_splits: &[(String, Principal, Nat)],
+) -> FeeSplit {
+    let zero = Nat::from(0);
+    let denominator = Nat::from(FEE_DENOMINATOR);
+
+    let total_rate = fees
+        .iter()
+        .fold(zero.clone(), |total, (_, _, rate)| total + rate.clone());
+
+    if total_rate == zero || price == &zero {
+        return FeeSplit {
+            seller_amount: price.clone(),
+            credits: Vec::new(),
+            dust: zero,
+        };
+    }
+
+    let total_fee = ceil_div(price.clone() * total_rate.clone(), denominator.clone());
+    let total_fee = if &total_fee > price {
+        price.clone()
+    } else {
+        total_fee
+    };
+
+    let mut amounts: Vec<Nat> = fees
+        .iter()
+        .map(|(_, _, rate)| (total_fee.clone() * rate.clone()) / total_rate.clone())
+        .collect();
+    let allocated = amounts
+        .iter()
+        .fold(zero.clone(), |total, amount| total + amount.clone());
+    let charged = |rate: &Nat| rate > &zero;
+    let remainder_to = fees
+        .iter()
+        .position(|(fee_label, _, rate)| fee_label == COLLECTION_FEE_LABEL && charged(rate))
+        .or_else(|| fees.iter().position(|(_, _, rate)| charged(rate)));
+    // some fee is charged, the total rate is not zero
+    if let Some(index) = remainder_to {
+        amounts[index] += total_fee.clone() - allocated;
+    }
+
+    let mut credits = Vec::new();
+
+    for ((fee_label, recipient, _), amount) in fees.iter().zip(amounts) {
+        if fee_label != PROTOCOL_FEE_LABEL {
+            credits.push((fee_label.clone(), *recipient, amount));
+            continue;
+        }
+
+        let mut protocol_amount = amount;
+
+        if let Some((referrer, share)) = &referral {
+            let referral_amount = (protocol_amount.clone() * share.clone()) / denominator.clone();
+            protocol_amount = protocol_amount - referral_amount.clone();
+
+            credits.push((REFERRAL_FEE_LABEL.to_string(), *referrer, referral_amount));
+        }
+
+        if treasury_splits.is_empty() {
+            credits.push((fee_label.clone(), *recipient, protocol_amount));
+            continue;
+        }
+
+        for (purpose, treasury, share) in treasury_splits {
+            let split = (protocol_amount.clone() * share.clone()) / denominator.clone();
+            credits.push((purpose.clone(), *treasury, split));
+        }
+    }
+
+    let credited = credits
+        .iter()
+        .fold(zero, |total, (_, _, amount)| total + amount.clone());
+
+    FeeSplit {
+        seller_amount: price.clone() - total_fee.clone(),
+        credits,
+        // treasury splits round down, whatever they left behind of the protocol fee
+        dust: total_fee - credited,
+    }
+}
+
+fn ceil_div(numerator: Nat, denominator: Nat) -> Nat {
+    (numerator + denominator.clone() - Nat::from(1)) / denominator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::COLLECTION_FEE_LABEL;
+    use ic_kit::mock_principals::{alice, bob, john, xtc};
+    use num_bigint::BigUint;
+
+    fn fee(label: &str, recipient: Principal, rate: u64) -> (String, Principal, Nat) {
+        (label.to_string(), recipient, Nat::from(rate))
+    }
+
+    /// xorshift64, so the randomized tests are reproducible from their seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+
+        /// mostly small prices, where rounding matters most, and some up to u64::MAX
+        fn price(&mut self) -> u64 {
+            match self.below(4) {
+                0 => self.below(10),
+                1 => self.below(10_000),
+                2 => self.below(1_000_000_000_000),
+                _ => self.next(),
+            }
+        }
+
+        /// `count` shares (e2) adding up to 10000
+        fn shares(&mut self, count: u64) -> Vec<u64> {
+            let mut cuts: Vec<u64> = (1..count)
+                .map(|_| self.below(FEE_DENOMINATOR + 1))
+                .collect();
+            cuts.push(0);
+            cuts.push(FEE_DENOMINATOR);
+            cuts.sort_unstable();
+            cuts.windows(2).map(|cut| cut[1] - cut[0]).collect()
+        }
+    }
+
+    fn assert_conserved(price: &Nat, split: &FeeSplit) {
+        assert_eq!(
+            split.seller_amount.clone() + split.total_fee(),
+            price.clone(),
+            "payouts, fees and dust do not add up to the price: {:?}",
+            split
+        );
+    }
+
+    #[test]
+    fn random_splits_add_up_to_the_price() {
+        let recipients = [alice(), bob(), john(), xtc()];
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+        for _ in 0..10_000 {
+            let price = Nat::from(rng.price());
+
+            let mut fees = vec![fee(PROTOCOL_FEE_LABEL, alice(), rng.below(5_001))];
+            for index in 0..rng.below(4) {
+                fees.push(fee(
+                    COLLECTION_FEE_LABEL,
+                    recipients[index as usize],
+                    rng.below(FEE_DENOMINATOR + 1),
+                ));
+            }
+
+            let referral = match rng.below(2) {
+                0 => None,
+                _ => Some((bob(), Nat::from(rng.below(FEE_DENOMINATOR + 1)))),
+            };
+
+            let split_count = rng.below(4);
+            let treasury_splits: Vec<_> = match split_count {
+                0 => Vec::new(),
+                _ => rng
+                    .shares(split_count)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, share)| fee("Treasury", recipients[index], share))
+                    .collect(),
+            };
+
+            let split = split_fees(&price, &fees, referral, &treasury_splits);
+            assert_conserved(&price, &split);
+
+            let total_rate = fees
+                .iter()
+                .fold(Nat::from(0), |total, (_, _, rate)| total + rate.clone());
+            if total_rate >= Nat::from(FEE_DENOMINATOR) {
+                assert_eq!(split.seller_amount, Nat::from(0));
+            } else if total_rate > Nat::from(0) && price > Nat::from(0) {
+                assert!(split.total_fee() > Nat::from(0));
+            }
+        }
+    }
+
+    #[test]
+    fn small_prices_keep_the_royalty() {
+        let fees = [
+            fee(PROTOCOL_FEE_LABEL, alice(), 300),
+            fee(COLLECTION_FEE_LABEL, xtc(), 300),
+        ];
+        let treasury_splits = [
+            fee("Operations", bob(), 3_334),
+            fee("Grants", john(), 3_333),
+            fee("Reserve", xtc(), 3_333),
+        ];
+
+        // price * total rate < 10000, the total fee rounds up to 1
+        for price in 1..10u64 {
+            let price = Nat::from(price);
+            let split = split_fees(
+                &price,
+                &fees,
+                Some((bob(), Nat::from(5_000))),
+                &treasury_splits,
+            );
+
+            assert_conserved(&price, &split);
+            assert_eq!(split.seller_amount, price - Nat::from(1));
+            // allocation cannot split 1 two ways, the royalty takes it rather than dust
+            assert!(split.credits.contains(&fee(COLLECTION_FEE_LABEL, xtc(), 1)));
+            assert_eq!(split.dust, Nat::from(0));
+        }
+    }
+
+    #[test]
+    fn rounding_goes_to_the_first_fee_without_a_collection_fee() {
+        let fees = [
+            fee(PROTOCOL_FEE_LABEL, alice(), 100),
+            fee(COLLECTION_FEE_LABEL, xtc(), 0),
+            fee("Other", john(), 100),
+        ];
+
+        let split = split_fees(&Nat::from(3), &fees, None, &[]);
+
+        assert_eq!(
+            split.credits,
+            vec![
+                fee(PROTOCOL_FEE_LABEL, alice(), 1),
+                fee(COLLECTION_FEE_LABEL, xtc(), 0),
+                fee("Other", john(), 0),
+            ]
+        );
+        assert_eq!(split.seller_amount, Nat::from(2));
+    }
+
+    #[test]
+    fn total_fee_is_capped_at_the_price() {
+        let fees = [
+            fee(PROTOCOL_FEE_LABEL, alice(), 8_000),
+            fee(COLLECTION_FEE_LABEL, xtc(), 7_000),
+        ];
+
+        let split = split_fees(&Nat::from(1_000), &fees, None, &[]);
+
+        assert_conserved(&Nat::from(1_000), &split);
+        assert_eq!(split.seller_amount, Nat::from(0));
+        assert_eq!(
+            split.credits,
+            vec![
+                fee(PROTOCOL_FEE_LABEL, alice(), 533),
+                fee(COLLECTION_FEE_LABEL, xtc(), 467),
+            ]
+        );
+        assert_eq!(split.dust, Nat::from(0));
+    }
+
+    #[test]
+    fn prices_near_u64_max_are_split_exactly() {
+        let fees = [
+            fee(PROTOCOL_FEE_LABEL, alice(), 250),
+            fee(COLLECTION_FEE_LABEL, xtc(), 1_000),
+        ];
+
+        for price in [u64::MAX, u64::MAX - 1, u64::MAX / 2 + 1].iter() {
+            let split = split_fees(
+                &Nat::from(*price),
+                &fees,
+                Some((bob(), Nat::from(3_333))),
+                &[],
+            );
+
+            let total_fee = (*price as u128 * 1_250 + 10_000 - 1) / 10_000;
+            assert_eq!(split.total_fee(), Nat::from(total_fee as u64));
+            assert_eq!(split.seller_amount, Nat::from(*price - total_fee as u64));
+            assert_conserved(&Nat::from(*price), &split);
+        }
+    }
+
+    #[test]
+    fn prices_beyond_u64_are_split_exactly() {
+        // a billion tokens with 18 decimals
+        let price = Nat::from(BigUint::from(10u32).pow(27));
+        let fees = [
+            fee(PROTOCOL_FEE_LABEL, alice(), 250),
+            fee(COLLECTION_FEE_LABEL, xtc(), 750),
+        ];
+        let treasury_splits = [
+            fee("Operations", bob(), 6_000),
+            fee("Grants", john(), 4_000),
+        ];
+
+        let split = split_fees(&price, &fees, None, &treasury_splits);
+
+        let tokens = |amount: u64| Nat::from(BigUint::from(amount) * BigUint::from(10u32).pow(18));
+        assert_eq!(split.seller_amount, tokens(900_000_000));
+        assert_eq!(
+            split.credits,
+            vec![
+                ("Operations".to_string(), bob(), tokens(15_000_000)),
+                ("Grants".to_string(), john(), tokens(10_000_000)),
+                (COLLECTION_FEE_LABEL.to_string(), xtc(), tokens(75_000_000)),
+            ]
+        );
+        assert_eq!(split.dust, Nat::from(0));
+    }
+
+    #[test]
+    fn treasury_splits_are_credited_under_their_own_label() {
+        let fees = [fee(PROTOCOL_FEE_LABEL, alice(), 200)];
+        let treasury_splits = [fee("Operations", bob(), 7500), fee("Grants", john(), 2500)];
+
+        let split = split_fees(&Nat::from(10_000), &fees, None, &treasury_splits);
+
+        assert_eq!(split.seller_amount, Nat::from(9_800));
+        assert_eq!(
+            split.credits,
+            vec![fee("Operations", bob(), 150), fee("Grants", john(), 50)]
+        );
+        assert_eq!(split.dust, Nat::from(0));
+    }
+
+    #[test]
+    fn protocol_fee_goes_to_its_recipient_without_treasury_splits() {
+        let fees = [
+            fee(PROTOCOL_FEE_LABEL, alice(), 200),
+            fee(COLLECTION_FEE_LABEL, xtc(), 500),
+        ];
+
+        let split = split_fees(
+            &Nat::from(10_000),
+            &fees,
+            Some((bob(), Nat::from(2_000))),
+            &[],
+        );
+
+        assert_eq!(split.seller_amount, Nat::from(9_300));
+        assert_eq!(
+            split.credits,
+            vec![
+                fee(REFERRAL_FEE_LABEL, bob(), 40),
+                fee(PROTOCOL_FEE_LABEL, alice(), 160),
+                fee(COLLECTION_FEE_LABEL, xtc(), 500),
+            ]
+        );
+    }
+}
@@ -1,16 +1,113 @@
+use crate::circuit_breaker::guarded;
 use crate::types::*;
-use crate::utils::convert_nat_to_u64;
+use crate::utils::{
+    account_identifier_bytes, call_error, convert_nat_to_u64, deposit_subaccount, icrc_subaccounts,
+    recorded_transfer_fee, standard_mismatch,
+};
 use crate::vendor_types::*;
 
 use ic_kit::{
     candid::{Nat, Principal},
     ic, RejectionCode,
 };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Calls into a fungible canister of a given standard
+///
+/// Every standard has an adapter registered for it, endpoints go through the registry so
+/// implementations can be swapped, eg; for in-memory fakes. See `register_fungible_adapter`.
+/// Calls through the registry are guarded by the canister's breaker, probes are not.
+pub trait FungibleAdapter {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+    ) -> BoxFuture<'a, NatResult>;
+
+    /// transfer funds held by marketplace itself
+    ///
+    /// marketplace is the sender and pays the ledger fee, `fee` is deducted from the amount sent
+    /// so it is not paid out of funds owed to other users
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+        fee: &'a Nat,
+    ) -> BoxFuture<'a, NatResult>;
+
+    fn balance_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult>;
+
+    fn allowance<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+        spender: &'a Principal,
+    ) -> BoxFuture<'a, NatResult>;
+
+    /// verify the canister implements the standard, returns its transfer fee
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, NatResult>;
+
+    /// refund what is left of a user's deposit account, standards paid through allowances
+    /// have none
+    fn refund_deposit<'a>(
+        &'a self,
+        contract: &'a Principal,
+        _owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move {
+            NatResult::Err(MPApiError::Other(format!(
+                "{} is not paid through deposit accounts",
+                contract
+            )))
+        })
+    }
+}
+
+thread_local!(
+    static FUNGIBLE_ADAPTERS: RefCell<HashMap<FungibleStandard, Rc<dyn FungibleAdapter>>> =
+        RefCell::new(default_fungible_adapters());
+);
+
+fn default_fungible_adapters() -> HashMap<FungibleStandard, Rc<dyn FungibleAdapter>> {
+    let mut adapters: HashMap<FungibleStandard, Rc<dyn FungibleAdapter>> = HashMap::new();
+    adapters.insert(FungibleStandard::DIP20, Rc::new(Dip20Proxy {}));
+    adapters.insert(FungibleStandard::ICRC1, Rc::new(Icrc1Proxy {}));
+    adapters.insert(FungibleStandard::ICRC2, Rc::new(IcrcProxy {}));
+    adapters.insert(FungibleStandard::ICP, Rc::new(IcpProxy {}));
+    adapters
+}
+
+/// replace the adapter used for a standard
+pub(crate) fn register_fungible_adapter(
+    standard: FungibleStandard,
+    adapter: Rc<dyn FungibleAdapter>,
+) {
+    FUNGIBLE_ADAPTERS.with(|adapters| {
+        adapters.borrow_mut().insert(standard, adapter);
+    });
+}
 
-pub struct FungibleProxy {}
+pub(crate) fn fungible_adapter(
+    standard: FungibleStandard,
+) -> Result<Rc<dyn FungibleAdapter>, MPApiError> {
+    FUNGIBLE_ADAPTERS.with(|adapters| {
+        adapters
+            .borrow()
+            .get(&standard)
+            .cloned()
+            .ok_or_else(|| MPApiError::Other(format!("no adapter registered for {:?}", standard)))
+    })
+}
 
-// dynamic dispatch through trait objects is not implemented in rust for
-// async functions, so we do the dispatch manually
 pub async fn transfer_from_fungible(
     from: &Principal,
     to: &Principal,
@@ -18,19 +115,25 @@ pub async fn transfer_from_fungible(
     contract: &Principal,
     fungible_canister_standard: FungibleStandard,
 ) -> NatResult {
-    match fungible_canister_standard {
-        FungibleStandard::DIP20 => Dip20Proxy::transfer_from(from, to, amount, contract).await,
-    }
+    let adapter = fungible_adapter(fungible_canister_standard)?;
+    guarded(contract, adapter.transfer_from(contract, from, to, amount)).await
 }
+
+/// transfer funds held by marketplace, less the transfer fee recorded for the canister
 pub async fn transfer_fungible(
     to: &Principal,
     amount: &Nat,
     contract: &Principal,
     fungible_canister_standard: FungibleStandard,
 ) -> NatResult {
-    match fungible_canister_standard {
-        FungibleStandard::DIP20 => Dip20Proxy::transfer(to, amount, contract).await,
-    }
+    let adapter = fungible_adapter(fungible_canister_standard.clone())?;
+    let fee = match recorded_transfer_fee(contract) {
+        Some(fee) => fee,
+        // funds left over from a removed collection
+        None => probe_fungible(contract, fungible_canister_standard).await?,
+    };
+
+    guarded(contract, adapter.transfer(contract, to, amount, &fee)).await
 }
 
 pub async fn balance_of_fungible(
@@ -38,9 +141,8 @@ pub async fn balance_of_fungible(
     owner: &Principal,
     fungible_canister_standard: FungibleStandard,
 ) -> NatResult {
-    match fungible_canister_standard {
-        FungibleStandard::DIP20 => Dip20Proxy::balance_of(contract, owner).await,
-    }
+    let adapter = fungible_adapter(fungible_canister_standard)?;
+    guarded(contract, adapter.balance_of(contract, owner)).await
 }
 
 pub async fn allowance_fungible(
@@ -49,9 +151,26 @@ pub async fn allowance_fungible(
     spender: &Principal,
     fungible_canister_standard: FungibleStandard,
 ) -> NatResult {
-    match fungible_canister_standard {
-        FungibleStandard::DIP20 => Dip20Proxy::allowance(contract, owner, spender).await,
-    }
+    let adapter = fungible_adapter(fungible_canister_standard)?;
+    guarded(contract, adapter.allowance(contract, owner, spender)).await
+}
+
+pub async fn refund_deposit_fungible(
+    contract: &Principal,
+    owner: &Principal,
+    fungible_canister_standard: FungibleStandard,
+) -> NatResult {
+    let adapter = fungible_adapter(fungible_canister_standard)?;
+    guarded(contract, adapter.refund_deposit(contract, owner)).await
+}
+
+pub async fn probe_fungible(
+    contract: &Principal,
+    fungible_canister_standard: FungibleStandard,
+) -> NatResult {
+    fungible_adapter(fungible_canister_standard)?
+        .probe(contract)
+        .await
 }
 
 pub(crate) struct Dip20Proxy {}
@@ -67,25 +186,44 @@ impl Dip20Proxy {
             ic::call(*contract, "transferFrom", (*from, *to, amount.clone())).await;
 
         call_res
-            .map_err(|err| MPApiError::TransferFromFungibleError(format!("{:?}", err)))?
+            .map_err(|err| call_error(contract, "transferFrom", err))?
             .0
-            .map_err(|err| MPApiError::TransferFromFungibleError(format!("{:?}", err)))
+            .map_err(|err| call_error(contract, "transferFrom", err))
+    }
+
+    /// arguments of `transfer`, DIP20 charges the fee on top of the value sent, so `amount` is
+    /// sent less the fee
+    pub fn transfer_args(
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+    ) -> Result<(Principal, Nat), MPApiError> {
+        if amount <= fee {
+            return Err(MPApiError::TransferFungibleError);
+        }
+
+        Ok((*to, amount.clone() - fee.clone()))
     }
 
-    pub async fn transfer(to: &Principal, amount: &Nat, contract: &Principal) -> NatResult {
+    pub async fn transfer(
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
         let call_res: Result<(TxReceipt,), (RejectionCode, String)> =
-            ic::call(*contract, "transfer", (*to, amount.clone())).await;
+            ic::call(*contract, "transfer", Self::transfer_args(to, amount, fee)?).await;
         call_res
-            .map_err(|_| MPApiError::TransferFungibleError)?
+            .map_err(|err| call_error(contract, "transfer", err))?
             .0
-            .map_err(|_| MPApiError::TransferFungibleError)
+            .map_err(|err| call_error(contract, "transfer", err))
     }
 
     pub async fn balance_of(contract: &Principal, owner: &Principal) -> NatResult {
         let call_res: Result<(Nat,), (RejectionCode, String)> =
             ic::call(*contract, "balanceOf", (*owner,)).await;
         call_res
-            .map_err(|_| MPApiError::TransferFungibleError)
+            .map_err(|err| call_error(contract, "balanceOf", err))
             .map(|res| res.0)
     }
 
@@ -98,7 +236,560 @@ impl Dip20Proxy {
             ic::call(*contract, "allowance", (*owner, *spender)).await;
 
         call_res
-            .map_err(|_| MPApiError::InsufficientFungibleAllowance)
+            .map_err(|err| call_error(contract, "allowance", err))
+            .map(|res| res.0)
+    }
+
+    pub async fn probe(contract: &Principal) -> NatResult {
+        let call_res: Result<(Dip20Metadata,), (RejectionCode, String)> =
+            ic::call(*contract, "getMetadata", ()).await;
+
+        call_res
+            .map_err(|err| standard_mismatch(contract, FungibleStandard::DIP20, err))
+            .map(|res| res.0.fee)
+    }
+}
+
+impl FungibleAdapter for Dip20Proxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, amount, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+        fee: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(to, amount, fee, contract))
+    }
+
+    fn balance_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::balance_of(contract, owner))
+    }
+
+    fn allowance<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+        spender: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::allowance(contract, owner, spender))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::probe(contract))
+    }
+}
+
+/// ICRC2 ledgers
+///
+/// Marketplace works with principals, which map to their default account, or to the subaccount
+/// they set with `setIcrcSubaccount`. The ledger fee is paid by the sender. For `transfer_from`
+/// that is the buyer, on top of the price. For `transfer` marketplace is the sender, so the fee is
+/// deducted from the amount sent, otherwise marketplace would pay it out of funds owed to other
+/// users.
+pub(crate) struct IcrcProxy {}
+
+impl IcrcProxy {
+    /// the account a principal pays from, is paid into and holds ICRC7 tokens in
+    pub fn account(owner: &Principal) -> Account {
+        Account {
+            owner: *owner,
+            subaccount: icrc_subaccounts(|icrc_subaccounts| icrc_subaccounts.get(owner).cloned()),
+        }
+    }
+
+    /// the principal an account maps to, `None` for subaccounts the principal did not set
+    pub fn principal_of(account: &Account) -> Option<Principal> {
+        let default_subaccount = |subaccount: &Option<Subaccount>| {
+            subaccount
+                .as_ref()
+                .map_or(true, |subaccount| subaccount.iter().all(|byte| *byte == 0))
+        };
+        let expected = Self::account(&account.owner);
+
+        let matches = match default_subaccount(&account.subaccount) {
+            true => default_subaccount(&expected.subaccount),
+            false => account.subaccount == expected.subaccount,
+        };
+        matches.then(|| account.owner)
+    }
+
+    pub fn no_allowances(contract: &Principal) -> MPApiError {
+        standard_mismatch(
+            contract,
+            FungibleStandard::ICRC1,
+            "marketplace takes payments through ICRC2 allowances".to_string(),
+        )
+    }
+
+    pub fn transfer_from_args(from: &Principal, to: &Principal, amount: &Nat) -> TransferFromArgs {
+        TransferFromArgs {
+            spender_subaccount: None,
+            from: Self::account(from),
+            to: Self::account(to),
+            amount: amount.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    pub async fn transfer_from(
+        from: &Principal,
+        to: &Principal,
+        amount: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        let call_res: Result<(TransferFromResult,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "icrc2_transfer_from",
+            (Self::transfer_from_args(from, to, amount),),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "icrc2_transfer_from", err))?
+            .0
+            .map_err(|err| call_error(contract, "icrc2_transfer_from", err))
+    }
+
+    /// arguments of `icrc1_transfer`, sending `amount` less the fee. The fee is declared, so the
+    /// ledger rejects the transfer when its fee changed since it was recorded
+    pub fn transfer_arg(
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+    ) -> Result<TransferArg, MPApiError> {
+        if amount <= fee {
+            return Err(MPApiError::TransferFungibleError);
+        }
+
+        Ok(TransferArg {
+            from_subaccount: None,
+            to: Self::account(to),
+            amount: amount.clone() - fee.clone(),
+            fee: Some(fee.clone()),
+            memo: None,
+            created_at_time: None,
+        })
+    }
+
+    pub async fn transfer(
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        let call_res: Result<(TransferResult,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "icrc1_transfer",
+            (Self::transfer_arg(to, amount, fee)?,),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "icrc1_transfer", err))?
+            .0
+            .map_err(|err| call_error(contract, "icrc1_transfer", err))
+    }
+
+    pub async fn fee(contract: &Principal) -> NatResult {
+        let call_res: Result<(Nat,), (RejectionCode, String)> =
+            ic::call(*contract, "icrc1_fee", ()).await;
+
+        call_res
+            .map_err(|err| call_error(contract, "icrc1_fee", err))
             .map(|res| res.0)
     }
+
+    pub async fn balance_of(contract: &Principal, owner: &Principal) -> NatResult {
+        let call_res: Result<(Nat,), (RejectionCode, String)> =
+            ic::call(*contract, "icrc1_balance_of", (Self::account(owner),)).await;
+
+        call_res
+            .map_err(|err| call_error(contract, "icrc1_balance_of", err))
+            .map(|res| res.0)
+    }
+
+    pub async fn allowance(
+        contract: &Principal,
+        owner: &Principal,
+        spender: &Principal,
+    ) -> NatResult {
+        let call_res: Result<(Allowance,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "icrc2_allowance",
+            (AllowanceArgs {
+                account: Self::account(owner),
+                spender: Self::account(spender),
+            },),
+        )
+        .await;
+
+        let allowance = call_res
+            .map_err(|err| call_error(contract, "icrc2_allowance", err))?
+            .0;
+
+        // an expired approval is no approval
+        match allowance.expires_at {
+            Some(expires_at) if expires_at <= ic::time() => Ok(Nat::from(0)),
+            _ => Ok(allowance.allowance),
+        }
+    }
+
+    pub async fn probe(contract: &Principal) -> NatResult {
+        Self::probe_standard(contract, FungibleStandard::ICRC2, "ICRC-2").await
+    }
+
+    /// verify `name` is among the ledger's supported standards, returns its transfer fee
+    pub async fn probe_standard(
+        contract: &Principal,
+        standard: FungibleStandard,
+        name: &str,
+    ) -> NatResult {
+        let call_res: Result<(Vec<SupportedStandard>,), (RejectionCode, String)> =
+            ic::call(*contract, "icrc1_supported_standards", ()).await;
+
+        let standards = call_res
+            .map_err(|err| standard_mismatch(contract, standard.clone(), err))?
+            .0;
+
+        if !standards.iter().any(|s| s.name == name) {
+            return Err(standard_mismatch(
+                contract,
+                standard,
+                format!("{} is not a supported standard", name),
+            ));
+        }
+
+        Self::fee(contract).await
+    }
+}
+
+impl FungibleAdapter for IcrcProxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, amount, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+        fee: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(to, amount, fee, contract))
+    }
+
+    fn balance_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::balance_of(contract, owner))
+    }
+
+    fn allowance<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+        spender: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::allowance(contract, owner, spender))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::probe(contract))
+    }
+}
+
+/// ICRC1 ledgers without ICRC2. Marketplace cannot take payments without allowances, so their
+/// collections are rejected at registration, ICRC1 ledgers that implement ICRC2 are registered
+/// as `ICRC2`
+pub(crate) struct Icrc1Proxy {}
+
+impl FungibleAdapter for Icrc1Proxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        _from: &'a Principal,
+        _to: &'a Principal,
+        _amount: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move { NatResult::Err(IcrcProxy::no_allowances(contract)) })
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+        fee: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(IcrcProxy::transfer(to, amount, fee, contract))
+    }
+
+    fn balance_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(IcrcProxy::balance_of(contract, owner))
+    }
+
+    fn allowance<'a>(
+        &'a self,
+        contract: &'a Principal,
+        _owner: &'a Principal,
+        _spender: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move { NatResult::Err(IcrcProxy::no_allowances(contract)) })
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, NatResult> {
+        Box::pin(async move { NatResult::Err(IcrcProxy::no_allowances(contract)) })
+    }
+}
+
+/// The ICP ledger, which has no allowances
+///
+/// Users pay by transferring ICP to their deposit account, a subaccount of marketplace derived
+/// from their principal (see `getDepositAccount`). The deposit stands in for the allowance and
+/// balance of other standards, and `transfer_from` sweeps from it into marketplace's main account.
+/// The ledger fee is paid out of the deposit on sweeps, and deducted from the amount on transfers.
+/// Transfers declare the fee recorded for the ledger, which rejects them if it has changed.
+pub(crate) struct IcpProxy {}
+
+impl IcpProxy {
+    pub const MEMO: u64 = 0;
+
+    /// the fee recorded for the ledger, or its current fee when no collection trades in it
+    pub async fn fee(contract: &Principal) -> U64Result {
+        let fee = match recorded_transfer_fee(contract) {
+            Some(fee) => fee,
+            None => Self::probe(contract).await?,
+        };
+
+        convert_nat_to_u64(fee).map_err(MPApiError::Other)
+    }
+
+    /// arguments sweeping `amount` from the deposit account of `from` to `to`
+    pub fn transfer_from_args(
+        from: &Principal,
+        to: &Principal,
+        amount: &Nat,
+        fee: u64,
+    ) -> Result<IcpTransferArgs, MPApiError> {
+        let amount =
+            convert_nat_to_u64(amount.clone()).map_err(MPApiError::TransferFromFungibleError)?;
+
+        Ok(Self::send_args(
+            Some(deposit_subaccount(from)),
+            to,
+            amount,
+            fee,
+        ))
+    }
+
+    /// arguments sending `amount` held by marketplace to `to`, less the fee
+    pub fn transfer_args(
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+    ) -> Result<IcpTransferArgs, MPApiError> {
+        if amount <= fee {
+            return Err(MPApiError::TransferFungibleError);
+        }
+        let amount = convert_nat_to_u64(amount.clone() - fee.clone())
+            .map_err(|_| MPApiError::TransferFungibleError)?;
+        let fee = convert_nat_to_u64(fee.clone()).map_err(|_| MPApiError::TransferFungibleError)?;
+
+        Ok(Self::send_args(None, to, amount, fee))
+    }
+
+    /// arguments refunding a whole deposit to the main account of its owner, less the fee
+    pub fn refund_args(
+        owner: &Principal,
+        deposit: u64,
+        fee: u64,
+    ) -> Result<IcpTransferArgs, MPApiError> {
+        if deposit <= fee {
+            return Err(MPApiError::InsufficientFungibleBalance);
+        }
+
+        Ok(Self::send_args(
+            Some(deposit_subaccount(owner)),
+            owner,
+            deposit - fee,
+            fee,
+        ))
+    }
+
+    pub async fn transfer_from(
+        from: &Principal,
+        to: &Principal,
+        amount: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        let fee = Self::fee(contract).await?;
+
+        Self::send(Self::transfer_from_args(from, to, amount, fee)?, contract).await
+    }
+
+    pub async fn transfer(
+        to: &Principal,
+        amount: &Nat,
+        fee: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        Self::send(Self::transfer_args(to, amount, fee)?, contract).await
+    }
+
+    /// refund a user's whole deposit to their main account, less the ledger fee
+    pub async fn refund_deposit(owner: &Principal, contract: &Principal) -> NatResult {
+        let fee = Self::fee(contract).await?;
+        let deposit = Self::deposit_of(contract, owner).await?;
+
+        Self::send(Self::refund_args(owner, deposit, fee)?, contract).await
+    }
+
+    /// balance of a user's deposit account
+    pub async fn balance_of(contract: &Principal, owner: &Principal) -> NatResult {
+        Self::deposit_of(contract, owner).await.map(Nat::from)
+    }
+
+    /// the deposit stands in for the allowance, it pays both the price and the ledger fee,
+    /// which trades add to the price (see `payment_fee`)
+    pub async fn allowance(
+        contract: &Principal,
+        owner: &Principal,
+        _spender: &Principal,
+    ) -> NatResult {
+        Self::deposit_of(contract, owner).await.map(Nat::from)
+    }
+
+    async fn deposit_of(contract: &Principal, owner: &Principal) -> U64Result {
+        let call_res: Result<(Tokens,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "account_balance",
+            (AccountBalanceArgs {
+                account: account_identifier_bytes(&ic::id(), Some(deposit_subaccount(owner)))
+                    .to_vec(),
+            },),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "account_balance", err))
+            .map(|res| res.0.e8s)
+    }
+
+    /// arguments of `transfer`, sending `amount` from a subaccount of marketplace to the main
+    /// account of `to`, the ledger charges the fee on top
+    fn send_args(
+        from_subaccount: Option<[u8; 32]>,
+        to: &Principal,
+        amount: u64,
+        fee: u64,
+    ) -> IcpTransferArgs {
+        IcpTransferArgs {
+            memo: Self::MEMO,
+            amount: Tokens { e8s: amount },
+            fee: Tokens { e8s: fee },
+            from_subaccount: from_subaccount.map(|subaccount| subaccount.to_vec()),
+            to: account_identifier_bytes(to, None).to_vec(),
+            created_at_time: None,
+        }
+    }
+
+    async fn send(args: IcpTransferArgs, contract: &Principal) -> NatResult {
+        let call_res: Result<(IcpTransferResult,), (RejectionCode, String)> =
+            ic::call(*contract, "transfer", (args,)).await;
+
+        call_res
+            .map_err(|err| call_error(contract, "transfer", err))?
+            .0
+            .map_err(|err| call_error(contract, "transfer", err))
+            .map(Nat::from)
+    }
+
+    /// ICRC1 ledgers do not answer the legacy `transfer_fee`, which sets the ICP ledger apart
+    pub async fn probe(contract: &Principal) -> NatResult {
+        let call_res: Result<(TransferFee,), (RejectionCode, String)> =
+            ic::call(*contract, "transfer_fee", (TransferFeeArg {},)).await;
+
+        call_res
+            .map_err(|err| standard_mismatch(contract, FungibleStandard::ICP, err))
+            .map(|res| Nat::from(res.0.transfer_fee.e8s))
+    }
+}
+
+impl FungibleAdapter for IcpProxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, amount, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        amount: &'a Nat,
+        fee: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(to, amount, fee, contract))
+    }
+
+    fn balance_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::balance_of(contract, owner))
+    }
+
+    fn allowance<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+        spender: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::allowance(contract, owner, spender))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::probe(contract))
+    }
+
+    fn refund_deposit<'a>(
+        &'a self,
+        contract: &'a Principal,
+        owner: &'a Principal,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::refund_deposit(owner, contract))
+    }
 }
@@ -0,0 +1,140 @@
+use crate::json::*;
+use crate::*;
+
+use num_bigint::BigUint;
+
+// Serves market data as JSON over http, for dashboards and aggregators without a candid agent.
+// Every endpoint answers with the query method named next to it:
+//
+// * `/collections` - `getCollections`
+// * `/collections/{collection}/listings?sort=price_asc&limit=100` - `getListings`, first page
+// * `/collections/{collection}/stats` - `getCollectionStats`
+// * `/tokens/{collection}/{token}` - `getTokenListing`, `getTokenOffers`, `getLastSale` and
+//   `getTokensMetadata`
+//
+// Responses are not certified, they should be fetched from the canister's raw domain.
+
+/// answer a http gateway request
+pub(crate) async fn route(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return respond(405, error("method not allowed"));
+    }
+
+    let (path, query) = match request.url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.url.as_str(), ""),
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["collections"] => collections_endpoint().await,
+        ["collections", collection, "listings"] => match parse_principal(collection) {
+            Ok(collection) => listings_endpoint(collection, query).await,
+            Err(response) => response,
+        },
+        ["collections", collection, "stats"] => match parse_principal(collection) {
+            Ok(collection) => stats_endpoint(collection).await,
+            Err(response) => response,
+        },
+        ["tokens", collection, token_id] => {
+            match (parse_principal(collection), parse_nat(token_id)) {
+                (Ok(collection), Ok(token_id)) => token_endpoint(collection, token_id).await,
+                (Err(response), _) | (_, Err(response)) => response,
+            }
+        }
+        _ => respond(404, error("not found")),
+    }
+}
+
+async fn collections_endpoint() -> HttpResponse {
+    let mut collections: Vec<Collection> = get_collections().await.into_values().collect();
+    collections.sort_by(|a, b| a.nft_canister_id.cmp(&b.nft_canister_id));
+
+    respond(200, Json::array(&collections, collection_json))
+}
+
+async fn listings_endpoint(nft_canister_id: Principal, query: &str) -> HttpResponse {
+    let mut sort = ListingSort::PriceAsc;
+    let mut limit = MAX_PAGE_SIZE;
+
+    for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        match (key, value) {
+            ("sort", "price_asc") => sort = ListingSort::PriceAsc,
+            ("sort", "price_desc") => sort = ListingSort::PriceDesc,
+            ("sort", "created_asc") => sort = ListingSort::CreatedAsc,
+            ("sort", "created_desc") => sort = ListingSort::CreatedDesc,
+            ("limit", value) => match value.parse() {
+                Ok(value) => limit = value,
+                Err(_) => return respond(400, error("invalid limit")),
+            },
+            ("sort", _) => return respond(400, error("invalid sort")),
+            _ => {}
+        }
+    }
+
+    match get_listings(nft_canister_id, ListingFilter::default(), sort, None, limit).await {
+        Ok(page) => respond(200, Json::array(&page.listings, listing_entry_json)),
+        Err(e) => respond(404, error_json(&e)),
+    }
+}
+
+async fn stats_endpoint(nft_canister_id: Principal) -> HttpResponse {
+    match get_collection_stats(nft_canister_id).await {
+        Ok(stats) => respond(200, collection_stats_json(&stats)),
+        Err(e) => respond(404, error_json(&e)),
+    }
+}
+
+async fn token_endpoint(nft_canister_id: Principal, token_id: Nat) -> HttpResponse {
+    if !collections(|collections| collections.contains_key(&nft_canister_id)) {
+        return respond(404, error_json(&MPApiError::NonExistentCollection));
+    }
+
+    let listing = get_token_listing(nft_canister_id, token_id.clone()).await.ok();
+    let offers = get_token_offers(nft_canister_id, vec![token_id.clone()])
+        .await
+        .remove(&token_id)
+        .unwrap_or_default();
+    let last_sale = get_last_sale(nft_canister_id, token_id.clone()).await;
+    let metadata = get_tokens_metadata(nft_canister_id, vec![token_id.clone()])
+        .await
+        .pop()
+        .flatten();
+
+    respond(
+        200,
+        Json::object(vec![
+            ("nft_canister_id", (&nft_canister_id).into()),
+            ("token_id", (&token_id).into()),
+            ("listing", Json::option(listing.as_ref(), listing_json)),
+            ("offers", Json::array(&offers, offer_json)),
+            ("last_sale", Json::option(last_sale.as_ref(), sale_json)),
+            ("metadata", Json::option(metadata.as_ref(), cached_token_json)),
+        ]),
+    )
+}
+
+fn parse_principal(text: &str) -> Result<Principal, HttpResponse> {
+    Principal::from_text(text).map_err(|_| respond(400, error("invalid principal")))
+}
+
+fn parse_nat(text: &str) -> Result<Nat, HttpResponse> {
+    text.parse::<BigUint>()
+        .map(Nat)
+        .map_err(|_| respond(400, error("invalid token id")))
+}
+
+fn error(message: &str) -> Json {
+    Json::object(vec![("error", message.into())])
+}
+
+fn respond(status_code: u16, body: Json) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body: body.render().into_bytes(),
+    }
+}
@@ -0,0 +1,266 @@
+use crate::types::*;
+use crate::vendor_types::*;
+
+use ic_kit::candid::{Nat, Principal};
+use std::fmt::Write;
+
+// A minimal JSON encoder for the http gateway, see `http`. Nats and nat64s are encoded as
+// strings, as they do not fit in a javascript number.
+
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    pub fn object(fields: Vec<(&'static str, Json)>) -> Json {
+        Json::Object(fields)
+    }
+
+    pub fn array<T, F: Fn(T) -> Json>(items: impl IntoIterator<Item = T>, f: F) -> Json {
+        Json::Array(items.into_iter().map(f).collect())
+    }
+
+    pub fn option<T, F: Fn(T) -> Json>(value: Option<T>, f: F) -> Json {
+        value.map_or(Json::Null, f)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+            Json::Number(value) if value.is_finite() => write!(out, "{}", value).unwrap(),
+            Json::Number(_) => out.push_str("null"),
+            Json::String(value) => write_string(out, value),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write(out);
+                }
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_string(out, key);
+                    out.push(':');
+                    value.write(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl From<&Nat> for Json {
+    fn from(value: &Nat) -> Self {
+        Json::String(value.0.to_string())
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<&Principal> for Json {
+    fn from(value: &Principal) -> Self {
+        Json::String(value.to_text())
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+/// enum variants by name, eg; `"DIP721v2"`
+pub fn variant_json(value: &impl std::fmt::Debug) -> Json {
+    Json::String(format!("{:?}", value))
+}
+
+pub fn fees_json(fees: &[(String, Principal, Nat)]) -> Json {
+    Json::array(fees, |(label, recipient, rate)| {
+        Json::object(vec![
+            ("label", label.as_str().into()),
+            ("recipient", recipient.into()),
+            ("rate", rate.into()),
+        ])
+    })
+}
+
+pub fn collection_json(collection: &Collection) -> Json {
+    let status = match &collection.status {
+        CollectionStatus::Active => Json::object(vec![("status", "Active".into())]),
+        CollectionStatus::Paused { canister, since } => Json::object(vec![
+            ("status", "Paused".into()),
+            ("canister", canister.into()),
+            ("since", (*since).into()),
+        ]),
+    };
+
+    Json::object(vec![
+        ("nft_canister_id", (&collection.nft_canister_id).into()),
+        ("collection_name", collection.collection_name.as_str().into()),
+        ("owner", (&collection.owner).into()),
+        ("collection_fee", (&collection.collection_fee).into()),
+        ("creation_time", collection.creation_time.into()),
+        ("nft_canister_standard", variant_json(&collection.nft_canister_standard)),
+        ("fungible_canister_id", (&collection.fungible_canister_id).into()),
+        ("fungible_canister_standard", variant_json(&collection.fungible_canister_standard)),
+        ("fungible_volume", (&collection.fungible_volume).into()),
+        ("status", status),
+    ])
+}
+
+pub fn listing_json(listing: &Listing) -> Json {
+    Json::object(vec![
+        ("price", (&listing.price).into()),
+        ("seller", (&listing.seller).into()),
+        ("status", variant_json(&listing.status)),
+        ("created", listing.created.into()),
+        ("fee", fees_json(&listing.fee)),
+    ])
+}
+
+pub fn listing_entry_json(entry: &ListingEntry) -> Json {
+    Json::object(vec![
+        ("nft_canister_id", (&entry.nft_canister_id).into()),
+        ("token_id", (&entry.token_id).into()),
+        ("listing", listing_json(&entry.listing)),
+    ])
+}
+
+pub fn offer_json(offer: &Offer) -> Json {
+    Json::object(vec![
+        ("nft_canister_id", (&offer.nft_canister_id).into()),
+        ("token_id", (&offer.token_id).into()),
+        ("price", (&offer.price).into()),
+        ("buyer", (&offer.buyer).into()),
+        ("token_owner", (&offer.token_owner).into()),
+        ("status", variant_json(&offer.status)),
+        ("created", offer.created.into()),
+        ("fee", fees_json(&offer.fee)),
+        ("referrer", Json::option(offer.referrer.as_ref(), Json::from)),
+    ])
+}
+
+pub fn sale_json(sale: &Sale) -> Json {
+    Json::object(vec![
+        ("trade_id", sale.trade_id.into()),
+        ("nft_canister_id", (&sale.nft_canister_id).into()),
+        ("token_id", (&sale.token_id).into()),
+        ("fungible_canister_id", (&sale.fungible_canister_id).into()),
+        ("buyer", (&sale.buyer).into()),
+        ("seller", (&sale.seller).into()),
+        ("price", (&sale.price).into()),
+        ("fees", (&sale.fees).into()),
+        ("trade_type", variant_json(&sale.trade_type)),
+        ("time", sale.time.into()),
+    ])
+}
+
+pub fn window_stats_json(stats: &WindowStats) -> Json {
+    Json::object(vec![
+        ("volume", (&stats.volume).into()),
+        ("sales", stats.sales.into()),
+        ("average_price", Json::option(stats.average_price.as_ref(), Json::from)),
+        ("median_price", Json::option(stats.median_price.as_ref(), Json::from)),
+        ("unique_buyers", stats.unique_buyers.into()),
+        ("unique_sellers", stats.unique_sellers.into()),
+    ])
+}
+
+pub fn collection_stats_json(stats: &CollectionStats) -> Json {
+    Json::object(vec![
+        ("day", window_stats_json(&stats.day)),
+        ("week", window_stats_json(&stats.week)),
+        ("month", window_stats_json(&stats.month)),
+        ("lifetime_volume", (&stats.lifetime_volume).into()),
+        ("floor", Json::option(stats.floor.as_ref(), Json::from)),
+        ("active_listings", stats.active_listings.into()),
+        ("active_offers", stats.active_offers.into()),
+    ])
+}
+
+pub fn generic_value_json(value: &GenericValue) -> Json {
+    match value {
+        GenericValue::BoolContent(value) => Json::Bool(*value),
+        GenericValue::TextContent(value) => value.as_str().into(),
+        GenericValue::BlobContent(value) => Json::array(value, |byte| Json::Number(*byte as f64)),
+        GenericValue::Principal(value) => value.into(),
+        GenericValue::Nat8Content(value) => Json::Number(*value as f64),
+        GenericValue::Nat16Content(value) => Json::Number(*value as f64),
+        GenericValue::Nat32Content(value) => Json::Number(*value as f64),
+        GenericValue::Nat64Content(value) => (*value).into(),
+        GenericValue::NatContent(value) => value.into(),
+        GenericValue::Int8Content(value) => Json::Number(*value as f64),
+        GenericValue::Int16Content(value) => Json::Number(*value as f64),
+        GenericValue::Int32Content(value) => Json::Number(*value as f64),
+        GenericValue::Int64Content(value) => Json::String(value.to_string()),
+        GenericValue::IntContent(value) => Json::String(value.0.to_string()),
+        GenericValue::FloatContent(value) => Json::Number(*value),
+        GenericValue::NestedContent(values) => properties_json(values),
+    }
+}
+
+pub fn properties_json(properties: &[(String, GenericValue)]) -> Json {
+    Json::Array(
+        properties
+            .iter()
+            .map(|(key, value)| {
+                Json::object(vec![
+                    ("key", key.as_str().into()),
+                    ("value", generic_value_json(value)),
+                ])
+            })
+            .collect(),
+    )
+}
+
+pub fn cached_token_json(token: &CachedToken) -> Json {
+    Json::object(vec![
+        ("owner", Json::option(token.owner.as_ref(), Json::from)),
+        ("operator", Json::option(token.operator.as_ref(), Json::from)),
+        ("properties", properties_json(&token.properties)),
+        ("refreshed", token.refreshed.into()),
+    ])
+}
+
+pub fn error_json(error: &MPApiError) -> Json {
+    Json::object(vec![("error", variant_json(error))])
+}
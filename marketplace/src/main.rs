@@ -1,7 +1,13 @@
 #![allow(warnings)]
 
+use crate::certification::*;
+use crate::circuit_breaker::*;
+use crate::fees::*;
 use crate::fungible_proxy::*;
 use crate::non_fungible_proxy::*;
+use crate::order_book::*;
+use crate::stats::*;
+use crate::token_cache::*;
 use crate::types::*;
 use crate::utils::*;
 use crate::vendor_types::*;
@@ -25,8 +31,20 @@ use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::default::Default;
 
+mod certification;
+mod circuit_breaker;
+#[cfg(test)]
+mod fakes;
+mod fees;
 mod fungible_proxy;
+mod http;
+mod json;
 mod non_fungible_proxy;
+mod order_book;
+mod stats;
+#[cfg(test)]
+mod tests;
+mod token_cache;
 mod types;
 mod upgrade;
 mod utils;
@@ -41,6 +59,23 @@ pub fn init(owner: Principal, protocol_fee: Nat, cap: Option<Principal>) {
         protocol_fee,
     });
     handshake(1_000_000_000_000, cap);
+    certify_all();
+}
+
+/// Re-probe canisters whose breaker cooled down and probe collections never probed, see
+/// `circuit_breaker`, and refresh stale cached tokens, see `token_cache`
+#[heartbeat]
+async fn heartbeat() {
+    reprobe_breakers().await;
+    probe_collections().await;
+    sweep_token_cache().await;
+}
+
+/// Serve market data as JSON to the http gateway, see `http` for the endpoints
+#[query(name = "http_request")]
+#[candid_method(query, rename = "http_request")]
+pub async fn http_request(request: HttpRequest) -> HttpResponse {
+    http::route(request).await
 }
 
 // cover metadata
@@ -65,9 +100,7 @@ fn dfx_info() -> &'static str {
 #[query]
 #[candid_method(query)]
 async fn failed_log() -> Result<Vec<TxLogEntry>, MPApiError> {
-    if let Err(e) = is_controller(&ic::caller()).await {
-        return Err(MPApiError::Other(format!("{:?}", e)));
-    }
+    is_controller(&ic::caller()).await?;
 
     Ok(balances(|balances| balances.failed_tx_log_entries.clone()))
 }
@@ -75,9 +108,7 @@ async fn failed_log() -> Result<Vec<TxLogEntry>, MPApiError> {
 #[update]
 #[candid_method(update)]
 async fn fix_balance(fungible_canister_id: Principal, user: Principal, amount: Nat) -> MPApiResult {
-    if let Err(e) = is_controller(&ic::caller()).await {
-        return Err(MPApiError::Other(format!("{:?}", e)));
-    }
+    is_controller(&ic::caller()).await?;
 
     balances_mut(|balances| {
         *balances
@@ -85,6 +116,7 @@ async fn fix_balance(fungible_canister_id: Principal, user: Principal, amount: N
             .entry((fungible_canister_id, user))
             .or_default() = amount.clone();
     });
+    certify_balance(&fungible_canister_id, &user);
 
     Ok(())
 }
@@ -93,10 +125,7 @@ async fn fix_balance(fungible_canister_id: Principal, user: Principal, amount: N
 ///
 /// To let the canister call the `aaaaa-aa` Management API `canister_status`,
 /// the canister needs to be a controller of itself.
-pub async fn is_controller(principal: &Principal) -> Result<(), String> {
-    let caller = ic::caller();
-    let self_id = ic::id();
-
+pub async fn is_controller(principal: &Principal) -> MPApiResult {
     let status = CanisterStatus::perform(
         Principal::management_canister(),
         (WithCanisterId {
@@ -104,43 +133,78 @@ pub async fn is_controller(principal: &Principal) -> Result<(), String> {
         },),
     )
     .await
-    .map(|(status,)| Ok(status))
-    .unwrap_or_else(|(code, message)| Err(format!("Code: {:?}, Message: {}", code, message)))?;
+    .map(|(status,)| status)
+    .map_err(|err| call_error(&Principal::management_canister(), "canister_status", err))?;
 
-    match status.settings.controllers.contains(&caller) {
+    match status.settings.controllers.contains(principal) {
         true => Ok(()),
-        false => Err(format!("{} is not a controller of {}", caller, self_id)),
+        false => Err(MPApiError::NotController(*principal)),
     }
 }
 
 /// process fees and add amounts to the fee to's balances
 ///
+/// Every credited fee is also recorded in the fee ledger, so recipients can tell
+/// royalties apart from refunds and seller fallbacks held in the same balances.
+/// When an active referrer is given, the referral share is taken out of the protocol fee.
+/// What remains of the protocol fee is split between the configured treasuries, if any.
+/// See `fees` for how amounts are rounded; rounding dust is added to the fungible's dust balance.
+///
+/// * `trade_id` - id of the trade the fees are charged for, see `next_trade_id`
+/// * `nft_canister_id` - Principal of the collection the trade happened in
+/// * `token_id` - Nat id of the traded token
 /// * `fungible_canister_id` - Principal for the fungible contract used to disperse fees in
 /// * `price` - Nat amount
 /// * `fees` - Vec of fees, (string fee purpose, principal of fee recipient, percent (e2))
+/// * `referrer` - optional referrer of the trade
+///
+/// Returns the total fee, the seller is owed `price - total fee`
 pub fn process_fees(
+    trade_id: u64,
+    nft_canister_id: Principal,
+    token_id: &Nat,
     fungible_canister_id: Principal,
     price: Nat,
     fees: Vec<(String, Principal, Nat)>,
+    referrer: Option<Principal>,
 ) -> Nat {
-    let mut total_fee: Nat = Nat::from(0);
+    let time = ic::time();
+    let referral = active_referrer(referrer)
+        .map(|referrer| (referrer, referrals(|referrals| referrals.share.clone())));
 
-    for (_, principal, fee) in fees {
-        // divide by 100 * 100 to allow 2 digits of precision in the fee percentage
-        let amount: Nat = (price.clone() * fee.clone()) / Nat::from(10000);
+    let split = split_fees(&price, &fees, referral, &treasury_splits());
 
-        total_fee += amount.clone();
+    for (fee_label, recipient, amount) in split.credits.iter().cloned() {
+        if amount == Nat::from(0) {
+            continue;
+        }
 
         // credit the fee to the fee recipient
         balances_mut(|balances| {
             *balances
                 .balances
-                .entry((fungible_canister_id, principal))
+                .entry((fungible_canister_id, recipient))
                 .or_default() += amount.clone();
         });
+        certify_balance(&fungible_canister_id, &recipient);
+
+        log_accrual(FeeAccrual::new(
+            trade_id,
+            nft_canister_id,
+            token_id.clone(),
+            fungible_canister_id,
+            fee_label,
+            recipient,
+            amount,
+            time,
+        ));
     }
 
-    total_fee
+    balances_mut(|balances| {
+        *balances.dust.entry(fungible_canister_id).or_default() += split.dust.clone();
+    });
+
+    split.total_fee()
 }
 
 // QUERY METHODS //
@@ -161,6 +225,37 @@ pub async fn get_collections() -> HashMap<Principal, Collection> {
     collections(|collections| collections.clone())
 }
 
+/// Get the breakers of external canisters that failed since they last answered
+/// A collection is paused while the breaker of its nft or fungible canister is open
+#[query(name = "getBreakers")]
+#[candid_method(query, rename = "getBreakers")]
+pub async fn get_breakers() -> Vec<(Principal, Breaker)> {
+    breakers(|breakers| {
+        breakers
+            .breakers
+            .iter()
+            .map(|(canister, breaker)| (*canister, breaker.clone()))
+            .collect()
+    })
+}
+
+/// Get the cached metadata of tokens, in the order of `token_ids`
+/// Only listed and offered tokens are cached, others are returned as `null`
+#[query(name = "getTokensMetadata")]
+#[candid_method(query, rename = "getTokensMetadata")]
+pub async fn get_tokens_metadata(
+    nft_canister_id: Principal,
+    token_ids: Vec<Nat>,
+) -> Vec<Option<CachedToken>> {
+    token_cache(|token_cache| {
+        let tokens = token_cache.tokens.get(&nft_canister_id);
+        token_ids
+            .iter()
+            .map(|token_id| tokens.and_then(|tokens| tokens.get(token_id)).cloned())
+            .collect()
+    })
+}
+
 /// Get a tokens listing. Will return with `MPApiError::InvalidListing` if the listing does not exist.
 #[query(name = "getTokenListing")]
 #[candid_method(query, rename = "getTokenListing")]
@@ -185,6 +280,47 @@ pub async fn get_token_listing(
     // todo: switch to a method where we return empty or last known listing info with sold status
 }
 
+/// Get a page of a collection's listings
+///
+/// * `filter` - price range and seller to match, unset fields match every listing
+/// * `sort` - order of the listings, by price or creation time
+/// * `cursor` - `next` of the previous page, or `null` for the first page
+/// * `limit` - listings per page, at most 100
+#[query(name = "getListings")]
+#[candid_method(query, rename = "getListings")]
+pub async fn get_listings(
+    nft_canister_id: Principal,
+    filter: ListingFilter,
+    sort: ListingSort,
+    cursor: Option<ListingCursor>,
+    limit: u64,
+) -> Result<ListingsPage, MPApiError> {
+    // verify collection is registered
+    if !collections(|collections| collections.contains_key(&nft_canister_id)) {
+        return Err(MPApiError::NonExistentCollection);
+    }
+
+    Ok(listings_page(
+        Some(nft_canister_id),
+        &filter,
+        sort,
+        cursor,
+        limit,
+    ))
+}
+
+/// Get a page of the listings of every collection, see `getListings`
+#[query(name = "getAllListings")]
+#[candid_method(query, rename = "getAllListings")]
+pub async fn get_all_listings(
+    filter: ListingFilter,
+    sort: ListingSort,
+    cursor: Option<ListingCursor>,
+    limit: u64,
+) -> ListingsPage {
+    listings_page(None, &filter, sort, cursor, limit)
+}
+
 /// Get a tokens current offers. Can pass as many token ids as you want
 #[query(name = "getTokenOffers")]
 #[candid_method(query, rename = "getTokenOffers")]
@@ -260,6 +396,83 @@ pub async fn get_buyer_offers(nft_canister_id: Principal, buyer: Principal) -> V
     })
 }
 
+/// Get all the listings a seller has made, across collections
+#[query(name = "getSellerListings")]
+#[candid_method(query, rename = "getSellerListings")]
+pub async fn get_seller_listings(seller: Principal) -> Vec<ListingEntry> {
+    marketplace(|mp| {
+        let collections = match mp.seller_listings.get(&seller) {
+            Some(collections) => collections,
+            None => return Vec::new(),
+        };
+
+        collections
+            .iter()
+            .flat_map(|(nft_canister_id, token_ids)| {
+                token_ids.iter().filter_map(move |token_id| {
+                    mp.listings
+                        .get(nft_canister_id)
+                        .and_then(|listings| listings.get(token_id))
+                        .map(|listing| {
+                            ListingEntry::new(*nft_canister_id, token_id.clone(), listing.clone())
+                        })
+                })
+            })
+            .collect()
+    })
+}
+
+/// Get all the offers made on tokens a user owns, as of the offer or the token's last sale
+/// on marketplace, across collections
+#[query(name = "getReceivedOffers")]
+#[candid_method(query, rename = "getReceivedOffers")]
+pub async fn get_received_offers(token_owner: Principal) -> Vec<Offer> {
+    marketplace(|mp| {
+        let collections = match mp.received_offers.get(&token_owner) {
+            Some(collections) => collections,
+            None => return Vec::new(),
+        };
+
+        collections
+            .iter()
+            .flat_map(|(nft_canister_id, offers)| {
+                offers.iter().filter_map(move |(token_id, buyer)| {
+                    mp.offers
+                        .get(nft_canister_id)
+                        .and_then(|offers| offers.get(token_id))
+                        .and_then(|offers| offers.get(buyer))
+                        .cloned()
+                })
+            })
+            .collect()
+    })
+}
+
+/// Get all the offers a buyer has made, across collections
+#[query(name = "getUserOffers")]
+#[candid_method(query, rename = "getUserOffers")]
+pub async fn get_user_offers(buyer: Principal) -> Vec<Offer> {
+    marketplace(|mp| {
+        let collections = match mp.user_offers.get(&buyer) {
+            Some(collections) => collections,
+            None => return Vec::new(),
+        };
+
+        collections
+            .iter()
+            .flat_map(|(nft_canister_id, token_ids)| {
+                token_ids.iter().filter_map(move |token_id| {
+                    mp.offers
+                        .get(nft_canister_id)
+                        .and_then(|offers| offers.get(token_id))
+                        .and_then(|offers| offers.get(&buyer))
+                        .cloned()
+                })
+            })
+            .collect()
+    })
+}
+
 /// Get all users fungible balances held by marketplace
 #[query(name = "getAllBalances")]
 #[candid_method(query, rename = "getAllBalances")]
@@ -287,6 +500,27 @@ pub async fn balance_of(pid: Principal) -> HashMap<Principal, Nat> {
         .collect()
 }
 
+/// Get the rounding dust held by marketplace, per fungible canister
+#[query(name = "getDust")]
+#[candid_method(query, rename = "getDust")]
+pub async fn get_dust() -> HashMap<Principal, Nat> {
+    balances(|b| b.dust.clone())
+}
+
+/// Get the ICP account identifier a user deposits into, to pay for collections traded in ICP
+#[query(name = "getDepositAccount")]
+#[candid_method(query, rename = "getDepositAccount")]
+pub async fn get_deposit_account(pid: Principal) -> String {
+    account_identifier(&ic::id(), Some(deposit_subaccount(&pid)))
+}
+
+/// Get the subaccount a principal uses on ICRC ledgers and ICRC7 collections, if it set one
+#[query(name = "getIcrcSubaccount")]
+#[candid_method(query, rename = "getIcrcSubaccount")]
+pub async fn get_icrc_subaccount(pid: Principal) -> Option<Subaccount> {
+    icrc_subaccounts(|icrc_subaccounts| icrc_subaccounts.get(&pid).cloned())
+}
+
 /// Get a collections floor price
 #[query(name = getFloor)]
 #[candid_method(query, rename = "getFloor")]
@@ -296,23 +530,297 @@ pub async fn get_floor(nft_canister_id: Principal) -> NatResult {
         .get(&nft_canister_id)
         .ok_or(MPApiError::NonExistentCollection)?;
 
-    marketplace(|mp| {
-        let listings = mp
-            .listings
+    best_ask(&nft_canister_id).ok_or_else(|| MPApiError::Other("No Listings".to_string()))
+}
+
+/// Get a tokens listing, with the certificate and witness to verify it, see `certification`
+#[query(name = "getCertifiedTokenListing")]
+#[candid_method(query, rename = "getCertifiedTokenListing")]
+pub async fn get_certified_token_listing(
+    nft_canister_id: Principal,
+    token_id: Nat,
+) -> CertifiedListing {
+    let listing = marketplace(|mp| {
+        mp.listings
             .get(&nft_canister_id)
-            .ok_or_else(|| MPApiError::Other("No Listings".to_string()))?;
+            .and_then(|listings| listings.get(&token_id))
+            .filter(|listing| listing.status != ListingStatus::Uninitialized)
+            .cloned()
+    });
+    let (certificate, witness) = certified(listing_key(&nft_canister_id, &token_id));
 
-        if let Some((_, listing)) = listings
-            .iter()
-            .min_by_key(|(_, listing)| listing.price.clone())
-        {
-            return Ok(listing.price.clone());
+    CertifiedListing::new(listing, certificate, witness)
+}
+
+/// Get a collections floor price, with the certificate and witness to verify it
+#[query(name = "getCertifiedFloor")]
+#[candid_method(query, rename = "getCertifiedFloor")]
+pub async fn get_certified_floor(nft_canister_id: Principal) -> CertifiedNat {
+    let (certificate, witness) = certified(floor_key(&nft_canister_id));
+
+    CertifiedNat::new(best_ask(&nft_canister_id), certificate, witness)
+}
+
+/// Get a users balance of a fungible held by marketplace, with the certificate and witness to
+/// verify it
+#[query(name = "getCertifiedBalance")]
+#[candid_method(query, rename = "getCertifiedBalance")]
+pub async fn get_certified_balance(
+    pid: Principal,
+    fungible_canister_id: Principal,
+) -> CertifiedNat {
+    let balance = balances(|balances| {
+        balances
+            .balances
+            .get(&(fungible_canister_id, pid))
+            .cloned()
+    });
+    let (certificate, witness) = certified(balance_key(&fungible_canister_id, &pid));
+
+    CertifiedNat::new(balance, certificate, witness)
+}
+
+/// Get a collections trailing 24h, 7d and 30d sales, with its floor and active listings and offers
+///
+/// The 7d and 30d windows start at the beginning of their oldest day
+#[query(name = "getCollectionStats")]
+#[candid_method(query, rename = "getCollectionStats")]
+pub async fn get_collection_stats(
+    nft_canister_id: Principal,
+) -> Result<CollectionStats, MPApiError> {
+    let collections = collections(|collections| collections.clone());
+    let collection = collections
+        .get(&nft_canister_id)
+        .ok_or(MPApiError::NonExistentCollection)?;
+
+    Ok(collection_stats(collection))
+}
+
+/// Get a collections volume, sales and floor per hour or per day, oldest first
+///
+/// Only buckets with a sale or listing change are returned, hourly ones for the last 2 days
+#[query(name = "getStatsHistory")]
+#[candid_method(query, rename = "getStatsHistory")]
+pub async fn get_stats_history(
+    nft_canister_id: Principal,
+    interval: StatsInterval,
+) -> Result<Vec<StatsHistoryEntry>, MPApiError> {
+    // verify collection is registered
+    if !collections(|collections| collections.contains_key(&nft_canister_id)) {
+        return Err(MPApiError::NonExistentCollection);
+    }
+
+    Ok(stats_history(&nft_canister_id, interval))
+}
+
+/// Get a collections market depth, up to `depth` price levels of listings and of offers,
+/// with the best ask and bid
+#[query(name = "getOrderBook")]
+#[candid_method(query, rename = "getOrderBook")]
+pub async fn get_order_book(
+    nft_canister_id: Principal,
+    depth: u64,
+) -> Result<OrderBook, MPApiError> {
+    // verify collection is registered
+    if !collections(|collections| collections.contains_key(&nft_canister_id)) {
+        return Err(MPApiError::NonExistentCollection);
+    }
+
+    Ok(order_book(&nft_canister_id, depth))
+}
+
+/// Get the fees earned by a recipient, grouped by collection, fungible and fee label
+#[query(name = "getRecipientRevenue")]
+#[candid_method(query, rename = "getRecipientRevenue")]
+pub async fn get_recipient_revenue(recipient: Principal) -> Vec<FeeRevenue> {
+    recipient_revenue(&recipient, |_| true)
+}
+
+/// Get the fees charged on a collection's trades, grouped by fungible, fee label and recipient
+#[query(name = "getCollectionRevenue")]
+#[candid_method(query, rename = "getCollectionRevenue")]
+pub async fn get_collection_revenue(nft_canister_id: Principal) -> Vec<FeeRevenue> {
+    sales_index(|sales_index| {
+        sales_index
+            .collection_revenue
+            .get(&nft_canister_id)
+            .into_iter()
+            .flatten()
+            .map(|((fungible_canister_id, fee_label, recipient), amount)| {
+                FeeRevenue::new(
+                    nft_canister_id,
+                    *fungible_canister_id,
+                    fee_label.clone(),
+                    *recipient,
+                    amount.clone(),
+                )
+            })
+            .collect()
+    })
+}
+
+/// Get a page of fee revenue summed per time bucket, newest first, optionally narrowed to a
+/// collection and/or recipient
+///
+/// * `interval` - bucket size in seconds, eg; `86400` for daily buckets
+/// * `before` - `None` for the first page, then the `next` of the previous page
+/// * `limit` - buckets per page, at most `MAX_PAGE_SIZE`
+#[query(name = "getRevenueHistory")]
+#[candid_method(query, rename = "getRevenueHistory")]
+pub async fn get_revenue_history(
+    nft_canister_id: Option<Principal>,
+    recipient: Option<Principal>,
+    interval: u64,
+    before: Option<u64>,
+    limit: u64,
+) -> Result<RevenuePage, MPApiError> {
+    if interval == 0 {
+        return Err(MPApiError::Other("interval must be greater than 0".to_string()));
+    }
+    let interval = interval.saturating_mul(1_000_000_000);
+
+    let matching = |accrual: &FeeAccrual| {
+        nft_canister_id.map_or(true, |id| id == accrual.nft_canister_id)
+            && recipient.map_or(true, |id| id == accrual.recipient)
+    };
+
+    // only the accruals of the collection, or else of the recipient, are walked
+    sales_index(|sales_index| {
+        let positions = match (nft_canister_id, recipient) {
+            (Some(nft_canister_id), _) => Some(sales_index.collection_fees.get(&nft_canister_id)),
+            (None, Some(recipient)) => Some(sales_index.recipient_fees.get(&recipient)),
+            (None, None) => None,
         }
+        .map(|positions| positions.map_or(&[][..], |positions| &positions[..]));
+
+        Ok(revenue_page(positions, matching, interval, before, limit))
+    })
+}
+
+/// Get the last sale of a token, if it was ever sold on marketplace
+#[query(name = "getLastSale")]
+#[candid_method(query, rename = "getLastSale")]
+pub async fn get_last_sale(nft_canister_id: Principal, token_id: Nat) -> Option<Sale> {
+    let position = sales_index(|sales_index| {
+        sales_index
+            .token_sales
+            .get(&nft_canister_id)
+            .and_then(|tokens| tokens.get(&token_id))
+            .and_then(|positions| positions.last().cloned())
+    })?;
+
+    sales_ledger(|sales_ledger| sales_ledger.sales.get(position as usize).cloned())
+}
+
+/// Get a page of a token's sales on marketplace, newest first
+///
+/// * `before` - `null` for the first page, then the `next` of the previous page
+/// * `limit` - sales per page, at most 100
+#[query(name = "getTokenSales")]
+#[candid_method(query, rename = "getTokenSales")]
+pub async fn get_token_sales(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    before: Option<u64>,
+    limit: u64,
+) -> SalesPage {
+    sales_index(|sales_index| {
+        let positions = sales_index
+            .token_sales
+            .get(&nft_canister_id)
+            .and_then(|tokens| tokens.get(&token_id))
+            .map_or(&[][..], |positions| &positions[..]);
+        sales_page(positions, before, limit)
+    })
+}
+
+/// Get a page of a collection's sales, newest first, see `getTokenSales`
+#[query(name = "getCollectionSales")]
+#[candid_method(query, rename = "getCollectionSales")]
+pub async fn get_collection_sales(
+    nft_canister_id: Principal,
+    before: Option<u64>,
+    limit: u64,
+) -> SalesPage {
+    sales_index(|sales_index| {
+        let positions = sales_index
+            .collection_sales
+            .get(&nft_canister_id)
+            .map_or(&[][..], |positions| &positions[..]);
+        sales_page(positions, before, limit)
+    })
+}
+
+/// Get a page of the sales a user bought or sold in, newest first, see `getTokenSales`
+#[query(name = "getUserSales")]
+#[candid_method(query, rename = "getUserSales")]
+pub async fn get_user_sales(user: Principal, before: Option<u64>, limit: u64) -> SalesPage {
+    sales_index(|sales_index| {
+        let positions = sales_index
+            .user_sales
+            .get(&user)
+            .map_or(&[][..], |positions| &positions[..]);
+        sales_page(positions, before, limit)
+    })
+}
+
+/// Get the fees credited for a single trade
+#[query(name = "getTradeFees")]
+#[candid_method(query, rename = "getTradeFees")]
+pub async fn get_trade_fees(trade_id: u64) -> Vec<FeeAccrual> {
+    let positions = sales_index(|sales_index| {
+        sales_index
+            .trade_fees
+            .get(&trade_id)
+            .cloned()
+            .unwrap_or_default()
+    });
 
-        Err(MPApiError::Other("No Listings".to_string()))
+    fee_ledger(|fee_ledger| {
+        positions
+            .iter()
+            .map(|position| fee_ledger.accruals[*position as usize].clone())
+            .collect()
     })
 }
 
+/// Get a referrer's registration, if any
+#[query(name = "getReferrer")]
+#[candid_method(query, rename = "getReferrer")]
+pub async fn get_referrer(referrer: Principal) -> Option<Referrer> {
+    referrals(|referrals| referrals.referrers.get(&referrer).cloned())
+}
+
+/// Get the share of the protocol fee credited to referrers. This is stored as an e2,
+/// so a referrer receiving a fifth of the protocol fee equals 2000:nat
+#[query(name = "getReferralShare")]
+#[candid_method(query, rename = "getReferralShare")]
+pub async fn get_referral_share() -> Nat {
+    referrals(|referrals| referrals.share.clone())
+}
+
+/// Get the referral fees earned by a referrer, grouped by collection and fungible
+#[query(name = "getReferrerEarnings")]
+#[candid_method(query, rename = "getReferrerEarnings")]
+pub async fn get_referrer_earnings(referrer: Principal) -> Vec<FeeRevenue> {
+    recipient_revenue(&referrer, |fee_label| fee_label == REFERRAL_FEE_LABEL)
+}
+
+/// Get the treasuries the protocol fee is currently split between, as (label, principal, share (e2))
+/// An empty list means the whole protocol fee goes to the marketplace owner
+#[query(name = "getTreasurySplits")]
+#[candid_method(query, rename = "getTreasurySplits")]
+pub async fn get_treasury_splits() -> Vec<(String, Principal, Nat)> {
+    treasury_splits()
+}
+
+/// Get every treasury split configuration that has been set, oldest first
+#[query(name = "getTreasurySplitHistory")]
+#[candid_method(query, rename = "getTreasurySplitHistory")]
+pub async fn get_treasury_split_history() -> Vec<TreasurySplitConfig> {
+    treasury(|treasury| treasury.history.clone())
+}
+
 // UPDATE METHODS //
 
 /// Add a Collection
@@ -323,6 +831,9 @@ pub async fn get_floor(nft_canister_id: Principal) -> NatResult {
 /// * nft_canister_standard` - nft standard, eg; `DIP721v2`
 /// * fungible_canister_id` - principal of the fungible a collection is traded with
 /// * fungible_canister_standard` - fungible standard, eg; `DIP20`
+///
+/// Both canisters are probed first, a collection is rejected when they do not implement the
+/// standards they are registered with. Detected capabilities are recorded on the collection
 #[update(name = "addCollection")]
 #[candid_method(update, rename = "addCollection")]
 pub async fn add_collection(
@@ -335,10 +846,21 @@ pub async fn add_collection(
     fungible_canister_id: Principal,
     fungible_canister_standard: FungibleStandard,
 ) -> MPApiResult {
-    if let Err(e) = is_controller(&ic::caller()).await {
-        return Err(MPApiError::Other(format!("{:?}", e)));
+    is_controller(&ic::caller()).await?;
+
+    let protocol_fee = init_data(|init_data| init_data.protocol_fee.clone());
+    if collection_fee.clone() + protocol_fee > Nat::from(FEE_DENOMINATOR) {
+        return Err(MPApiError::Other(
+            "protocol and collection fees cannot exceed 10000".to_string(),
+        ));
     }
 
+    // reject canisters that do not implement the standards they are registered with
+    let (nft_interfaces, approvals) =
+        probe_non_fungible(&nft_canister_id, nft_canister_standard).await?;
+    let transfer_fee =
+        probe_fungible(&fungible_canister_id, fungible_canister_standard.clone()).await?;
+
     collections_mut(|collections| {
         collections.insert(
             nft_canister_id,
@@ -352,6 +874,8 @@ pub async fn add_collection(
                 fungible_canister_id,
                 fungible_canister_standard,
                 Nat::from(0),
+                CollectionCapabilities::new(nft_interfaces, approvals, transfer_fee, true),
+                collection_status(&nft_canister_id, &fungible_canister_id),
             ),
         );
     });
@@ -361,12 +885,26 @@ pub async fn add_collection(
 
 /// Set the base protocol level transaction fee
 /// fee is stored as an e2, so for a 2.5% fee the value would be `250:nat`
+///
+/// Existing listings and offers keep the fees snapshotted when they were made
 #[update(name = "setProtocolFee")]
 #[candid_method(update, rename = "setProtocolFee")]
 async fn set_protocol_fee(fee: Nat) -> MPApiResult {
-    if let Err(e) = is_controller(&ic::caller()).await {
-        return Err(MPApiError::Other(format!("{:?}", e)));
+    is_controller(&ic::caller()).await?;
+
+    let max_collection_fee = collections(|collections| {
+        collections
+            .values()
+            .map(|collection| collection.collection_fee.clone())
+            .max()
+            .unwrap_or_default()
+    });
+    if fee.clone() + max_collection_fee > Nat::from(FEE_DENOMINATOR) {
+        return Err(MPApiError::Other(
+            "protocol and collection fees cannot exceed 10000".to_string(),
+        ));
     }
+
     // commit to state
     init_data_mut(|init_data| {
         init_data.protocol_fee = fee;
@@ -375,15 +913,128 @@ async fn set_protocol_fee(fee: Nat) -> MPApiResult {
     Ok(())
 }
 
+/// Set the treasuries the protocol fee is split between
+///
+/// * `splits` - Vec of (string treasury purpose, principal of treasury, share (e2)).
+/// Shares must add up to `10000:nat`. An empty vec sends the whole protocol fee to the owner again
+#[update(name = "setTreasurySplits")]
+#[candid_method(update, rename = "setTreasurySplits")]
+async fn set_treasury_splits(splits: Vec<(String, Principal, Nat)>) -> MPApiResult {
+    let caller = ic::caller();
+    is_controller(&caller).await?;
+
+    let total_share = splits
+        .iter()
+        .fold(Nat::from(0), |total, (_, _, share)| total + share.clone());
+
+    if !splits.is_empty() && total_share != Nat::from(10000) {
+        return Err(MPApiError::Other(
+            "treasury shares must add up to 10000".to_string(),
+        ));
+    }
+
+    // commit to state
+    treasury_mut(|treasury| {
+        treasury
+            .history
+            .push(TreasurySplitConfig::new(splits, caller, ic::time()));
+    });
+
+    Ok(())
+}
+
+/// Sweep the rounding dust held for a fungible canister into the marketplace owner's balance,
+/// where it can be withdrawn with `withdrawFungible`
+#[update(name = "sweepDust")]
+#[candid_method(update, rename = "sweepDust")]
+async fn sweep_dust(fungible_canister_id: Principal) -> MPApiResult {
+    is_controller(&ic::caller()).await?;
+    let owner = init_data(|init_data| init_data.owner);
+
+    // commit to state
+    balances_mut(|balances| {
+        let dust = balances
+            .dust
+            .remove(&fungible_canister_id)
+            .ok_or(MPApiError::InsufficientFungibleBalance)?;
+
+        *balances
+            .balances
+            .entry((fungible_canister_id, owner))
+            .or_default() += dust;
+
+        Ok(())
+    })?;
+    certify_balance(&fungible_canister_id, &owner);
+
+    Ok(())
+}
+
+/// Register the caller as a referrer, so it can be passed to `directBuy`, `makeOffer` and `acceptOffer`
+#[update(name = "registerReferrer")]
+#[candid_method(update, rename = "registerReferrer")]
+pub async fn register_referrer() -> MPApiResult {
+    let caller = ic::caller();
+
+    referrals_mut(|referrals| {
+        // re-registering would re-enable a referrer disabled by a controller
+        if referrals.referrers.contains_key(&caller) {
+            return Err(MPApiError::InvalidReferrer);
+        }
+
+        referrals
+            .referrers
+            .insert(caller, Referrer::new(true, ic::time()));
+
+        Ok(())
+    })
+}
+
+/// Enable or disable a registered referrer. Disabled referrers are not credited on trades
+#[update(name = "setReferrerEnabled")]
+#[candid_method(update, rename = "setReferrerEnabled")]
+async fn set_referrer_enabled(referrer: Principal, enabled: bool) -> MPApiResult {
+    is_controller(&ic::caller()).await?;
+
+    referrals_mut(|referrals| {
+        let registration = referrals
+            .referrers
+            .get_mut(&referrer)
+            .ok_or(MPApiError::InvalidReferrer)?;
+        registration.enabled = enabled;
+
+        Ok(())
+    })
+}
+
+/// Set the share of the protocol fee credited to referrers
+/// share is stored as an e2, so for a fifth of the protocol fee the value would be `2000:nat`
+#[update(name = "setReferralShare")]
+#[candid_method(update, rename = "setReferralShare")]
+async fn set_referral_share(share: Nat) -> MPApiResult {
+    is_controller(&ic::caller()).await?;
+
+    if share > Nat::from(10000) {
+        return Err(MPApiError::Other(
+            "referral share cannot exceed 10000".to_string(),
+        ));
+    }
+
+    // commit to state
+    referrals_mut(|referrals| {
+        referrals.share = share;
+    });
+
+    Ok(())
+}
+
 /// Verify a listing, and cancel if allowance is expired
 #[update]
 #[candid_method]
 pub async fn verify_listing(nft_canister_id: Principal, token_id: Nat) -> MPApiResult {
     let caller = ic::caller();
     // lock to controllers only for now
-    if let Err(e) = is_controller(&caller).await {
-        return Err(MPApiError::Other(format!("{:?}", e)));
-    }
+    is_controller(&caller).await?;
 
     let collections = collections(|collections| collections.clone());
     let collection = collections
@@ -439,18 +1090,12 @@ pub async fn verify_listing(nft_canister_id: Principal, token_id: Nat) -> MPApiR
                 .caller(Principal::anonymous())
                 .operation("cancelListing")
                 .details(vec![
-                    (
-                        "token_id".into(),
-                        DetailValue::U64(convert_nat_to_u64(token_id).unwrap()),
-                    ),
+                    ("token_id".into(), nat_detail(token_id)),
                     (
                         "nft_canister_id".into(),
                         DetailValue::Principal(nft_canister_id),
                     ),
-                    (
-                        "price".into(),
-                        DetailValue::U64(convert_nat_to_u64(price).unwrap()),
-                    ),
+                    ("price".into(), nat_detail(price)),
                     ("seller".into(), DetailValue::Principal(seller)),
                 ])
                 .build()
@@ -468,14 +1113,44 @@ pub async fn verify_listing(nft_canister_id: Principal, token_id: Nat) -> MPApiR
 #[update(name = "makeListing")]
 #[candid_method(update, rename = "makeListing")]
 pub async fn make_listing(nft_canister_id: Principal, token_id: Nat, price: Nat) -> MPApiResult {
+    let seller = ic::caller();
+    list_token(nft_canister_id, token_id.clone(), price.clone(), seller).await?;
+
+    // insert (async with fallback) event to cap
+    insert_sync(
+        IndefiniteEventBuilder::new()
+            .caller(seller)
+            .operation("makeListing")
+            .details(vec![
+                ("token_id".into(), nat_detail(token_id)),
+                (
+                    "nft_canister_id".into(),
+                    DetailValue::Principal(nft_canister_id),
+                ),
+                ("price".into(), nat_detail(price)),
+                ("seller".into(), DetailValue::Principal(seller)),
+            ])
+            .build()
+            .unwrap(),
+    );
+
+    Ok(())
+}
+
+/// Check and commit a listing of `seller`
+pub(crate) async fn list_token(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    price: Nat,
+    seller: Principal,
+) -> MPApiResult {
     let collections = collections(|collections| collections.clone());
     let collection = collections
         .get(&nft_canister_id)
         .ok_or(MPApiError::NonExistentCollection)?;
+    ensure_trading(collection)?;
 
-    let seller = ic::caller();
     let self_id = ic::id();
-    let init_data = init_data(|init_data| init_data.clone());
 
     // check if the NFT is owned by the seller still
     let token_owner = owner_of_non_fungible(
@@ -526,52 +1201,48 @@ pub async fn make_listing(nft_canister_id: Principal, token_id: Nat, price: Nat)
             return Err(MPApiError::InvalidListingStatus);
         }
 
+        // relisting replaces the previous ask, and the previous seller's listing
+        let created = ic::time();
+        unindex_ask(&nft_canister_id, &token_id, &listing.price, listing.created);
+        index_ask(&nft_canister_id, &token_id, &price, created);
+        unindex_user_entry(
+            &mut mp.seller_listings,
+            &listing.seller,
+            &nft_canister_id,
+            &token_id,
+        );
+        index_user_entry(
+            &mut mp.seller_listings,
+            &seller,
+            &nft_canister_id,
+            token_id.clone(),
+        );
+
         *listing = Listing::new(
             price.clone(),
             seller,
             ListingStatus::Created,
-            ic::time(),
-            [
-                (
-                    "Protocol Fee".to_string(),
-                    init_data.owner,
-                    init_data.protocol_fee.clone(),
-                ),
-                (
-                    "Collection Fee".to_string(),
-                    collection.owner,
-                    collection.collection_fee.clone(),
-                ),
-            ]
-            .to_vec(),
-        );
-
-        // insert (async with fallback) event to cap
-        insert_sync(
-            IndefiniteEventBuilder::new()
-                .caller(seller)
-                .operation("makeListing")
-                .details(vec![
-                    (
-                        "token_id".into(),
-                        DetailValue::U64(convert_nat_to_u64(token_id).unwrap()),
-                    ),
-                    (
-                        "nft_canister_id".into(),
-                        DetailValue::Principal(collection.nft_canister_id),
-                    ),
-                    (
-                        "price".into(),
-                        DetailValue::U64(convert_nat_to_u64(price.clone()).unwrap()),
-                    ),
-                    ("seller".into(), DetailValue::Principal(seller)),
-                ])
-                .build()
-                .unwrap(),
+            created,
+            collection_fees(collection),
         );
 
         Ok(())
-    })
+    })?;
+    certify_listing(&nft_canister_id, &token_id);
+    remember_account(&seller);
+
+    // the listing is made either way, a failed fetch is retried by the heartbeat
+    cache_token(
+        &nft_canister_id,
+        &token_id,
+        collection.nft_canister_standard,
+        seller,
+        Some(self_id),
+    )
+    .await
+    .ok();
+
+    Ok(())
 }
 
 /// Make an offer on a given nft
@@ -583,17 +1254,67 @@ pub async fn make_listing(nft_canister_id: Principal, token_id: Nat, price: Nat)
 /// equal to the total of all offers made already, plus the price for the current offer. For example,
 /// if a user has made 2 offers for 1.00 WICP each, and is making an additional offer of 1.00 WICP,
 /// the total allowance should be 3 WICP.
+///
+/// The protocol and collection fees in effect when the offer is made are snapshotted on the
+/// offer, and are the fees charged when it is accepted. Fee changes made afterwards do not
+/// apply to it, unless the buyer updates the offer price, which takes a new snapshot.
+///
+/// * `referrer` - optional registered referrer, credited a share of the protocol fee on acceptance
 #[update(name = "makeOffer")]
 #[candid_method(update, rename = "makeOffer")]
-pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -> MPApiResult {
+pub async fn make_offer(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    price: Nat,
+    referrer: Option<Principal>,
+) -> MPApiResult {
+    let buyer = ic::caller();
+    let token_owner =
+        place_offer(nft_canister_id, token_id.clone(), price.clone(), buyer, referrer).await?;
+
+    let mut details: Vec<(String, DetailValue)> = vec![
+        ("token_id".into(), nat_detail(token_id.clone())),
+        (
+            "nft_canister_id".into(),
+            DetailValue::Principal(nft_canister_id),
+        ),
+        ("price".into(), nat_detail(price.clone())),
+        ("buyer".into(), DetailValue::Principal(buyer)),
+        ("seller".into(), DetailValue::Principal(token_owner)),
+    ];
+    details.extend(referrer.map(|referrer| ("referrer".into(), DetailValue::Principal(referrer))));
+
+    // insert (async with fallback) event to cap
+    insert_sync(
+        IndefiniteEventBuilder::new()
+            .caller(buyer)
+            .operation("makeOffer")
+            .details(details)
+            .build()
+            .unwrap(),
+    );
+
+    Ok(())
+}
+
+/// Check and commit an offer of `buyer`, returning the token owner it was made to
+pub(crate) async fn place_offer(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    price: Nat,
+    buyer: Principal,
+    referrer: Option<Principal>,
+) -> Result<Principal, MPApiError> {
     let collections = collections(|collections| collections.clone());
     let collection = collections
         .get(&nft_canister_id)
         .ok_or(MPApiError::NonExistentCollection)?;
+    ensure_trading(collection)?;
 
-    let buyer = ic::caller();
     let self_id = ic::id();
 
+    validate_referrer(&referrer, &buyer)?;
+
     let token_owner = owner_of_non_fungible(
         &nft_canister_id,
         &token_id,
@@ -602,6 +1323,9 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
     .await?
     .ok_or_else(|| MPApiError::Other("error calling owner_of".to_string()))?;
 
+    // the buyer pays the ledger fee on top of the price, out of the same allowance or deposit
+    let payment = price.clone() + payment_fee(collection);
+
     // check if marketplace has allowance
     let allowance = allowance_fungible(
         &collection.fungible_canister_id,
@@ -609,10 +1333,9 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
         &self_id,
         collection.fungible_canister_standard.clone(),
     )
-    .await
-    .map_err(|_| MPApiError::Other("Error calling allowance".to_string()))?;
+    .await?;
 
-    if allowance.clone() < price.clone() {
+    if allowance < payment {
         return Err(MPApiError::InsufficientFungibleAllowance);
     }
 
@@ -622,10 +1345,9 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
         &buyer,
         collection.fungible_canister_standard.clone(),
     )
-    .await
-    .map_err(|_| MPApiError::Other("Error calling balanceOf".to_string()))?;
+    .await?;
 
-    if balance < price.clone() {
+    if balance < payment {
         return Err(MPApiError::InsufficientFungibleBalance);
     }
 
@@ -638,11 +1360,15 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
             .entry(token_id.clone())
             .or_default();
 
-        offers
+        let offer = offers
             .entry(buyer)
             .and_modify(|offer| {
-                // listing already exists, we are modifying it here
+                // listing already exists, we are modifying it here.
+                // a new price is a new commitment, so it is made under the current fees
+                unindex_bid(&nft_canister_id, &token_id, &offer.price, &buyer);
                 offer.price = price.clone();
+                offer.fee = collection_fees(collection);
+                offer.referrer = referrer;
             })
             .or_insert_with(|| {
                 Offer::new(
@@ -653,8 +1379,27 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
                     token_owner,
                     OfferStatus::Created,
                     ic::time(),
+                    collection_fees(collection),
+                    referrer,
                 )
             });
+        // the token may have changed hands outside marketplace since the offer was made
+        if offer.token_owner != token_owner {
+            unindex_user_entry(
+                &mut mp.received_offers,
+                &offer.token_owner,
+                &nft_canister_id,
+                &(token_id.clone(), buyer),
+            );
+            offer.token_owner = token_owner;
+        }
+        index_bid(&nft_canister_id, &token_id, &price, &buyer);
+        index_user_entry(
+            &mut mp.received_offers,
+            &offer.token_owner,
+            &nft_canister_id,
+            (token_id.clone(), buyer),
+        );
 
         let buyer_offers = mp
             .user_offers
@@ -666,33 +1411,20 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
             buyer_offers.push(token_id.clone());
         }
     });
+    remember_account(&buyer);
 
-    // insert (async with fallback) event to cap
-    insert_sync(
-        IndefiniteEventBuilder::new()
-            .caller(buyer)
-            .operation("makeOffer")
-            .details(vec![
-                (
-                    "token_id".into(),
-                    DetailValue::U64(convert_nat_to_u64(token_id.clone()).unwrap()),
-                ),
-                (
-                    "nft_canister_id".into(),
-                    DetailValue::Principal(nft_canister_id),
-                ),
-                (
-                    "price".into(),
-                    DetailValue::U64(convert_nat_to_u64(price.clone()).unwrap()),
-                ),
-                ("buyer".into(), DetailValue::Principal(buyer)),
-                ("seller".into(), DetailValue::Principal(token_owner)),
-            ])
-            .build()
-            .unwrap(),
-    );
+    // the offer is made either way, a failed fetch is retried by the heartbeat
+    cache_token(
+        &nft_canister_id,
+        &token_id,
+        collection.nft_canister_standard,
+        token_owner,
+        None,
+    )
+    .await
+    .ok();
 
-    Ok(())
+    Ok(token_owner)
 }
 
 /// Direct buy a nft that has been listed
@@ -709,203 +1441,123 @@ pub async fn make_offer(nft_canister_id: Principal, token_id: Nat, price: Nat) -
 /// released to the respective principal ids for the fee recipients and the seller. In the slim case where
 /// a transaction passed all checks, but an error occurred transferring the nft, the buyers balance will
 /// remain on the marketplace for withdraw using the `withdrawFungible` as a fallback
+///
+/// * `referrer` - optional registered referrer, credited a share of the protocol fee
 #[update(name = "directBuy")]
 #[candid_method(update, rename = "directBuy")]
-pub async fn direct_buy(nft_canister_id: Principal, token_id: Nat) -> MPApiResult {
+pub async fn direct_buy(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    referrer: Option<Principal>,
+) -> MPApiResult {
+    let buyer = ic::caller();
+    let sale = buy_listing(nft_canister_id, token_id, buyer, referrer).await?;
+
+    insert_trade_event("directBuy", buyer, &sale, referrer);
+
+    Ok(())
+}
+
+/// buy a listed nft, all of `directBuy` but the CAP event
+pub(crate) async fn buy_listing(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    buyer: Principal,
+    referrer: Option<Principal>,
+) -> Result<Sale, MPApiError> {
     let c = collections(|collections| collections.clone());
     let collection = c
         .get(&nft_canister_id)
         .ok_or(MPApiError::NonExistentCollection)?;
+    ensure_trading(collection)?;
 
-    let buyer = ic::caller();
-    let self_id = ic::id();
+    validate_referrer(&referrer, &buyer)?;
 
     // check listing exists
-    let mut all_listings = marketplace(|mp| mp.listings.clone());
-
-    let listings = all_listings.entry(nft_canister_id).or_default();
-
-    let listing = listings
-        .get_mut(&token_id.clone())
-        .ok_or(MPApiError::InvalidListing)?;
+    let listing = marketplace(|mp| {
+        mp.listings
+            .get(&nft_canister_id)
+            .and_then(|listings| listings.get(&token_id))
+            .cloned()
+    })
+    .ok_or(MPApiError::InvalidListing)?;
 
     // guarding against re-entrancy
     if listing.status != ListingStatus::Created {
         return Err(MPApiError::InvalidListingStatus);
     }
 
-    let price = listing.price.clone();
-
-    // check token owner and operator
-    let token_owner: Principal;
-    let token_metadata = DIP721v2Proxy::token_metadata(&token_id.clone(), &nft_canister_id).await;
-    match token_metadata {
-        Ok(metadata) => {
-            match metadata.owner {
-                Some(principal) => {
-                    // we only care if mp is the operator, disregard current owner/listing
-                    token_owner = principal;
-                }
-                None => return Err(MPApiError::InvalidOwner),
-            }
-            match metadata.operator {
-                Some(principal) => {
-                    if (principal != self_id) {
-                        return Err(MPApiError::InvalidOperator);
-                    }
-                }
-                None => return Err(MPApiError::InvalidOperator),
-            }
-        }
-        Err(e) => return Err(e),
-    }
-
-    // Claim funds from user wallet
-    if let Err(e) = transfer_from_fungible(
-        &buyer,
-        &self_id,
-        &price.clone(),
-        &collection.fungible_canister_id,
-        collection.fungible_canister_standard.clone(),
-    )
-    .await
-    {
-        return Err(e);
-    }
-
-    // Successfully auto deposited fungibles, transfer the nft from marketplace to the buyer
-    if let Err(e) = transfer_from_non_fungible(
-        &token_owner,                     // from
-        &buyer,                           // to
-        &token_id,                        // nft id
-        &nft_canister_id,                 // contract
-        collection.nft_canister_standard, // nft type
+    // check token owner and operator, we only care if mp is the operator, disregard current owner/listing
+    let token_owner = verify_custody_non_fungible(
+        &nft_canister_id,
+        &token_id,
+        collection.nft_canister_standard,
     )
-    .await
-    {
-        // error transferring nft, sale failed
-
-        // send funds back to buyer
-        if transfer_fungible(
-            &buyer,
-            &price.clone(),
-            &collection.fungible_canister_id,
-            collection.fungible_canister_standard.clone(),
-        )
-        .await
-        .is_err()
-        {
-            // auto withdraw failed, fallback to withdrawFungible
-            // add deposited funds to buyer mp balance (fallback to avoid extra transactions/time/cycles)
-            balances_mut(|balances| {
-                *balances
-                    .balances
-                    .entry((collection.fungible_canister_id, buyer))
-                    .or_default() += price.clone();
-            });
-        }
-
-        balances_mut(|balances| {
-            balances.failed_tx_log_entries.push(TxLogEntry::new(
-                buyer,
-                token_owner,
-                format!(
-"direct buy non fungible failed for user {} for contract {} for token id {}; price {:?}; error: {:?}",
-buyer, nft_canister_id, token_id, price.clone(), e,
-)));
-        });
-
-        return Err(e);
-    }
-
-    let total_fees = process_fees(
-        collection.fungible_canister_id,
-        price.clone(),
-        listing.fee.clone(),
-    );
+    .await?;
 
-    // transfer the funds from the MP to the seller, or
-    if transfer_fungible(
-        &token_owner,
-        &(price.clone() - total_fees.clone()),
-        &collection.fungible_canister_id,
-        collection.fungible_canister_standard.clone(),
+    let sale = execute_trade(
+        collection,
+        token_id,
+        buyer,
+        token_owner,
+        listing.price,
+        listing.fee,
+        referrer,
+        TradeType::DirectBuy,
     )
-    .await
-    .is_err()
-    {
-        // fallback to sellers mp balance
-        balances_mut(|balances| {
-            *balances
-                .balances
-                .entry((collection.fungible_canister_id, token_owner))
-                .or_default() += price.clone() - total_fees.clone();
-        });
-    }
-
-    // commit to state
-    remove_listing(&nft_canister_id, &token_id);
-    remove_offer(&nft_canister_id, &token_id, &buyer);
-    inc_volume(&nft_canister_id, &price);
-
-    // insert (async with fallback) event to cap
-    insert_sync(
-        IndefiniteEventBuilder::new()
-            .caller(buyer)
-            .operation("directBuy")
-            .details(vec![
-                (
-                    "token_id".into(),
-                    DetailValue::U64(convert_nat_to_u64(token_id).unwrap()),
-                ),
-                (
-                    "nft_canister_id".into(),
-                    DetailValue::Principal(nft_canister_id),
-                ),
-                ("buyer".into(), DetailValue::Principal(buyer)),
-                ("seller".into(), DetailValue::Principal(token_owner)),
-                (
-                    "price".into(),
-                    DetailValue::U64(convert_nat_to_u64(price).unwrap()),
-                ),
-                (
-                    "total_fees".into(),
-                    DetailValue::U64(convert_nat_to_u64(total_fees).unwrap()),
-                ),
-            ])
-            .build()
-            .unwrap(),
-    );
+    .await?;
+    remember_account(&buyer);
 
-    Ok(())
+    Ok(sale)
 }
 
 /// Accept an offer that has been made on any given nft
+///
+/// * `referrer` - optional registered referrer, credited a share of the protocol fee.
+/// Takes precedence over the referrer the offer was made with
 #[update(name = "acceptOffer")]
 #[candid_method(update, rename = "acceptOffer")]
 pub async fn accept_offer(
     nft_canister_id: Principal,
     token_id: Nat,
     buyer: Principal,
+    referrer: Option<Principal>,
 ) -> MPApiResult {
+    let seller = ic::caller();
+    let (sale, referrer) = sell_to_offer(nft_canister_id, token_id, buyer, seller, referrer).await?;
+
+    insert_trade_event("acceptOffer", seller, &sale, referrer);
+
+    Ok(())
+}
+
+/// sell a nft to an offer made on it, all of `acceptOffer` but the CAP event
+///
+/// returns the sale, and the referrer of the trade
+pub(crate) async fn sell_to_offer(
+    nft_canister_id: Principal,
+    token_id: Nat,
+    buyer: Principal,
+    seller: Principal,
+    referrer: Option<Principal>,
+) -> Result<(Sale, Option<Principal>), MPApiError> {
     let c = collections(|collections| collections.clone());
     let collection = c
         .get(&nft_canister_id)
         .ok_or(MPApiError::NonExistentCollection)?;
+    ensure_trading(collection)?;
 
-    let seller = ic::caller();
-    let self_id = ic::id();
+    validate_referrer(&referrer, &seller)?;
 
-    let mut offers = marketplace(|mp| mp.offers.clone());
-    let token_offers = offers
-        .entry(nft_canister_id)
-        .or_default()
-        .entry(token_id.clone())
-        .or_default();
-    let offer = token_offers.get(&buyer).ok_or(MPApiError::InvalidListing)?;
-    let offer_price = offer.price.clone();
-
-    let init_data = init_data(|init_data| init_data.clone());
+    let offer = marketplace(|mp| {
+        mp.offers
+            .get(&nft_canister_id)
+            .and_then(|offers| offers.get(&token_id))
+            .and_then(|token_offers| token_offers.get(&buyer))
+            .cloned()
+    })
+    .ok_or(MPApiError::InvalidListing)?;
+    let referrer = referrer.or(offer.referrer);
 
     // guarding against re-entrancy
     if offer.status != OfferStatus::Created {
@@ -913,43 +1565,64 @@ pub async fn accept_offer(
     }
 
     // check token owner and operator
-    let token_owner: Principal;
-    let token_metadata = DIP721v2Proxy::token_metadata(&token_id.clone(), &nft_canister_id).await;
-    match token_metadata {
-        Ok(metadata) => {
-            match metadata.owner {
-                Some(principal) => {
-                    // error if caller is not the token owner
-                    if (principal != seller) {
-                        return Err(MPApiError::Unauthorized);
-                    }
-                    token_owner = principal;
-                }
-                None => return Err(MPApiError::InvalidOwner),
-            }
-            match metadata.operator {
-                Some(principal) => {
-                    if (principal != self_id) {
-                        return Err(MPApiError::InvalidOperator);
-                    }
-                }
-                None => return Err(MPApiError::InvalidOperator),
-            }
-        }
-        Err(e) => return Err(e),
+    let token_owner = verify_custody_non_fungible(
+        &nft_canister_id,
+        &token_id,
+        collection.nft_canister_standard,
+    )
+    .await?;
+
+    // error if caller is not the token owner
+    if (token_owner != seller) {
+        return Err(MPApiError::Unauthorized);
     }
 
+    // the offer is charged the fees snapshotted when it was made
+    let sale = execute_trade(
+        collection,
+        token_id,
+        buyer,
+        seller,
+        offer.price,
+        offer.fee,
+        referrer,
+        TradeType::AcceptOffer,
+    )
+    .await?;
+    remember_account(&seller);
+
+    Ok((sale, referrer))
+}
+
+/// Take the buyer's payment, move the nft to the buyer and settle the trade
+///
+/// When the nft cannot be moved the payment is sent back to the buyer, or held in their balance
+/// for `withdrawFungible` if that fails too. The seller's payout falls back to their balance
+/// the same way
+async fn execute_trade(
+    collection: &Collection,
+    token_id: Nat,
+    buyer: Principal,
+    seller: Principal,
+    price: Nat,
+    fee: Vec<(String, Principal, Nat)>,
+    referrer: Option<Principal>,
+    trade_type: TradeType,
+) -> Result<Sale, MPApiError> {
+    let nft_canister_id = collection.nft_canister_id;
+    let fungible_canister_id = collection.fungible_canister_id;
+
     // Claim funds from user wallet
     transfer_from_fungible(
         &buyer,
-        &self_id,
-        &offer_price.clone(),
-        &collection.fungible_canister_id,
+        &ic::id(),
+        &price,
+        &fungible_canister_id,
         collection.fungible_canister_standard.clone(),
     )
     .await?;
 
-    // Successfully auto deposited fungibles, transfer the nft from marketplace to the buyer
+    // Successfully auto deposited fungibles, transfer the nft from the seller to the buyer
     if let Err(e) = transfer_from_non_fungible(
         &seller,                          // from
         &buyer,                           // to
@@ -964,8 +1637,8 @@ pub async fn accept_offer(
         // send funds back to buyer
         if transfer_fungible(
             &buyer,
-            &offer_price.clone(),
-            &collection.fungible_canister_id,
+            &price,
+            &fungible_canister_id,
             collection.fungible_canister_standard.clone(),
         )
         .await
@@ -976,95 +1649,119 @@ pub async fn accept_offer(
             balances_mut(|balances| {
                 *balances
                     .balances
-                    .entry((collection.fungible_canister_id, buyer))
-                    .or_default() += offer_price.clone();
+                    .entry((fungible_canister_id, buyer))
+                    .or_default() += price.clone();
             });
+            certify_balance(&fungible_canister_id, &buyer);
         }
 
-        balances_mut(|balances| {
-            balances.failed_tx_log_entries.push(TxLogEntry::new(
+        let entry = match trade_type {
+            TradeType::DirectBuy => TxLogEntry::new(
+                buyer,
+                seller,
+                format!(
+"direct buy non fungible failed for user {} for contract {} for token id {}; price {:?}; error: {:?}",
+buyer, nft_canister_id, token_id, price, e,
+)),
+            TradeType::AcceptOffer => TxLogEntry::new(
                 seller,
                 buyer,
                 format!(
 "accept offer non fungible failed for user {} for contract {} for token id {}; price: {:?}; error: {:?}",
-seller, nft_canister_id, token_id, offer_price.clone(), e,
-)));
+seller, nft_canister_id, token_id, price, e,
+)),
+        };
+        balances_mut(|balances| {
+            balances.failed_tx_log_entries.push(entry);
         });
+
         return Err(e);
     }
 
+    let trade_id = next_trade_id();
     let total_fees = process_fees(
-        collection.fungible_canister_id,
-        offer_price.clone(),
-        [
-            (
-                "Protocol Fee".to_string(),
-                init_data.owner,
-                init_data.protocol_fee.clone(),
-            ),
-            (
-                "Collection Fee".to_string(),
-                collection.owner,
-                collection.collection_fee.clone(),
-            ),
-        ]
-        .to_vec(),
+        trade_id,
+        nft_canister_id,
+        &token_id,
+        fungible_canister_id,
+        price.clone(),
+        fee,
+        referrer,
     );
 
-    // successfully transferred nft to buyer, release funds to seller
+    // successfully transferred nft to buyer, release funds to seller, or
     if transfer_fungible(
         &seller,
-        &(offer_price.clone() - total_fees.clone()),
-        &collection.fungible_canister_id,
+        &(price.clone() - total_fees.clone()),
+        &fungible_canister_id,
         collection.fungible_canister_standard.clone(),
     )
     .await
     .is_err()
     {
-        // add deposited funds to seller balance
+        // fallback to sellers mp balance
         balances_mut(|balances| {
             *balances
                 .balances
-                .entry((collection.fungible_canister_id, seller))
-                .or_default() += offer_price.clone() - total_fees.clone();
+                .entry((fungible_canister_id, seller))
+                .or_default() += price.clone() - total_fees.clone();
         });
+        certify_balance(&fungible_canister_id, &seller);
     }
 
     // commit to state
     remove_listing(&nft_canister_id, &token_id);
     remove_offer(&nft_canister_id, &token_id, &buyer);
-    inc_volume(&nft_canister_id, &offer_price);
+    transfer_offers(&nft_canister_id, &token_id, &buyer);
+    inc_volume(&nft_canister_id, &price);
+    record_sale(&nft_canister_id, &price, buyer, seller);
+    let sale = Sale::new(
+        trade_id,
+        nft_canister_id,
+        token_id.clone(),
+        fungible_canister_id,
+        buyer,
+        seller,
+        price,
+        total_fees,
+        trade_type,
+        ic::time(),
+    );
+    log_sale(sale.clone());
+    settle_cached_token(&nft_canister_id, &token_id, buyer);
+
+    Ok(sale)
+}
+
+/// insert (async with fallback) the event of a trade to cap
+fn insert_trade_event(
+    operation: &str,
+    caller: Principal,
+    sale: &Sale,
+    referrer: Option<Principal>,
+) {
+    let mut details: Vec<(String, DetailValue)> = vec![
+        ("token_id".into(), nat_detail(sale.token_id.clone())),
+        (
+            "nft_canister_id".into(),
+            DetailValue::Principal(sale.nft_canister_id),
+        ),
+        ("buyer".into(), DetailValue::Principal(sale.buyer)),
+        ("seller".into(), DetailValue::Principal(sale.seller)),
+        ("price".into(), nat_detail(sale.price.clone())),
+        ("total_fees".into(), nat_detail(sale.fees.clone())),
+        ("trade_id".into(), DetailValue::U64(sale.trade_id)),
+    ];
+    details.extend(referrer.map(|referrer| ("referrer".into(), DetailValue::Principal(referrer))));
 
-    // insert (async with fallback) event to cap
     insert_sync(
         IndefiniteEventBuilder::new()
-            .caller(seller)
-            .operation("acceptOffer")
-            .details(vec![
-                (
-                    "token_id".into(),
-                    DetailValue::U64(convert_nat_to_u64(token_id.clone()).unwrap()),
-                ),
-                (
-                    "nft_canister_id".into(),
-                    DetailValue::Principal(nft_canister_id),
-                ),
-                ("buyer".into(), DetailValue::Principal(buyer)),
-                ("seller".into(), DetailValue::Principal(seller)),
-                (
-                    "price".into(),
-                    DetailValue::U64(convert_nat_to_u64(offer_price.clone()).unwrap()),
-                ),
-                (
-                    "total_fees".into(),
-                    DetailValue::U64(convert_nat_to_u64(total_fees).unwrap()),
-                ),
-            ])
+            .caller(caller)
+            .operation(operation)
+            .details(details)
             .build()
             .unwrap(),
     );
-
-    Ok(())
 }
 
 /// Cancel a created listing
@@ -1104,18 +1801,12 @@ pub async fn cancel_listing(nft_canister_id: Principal, token_id: Nat) -> MPApiR
             .caller(seller)
             .operation("cancelListing")
             .details(vec![
-                (
-                    "token_id".into(),
-                    DetailValue::U64(convert_nat_to_u64(token_id).unwrap()),
-                ),
+                ("token_id".into(), nat_detail(token_id)),
                 (
                     "nft_canister_id".into(),
                     DetailValue::Principal(nft_canister_id),
                 ),
-                (
-                    "price".into(),
-                    DetailValue::U64(convert_nat_to_u64(listing.price).unwrap()),
-                ),
+                ("price".into(), nat_detail(listing.price)),
                 ("seller".into(), DetailValue::Principal(seller)),
             ])
             .build()
@@ -1157,6 +1848,7 @@ pub async fn cancel_offer(nft_canister_id: Principal, token_id: Nat) -> MPApiRes
 
     // commit to state
     remove_offer(&nft_canister_id, &token_id, &buyer);
+    remember_account(&buyer);
 
     // insert (async with fallback) event to cap
     insert_sync(
@@ -1164,18 +1856,12 @@ pub async fn cancel_offer(nft_canister_id: Principal, token_id: Nat) -> MPApiRes
             .caller(buyer)
             .operation("cancelOffer")
             .details(vec![
-                (
-                    "token_id".into(),
-                    DetailValue::U64(convert_nat_to_u64(token_id).unwrap()),
-                ),
+                ("token_id".into(), nat_detail(token_id)),
                 (
                     "nft_canister_id".into(),
                     DetailValue::Principal(nft_canister_id),
                 ),
-                (
-                    "price".into(),
-                    DetailValue::U64(convert_nat_to_u64(offer.price).unwrap()),
-                ),
+                ("price".into(), nat_detail(offer.price)),
                 ("buyer".into(), DetailValue::Principal(buyer)),
                 ("seller".into(), DetailValue::Principal(token_owner)),
             ])
@@ -1230,6 +1916,7 @@ pub async fn deny_offer(
 
     // commit to state
     remove_offer(&nft_canister_id, &token_id, &buyer);
+    remember_account(&seller);
 
     // insert (async with fallback) event to cap
     insert_sync(
@@ -1237,18 +1924,12 @@ pub async fn deny_offer(
             .caller(buyer)
             .operation("denyOffer")
             .details(vec![
-                (
-                    "token_id".into(),
-                    DetailValue::U64(convert_nat_to_u64(token_id).unwrap()),
-                ),
+                ("token_id".into(), nat_detail(token_id)),
                 (
                     "nft_canister_id".into(),
                     DetailValue::Principal(nft_canister_id),
                 ),
-                (
-                    "price".into(),
-                    DetailValue::U64(convert_nat_to_u64(offer.price).unwrap()),
-                ),
+                ("price".into(), nat_detail(offer.price)),
                 ("buyer".into(), DetailValue::Principal(buyer)),
             ])
             .build()
@@ -1258,6 +1939,30 @@ pub async fn deny_offer(
     Ok(())
 }
 
+/// Set the subaccount the caller pays from and is paid into on ICRC ledgers, and holds ICRC7
+/// tokens in. `null`, or an all zero subaccount, goes back to the default account
+///
+/// Open offers are paid from the new subaccount when accepted
+#[update(name = "setIcrcSubaccount")]
+#[candid_method(update, rename = "setIcrcSubaccount")]
+pub async fn set_icrc_subaccount(subaccount: Option<Subaccount>) -> MPApiResult {
+    let caller = ic::caller();
+
+    if subaccount.as_ref().map_or(false, |subaccount| subaccount.len() != 32) {
+        return Err(MPApiError::Other("subaccounts are 32 bytes".to_string()));
+    }
+
+    // commit to state
+    icrc_subaccounts_mut(|icrc_subaccounts| {
+        match subaccount.filter(|subaccount| subaccount.iter().any(|byte| *byte != 0)) {
+            Some(subaccount) => icrc_subaccounts.insert(caller, subaccount),
+            None => icrc_subaccounts.remove(&caller),
+        };
+    });
+
+    Ok(())
+}
+
 /// Withdraw Fungible
 ///
 /// this is a fallback method, for withdrawing held fungibles in the marketplace canister.
@@ -1279,30 +1984,52 @@ pub async fn withdraw_fungible(
     if balance.clone() <= Nat::from(0) {
         return Err(MPApiError::InsufficientFungibleBalance);
     }
-    if transfer_fungible(
+    if let Err(e) = transfer_fungible(
         &caller,
         balance,
         &fungible_canister_id,
         fungible_canister_standard.clone(),
     )
     .await
-    .is_err()
     {
         balances_mut(|balances| {
             balances.failed_tx_log_entries.push(TxLogEntry::new(
                 self_id,
                 caller,
-                format!("withdraw failed for user {}", caller,),
+                format!("withdraw failed for user {}: {:?}", caller, e),
             ));
         });
 
-        return Err(MPApiError::TransferFungibleError);
+        return Err(e);
     }
 
     // remove balance
     balances_mut(|balances| {
         balances.balances.remove(&(fungible_canister_id, caller));
     });
+    certify_balance(&fungible_canister_id, &caller);
+
+    Ok(())
+}
+
+/// Withdraw Deposit
+///
+/// refund what is left of the caller's ICP deposit account to their main account, less the ledger fee
+#[update(name = "withdrawDeposit")]
+#[candid_method(update, rename = "withdrawDeposit")]
+pub async fn withdraw_deposit(fungible_canister_id: Principal) -> MPApiResult {
+    let caller = ic::caller();
+
+    // only ledgers collections are traded in
+    let fungible_canister_standard = collections(|collections| {
+        collections
+            .values()
+            .find(|collection| collection.fungible_canister_id == fungible_canister_id)
+            .map(|collection| collection.fungible_canister_standard.clone())
+    })
+    .ok_or(MPApiError::NonExistentCollection)?;
+
+    refund_deposit_fungible(&fungible_canister_id, &caller, fungible_canister_standard).await?;
 
     Ok(())
 }
@@ -1,16 +1,103 @@
-use crate::types::FungibleStandard::DIP20;
-use crate::types::NFTStandard::{DIP721v2, EXT};
+use crate::circuit_breaker::guarded;
+use crate::fungible_proxy::IcrcProxy;
+use crate::types::NFTStandard::{DIP721v1, DIP721v2, EXT, ICRC7};
 use crate::types::*;
-use crate::utils::convert_nat_to_u64;
+use crate::utils::{
+    call_error, convert_nat_to_u64, ext_token_identifier, principal_of_user, standard_mismatch,
+};
 use crate::vendor_types::*;
 
 use ic_kit::{
     candid::{Nat, Principal},
     ic, RejectionCode,
 };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Calls into an nft canister of a given standard
+///
+/// Every standard has an adapter registered for it, endpoints go through the registry so
+/// implementations can be swapped, eg; for in-memory fakes. See `register_nft_adapter`.
+/// Calls through the registry are guarded by the canister's breaker, probes are not.
+pub trait NonFungibleAdapter {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult>;
+
+    /// transfer a token held by marketplace itself
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult>;
+
+    fn owner_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult>;
+
+    fn operator_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult>;
+
+    /// verify the canister implements the standard
+    ///
+    /// returns the interfaces it reports, and whether it supports approvals
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, ProbeResult>;
+
+    /// properties of a token, standards without per token metadata have none
+    fn properties<'a>(
+        &'a self,
+        _contract: &'a Principal,
+        _token_id: &'a Nat,
+    ) -> BoxFuture<'a, PropertiesResult> {
+        Box::pin(async { PropertiesResult::Ok(Vec::new()) })
+    }
+}
+
+pub type ProbeResult = Result<(Vec<String>, bool), MPApiError>;
+pub type PropertiesResult = Result<Vec<(String, GenericValue)>, MPApiError>;
+
+thread_local!(
+    static NFT_ADAPTERS: RefCell<HashMap<NFTStandard, Rc<dyn NonFungibleAdapter>>> =
+        RefCell::new(default_nft_adapters());
+);
+
+fn default_nft_adapters() -> HashMap<NFTStandard, Rc<dyn NonFungibleAdapter>> {
+    let mut adapters: HashMap<NFTStandard, Rc<dyn NonFungibleAdapter>> = HashMap::new();
+    adapters.insert(DIP721v1, Rc::new(DIP721v1Proxy {}));
+    adapters.insert(DIP721v2, Rc::new(DIP721v2Proxy {}));
+    adapters.insert(EXT, Rc::new(EXTProxy {}));
+    adapters.insert(ICRC7, Rc::new(ICRC7Proxy {}));
+    adapters
+}
+
+/// replace the adapter used for a standard
+pub(crate) fn register_nft_adapter(standard: NFTStandard, adapter: Rc<dyn NonFungibleAdapter>) {
+    NFT_ADAPTERS.with(|adapters| {
+        adapters.borrow_mut().insert(standard, adapter);
+    });
+}
+
+pub(crate) fn nft_adapter(standard: NFTStandard) -> Result<Rc<dyn NonFungibleAdapter>, MPApiError> {
+    NFT_ADAPTERS.with(|adapters| {
+        adapters
+            .borrow()
+            .get(&standard)
+            .cloned()
+            .ok_or_else(|| MPApiError::Other(format!("no adapter registered for {:?}", standard)))
+    })
+}
 
-// dynamic dispatch through trait objects is not implemented in rust for
-// async functions, so we do the dispatch manually
 pub async fn transfer_from_non_fungible(
     from: &Principal,
     to: &Principal,
@@ -18,10 +105,8 @@ pub async fn transfer_from_non_fungible(
     contract: &Principal,
     nft_type: NFTStandard,
 ) -> NatResult {
-    match nft_type {
-        DIP721v2 => DIP721v2Proxy::transfer_from(from, to, token_id, contract).await,
-        EXT => unimplemented!(),
-    }
+    let adapter = nft_adapter(nft_type)?;
+    guarded(contract, adapter.transfer_from(contract, from, to, token_id)).await
 }
 
 pub async fn transfer_non_fungible(
@@ -30,10 +115,8 @@ pub async fn transfer_non_fungible(
     contract: &Principal,
     nft_type: NFTStandard,
 ) -> Result<Nat, MPApiError> {
-    match nft_type {
-        DIP721v2 => DIP721v2Proxy::transfer(contract, to, token_id).await,
-        EXT => EXTProxy::transfer(to, token_id, contract).await,
-    }
+    let adapter = nft_adapter(nft_type)?;
+    guarded(contract, adapter.transfer(contract, to, token_id)).await
 }
 
 pub async fn owner_of_non_fungible(
@@ -41,10 +124,8 @@ pub async fn owner_of_non_fungible(
     token_id: &Nat,
     nft_type: NFTStandard,
 ) -> PrincipalResult {
-    match nft_type {
-        DIP721v2 => DIP721v2Proxy::owner_of(contract, token_id).await,
-        EXT => unimplemented!(),
-    }
+    let adapter = nft_adapter(nft_type)?;
+    guarded(contract, adapter.owner_of(contract, token_id)).await
 }
 
 pub async fn operator_of_non_fungible(
@@ -52,9 +133,38 @@ pub async fn operator_of_non_fungible(
     token_id: &Nat,
     nft_type: NFTStandard,
 ) -> PrincipalResult {
-    match nft_type {
-        DIP721v2 => DIP721v2Proxy::operator_of(contract, token_id).await,
-        EXT => unimplemented!(),
+    let adapter = nft_adapter(nft_type)?;
+    guarded(contract, adapter.operator_of(contract, token_id)).await
+}
+
+pub async fn properties_non_fungible(
+    contract: &Principal,
+    token_id: &Nat,
+    nft_type: NFTStandard,
+) -> PropertiesResult {
+    let adapter = nft_adapter(nft_type)?;
+    guarded(contract, adapter.properties(contract, token_id)).await
+}
+
+pub async fn probe_non_fungible(contract: &Principal, nft_type: NFTStandard) -> ProbeResult {
+    nft_adapter(nft_type)?.probe(contract).await
+}
+
+/// verify marketplace can settle a token, ie; it has an owner and marketplace is its operator
+///
+/// returns the current owner
+pub async fn verify_custody_non_fungible(
+    contract: &Principal,
+    token_id: &Nat,
+    nft_type: NFTStandard,
+) -> Result<Principal, MPApiError> {
+    let token_owner = owner_of_non_fungible(contract, token_id, nft_type)
+        .await?
+        .ok_or(MPApiError::InvalidOwner)?;
+
+    match operator_of_non_fungible(contract, token_id, nft_type).await? {
+        Some(principal) if principal == ic::id() => Ok(token_owner),
+        _ => Err(MPApiError::InvalidOperator),
     }
 }
 
@@ -69,9 +179,9 @@ impl DIP721v2Proxy {
             ic::call(*contract, "tokenMetadata", (token_id.clone(),)).await;
 
         call_res
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))?
+            .map_err(|err| call_error(contract, "tokenMetadata", err))?
             .0
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))
+            .map_err(|err| call_error(contract, "tokenMetadata", err))
     }
 
     pub async fn transfer_from(
@@ -84,9 +194,9 @@ impl DIP721v2Proxy {
             ic::call(*contract, "transferFrom", (*from, *to, token_id.clone())).await;
 
         call_res
-            .map_err(|err| MPApiError::TransferFromNonFungibleError(format!("{:?}", err)))?
+            .map_err(|err| call_error(contract, "transferFrom", err))?
             .0
-            .map_err(|err| MPApiError::TransferFromNonFungibleError(format!("{:?}", err)))
+            .map_err(|err| call_error(contract, "transferFrom", err))
     }
 
     pub async fn transfer(
@@ -97,16 +207,10 @@ impl DIP721v2Proxy {
         let call_res: Result<(Result<Nat, NftError>,), (RejectionCode, String)> =
             ic::call(*contract, "transfer", (*to, token_id.clone())).await;
 
-        let res = call_res
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))?
+        call_res
+            .map_err(|err| call_error(contract, "transfer", err))?
             .0
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))
-            .map(|res| convert_nat_to_u64(res).unwrap());
-
-        match &res {
-            Ok(val) => Ok(Nat::from(*val)),
-            _ => Err(MPApiError::TransferFungibleError),
-        }
+            .map_err(|err| call_error(contract, "transfer", err))
     }
 
     pub async fn owner_of(
@@ -117,9 +221,9 @@ impl DIP721v2Proxy {
             ic::call(*contract, "ownerOf", (token_id.clone(),)).await;
 
         call_res
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))?
+            .map_err(|err| call_error(contract, "ownerOf", err))?
             .0
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))
+            .map_err(|err| call_error(contract, "ownerOf", err))
     }
 
     pub async fn operator_of(
@@ -130,40 +234,614 @@ impl DIP721v2Proxy {
             ic::call(*contract, "operatorOf", (token_id.clone(),)).await;
 
         call_res
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))?
+            .map_err(|err| call_error(contract, "operatorOf", err))?
             .0
-            .map_err(|err| MPApiError::Other(format!("{:?}", err)))
+            .map_err(|err| call_error(contract, "operatorOf", err))
+    }
+
+    pub async fn probe(contract: &Principal) -> ProbeResult {
+        let call_res: Result<(Vec<SupportedInterface>,), (RejectionCode, String)> =
+            ic::call(*contract, "supportedInterfaces", ()).await;
+
+        let interfaces = call_res
+            .map_err(|err| standard_mismatch(contract, DIP721v2, err))?
+            .0;
+
+        // DIP721v1 canisters can answer `supportedInterfaces` too, only v2 serves `metadata`
+        let call_res: Result<(Dip721Metadata,), (RejectionCode, String)> =
+            ic::call(*contract, "metadata", ()).await;
+        call_res.map_err(|err| standard_mismatch(contract, DIP721v2, err))?;
+
+        Ok((
+            interfaces.iter().map(|i| format!("{:?}", i)).collect(),
+            interfaces.contains(&SupportedInterface::Approval),
+        ))
+    }
+
+    pub async fn properties(contract: &Principal, token_id: &Nat) -> PropertiesResult {
+        Self::token_metadata(token_id, contract)
+            .await
+            .map(|metadata| metadata.properties)
+    }
+}
+
+impl NonFungibleAdapter for DIP721v2Proxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, token_id, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(contract, to, token_id))
+    }
+
+    fn owner_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::owner_of(contract, token_id))
+    }
+
+    fn operator_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::operator_of(contract, token_id))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, ProbeResult> {
+        Box::pin(Self::probe(contract))
+    }
+
+    fn properties<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PropertiesResult> {
+        Box::pin(Self::properties(contract, token_id))
+    }
+}
+
+pub(crate) struct DIP721v1Proxy {}
+
+impl DIP721v1Proxy {
+    fn token_index(token_id: &Nat) -> Result<u64, MPApiError> {
+        convert_nat_to_u64(token_id.clone()).map_err(MPApiError::Other)
+    }
+
+    /// arguments of `transferFromDip721`, v1 token ids are `nat64`
+    pub fn transfer_from_args(
+        from: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+    ) -> Result<(Principal, Principal, u64), MPApiError> {
+        Ok((*from, *to, Self::token_index(token_id)?))
+    }
+
+    pub async fn transfer_from(
+        from: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        let call_res: Result<(TxReceiptDIP721v1,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "transferFromDip721",
+            Self::transfer_from_args(from, to, token_id)?,
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "transferFromDip721", err))?
+            .0
+            .map_err(|err| call_error(contract, "transferFromDip721", err))
+    }
+
+    pub async fn transfer(
+        contract: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+    ) -> Result<Nat, MPApiError> {
+        // v1 has no plain transfer, marketplace transfers from itself
+        let call_res: Result<(TxReceiptDIP721v1,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "transferFromDip721",
+            Self::transfer_from_args(&ic::id(), to, token_id)?,
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "transferFromDip721", err))?
+            .0
+            .map_err(|err| call_error(contract, "transferFromDip721", err))
+    }
+
+    pub async fn owner_of(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Principal>, MPApiError> {
+        let call_res: Result<(OwnerResultDIP721v1,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "ownerOfDip721",
+            (Self::token_index(token_id)?,),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "ownerOfDip721", err))?
+            .0
+            .map_err(|err| call_error(contract, "ownerOfDip721", err))
+            .map(Some)
+    }
+
+    /// v1 approves either a single token, or every token of an owner. Marketplace is reported
+    /// as the operator when it is approved for all of the owner's tokens
+    pub async fn operator_of(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Principal>, MPApiError> {
+        let call_res: Result<(OwnerResultDIP721v1,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "getApprovedDip721",
+            (Self::token_index(token_id)?,),
+        )
+        .await;
+
+        let self_id = ic::id();
+        // tokens without an approval answer an error
+        let approved = match call_res {
+            Ok((Ok(operator),)) => Some(operator),
+            _ => None,
+        };
+        if approved == Some(self_id) {
+            return Ok(approved);
+        }
+
+        let owner = match Self::owner_of(contract, token_id).await? {
+            Some(owner) => owner,
+            None => return Ok(approved),
+        };
+
+        let call_res: Result<(bool,), (RejectionCode, String)> =
+            ic::call(*contract, "isApprovedForAllDip721", (owner, self_id)).await;
+        let approved_for_all = call_res
+            .map_err(|err| call_error(contract, "isApprovedForAllDip721", err))?
+            .0;
+
+        Ok(Self::operator(approved, approved_for_all))
+    }
+
+    /// the operator of a token approved to `approved`, marketplace when the owner approved it
+    /// for all of their tokens
+    pub fn operator(approved: Option<Principal>, approved_for_all: bool) -> Option<Principal> {
+        match approved_for_all {
+            true => Some(ic::id()),
+            false => approved,
+        }
+    }
+
+    pub async fn properties(contract: &Principal, token_id: &Nat) -> PropertiesResult {
+        let call_res: Result<(MetadataResultDIP721v1,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "getMetadataDip721",
+            (Self::token_index(token_id)?,),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "getMetadataDip721", err))?
+            .0
+            .map_err(|err| call_error(contract, "getMetadataDip721", err))
+            .map(Self::properties_of)
+    }
+
+    /// flatten v1 metadata parts into properties, the key values of every part followed by
+    /// the part's data under its purpose, eg; `Rendered`
+    pub fn properties_of(metadata: MetadataDesc) -> Vec<(String, GenericValue)> {
+        let mut properties = Vec::new();
+        for part in metadata {
+            properties.extend(part.key_val_data.into_iter().map(|key_val| {
+                let val = match key_val.val {
+                    MetadataVal::TextContent(text) => GenericValue::TextContent(text),
+                    MetadataVal::BlobContent(blob) => GenericValue::BlobContent(blob),
+                    MetadataVal::NatContent(nat) => GenericValue::NatContent(nat),
+                    MetadataVal::Nat8Content(nat) => GenericValue::Nat8Content(nat),
+                    MetadataVal::Nat16Content(nat) => GenericValue::Nat16Content(nat),
+                    MetadataVal::Nat32Content(nat) => GenericValue::Nat32Content(nat),
+                    MetadataVal::Nat64Content(nat) => GenericValue::Nat64Content(nat),
+                };
+                (key_val.key, val)
+            }));
+            if !part.data.is_empty() {
+                properties.push((
+                    format!("{:?}", part.purpose),
+                    GenericValue::BlobContent(part.data),
+                ));
+            }
+        }
+
+        properties
+    }
+
+    pub async fn probe(contract: &Principal) -> ProbeResult {
+        let call_res: Result<(Vec<InterfaceId>,), (RejectionCode, String)> =
+            ic::call(*contract, "supportedInterfacesDip721", ()).await;
+
+        let interfaces = call_res
+            .map_err(|err| standard_mismatch(contract, DIP721v1, err))?
+            .0;
+
+        Ok((
+            interfaces.iter().map(|i| format!("{:?}", i)).collect(),
+            interfaces.contains(&InterfaceId::Approval),
+        ))
+    }
+}
+
+impl NonFungibleAdapter for DIP721v1Proxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, token_id, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(contract, to, token_id))
+    }
+
+    fn owner_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::owner_of(contract, token_id))
+    }
+
+    fn operator_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::operator_of(contract, token_id))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, ProbeResult> {
+        Box::pin(Self::probe(contract))
+    }
+
+    fn properties<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PropertiesResult> {
+        Box::pin(Self::properties(contract, token_id))
     }
 }
 
 pub(crate) struct EXTProxy {}
 
 impl EXTProxy {
+    /// request moving a token between principals, EXT names tokens by their token identifier
+    pub fn transfer_request(
+        contract: &Principal,
+        from: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+    ) -> Result<TransferRequest, MPApiError> {
+        Ok(TransferRequest {
+            from: User::principal(*from),
+            to: User::principal(*to),
+            token: ext_token_identifier(contract, token_id)?,
+            amount: Nat::from(1),
+            memo: vec![],
+            notify: false,
+            subaccount: None,
+        })
+    }
+
+    /// request the allowance of marketplace on a token, granted by its bearer
+    pub fn allowance_request(
+        contract: &Principal,
+        bearer: AccountIdentifier,
+        token_id: &Nat,
+    ) -> Result<AllowanceRequest, MPApiError> {
+        Ok(AllowanceRequest {
+            owner: User::address(bearer),
+            spender: ic::id(),
+            token: ext_token_identifier(contract, token_id)?,
+        })
+    }
+
     pub async fn transfer_from(
         from: &Principal,
         to: &Principal,
-        token_id: &u64,
+        token_id: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        let call_res: Result<(TransferResponse,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "transfer",
+            (Self::transfer_request(contract, from, to, token_id)?,),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "transfer", err))?
+            .0
+            .map_err(|err| call_error(contract, "transfer", err))
+    }
+
+    pub async fn transfer(
+        to: &Principal,
+        token_id: &Nat,
         contract: &Principal,
-    ) -> U64Result {
-        let call_res: Result<(TxReceiptDIP721v2,), (RejectionCode, String)> = ic::call(
+    ) -> Result<Nat, MPApiError> {
+        let call_res: Result<(TransferResponse,), (RejectionCode, String)> = ic::call(
             *contract,
             "transfer",
-            (TransferRequest {
-                from: User::principal(*from),
-                to: User::principal(*to),
-                token: *token_id,
-                amount: Nat::from(1),
-                memo: vec![],
-                notify: false,
-                subaccount: None,
-            },),
+            (Self::transfer_request(contract, &ic::id(), to, token_id)?,),
         )
         .await;
+
         call_res
-            .map_err(|_| MPApiError::TransferFungibleError)?
+            .map_err(|err| call_error(contract, "transfer", err))?
+            .0
+            .map_err(|err| call_error(contract, "transfer", err))
+    }
+
+    /// account identifier currently holding the token
+    pub async fn bearer(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<AccountIdentifier, MPApiError> {
+        let call_res: Result<(BearerResponse,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "bearer",
+            (ext_token_identifier(contract, token_id)?,),
+        )
+        .await;
+
+        call_res
+            .map_err(|err| call_error(contract, "bearer", err))?
+            .0
+            .map_err(|err| call_error(contract, "bearer", err))
+    }
+
+    /// EXT owners are account identifiers, only the ones marketplace has seen can be mapped back
+    /// to a principal. Unknown owners are returned as `None`
+    pub async fn owner_of(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Principal>, MPApiError> {
+        let bearer = Self::bearer(contract, token_id).await?;
+
+        Ok(Self::owner_of_bearer(bearer))
+    }
+
+    pub fn owner_of_bearer(bearer: AccountIdentifier) -> Option<Principal> {
+        principal_of_user(&User::address(bearer))
+    }
+
+    /// EXT has no operators, only allowances. Marketplace is reported as the operator
+    /// when the token's bearer has allowed marketplace to spend it
+    pub async fn operator_of(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Principal>, MPApiError> {
+        let self_id = ic::id();
+        let bearer = Self::bearer(contract, token_id).await?;
+
+        let call_res: Result<(AllowanceResponse,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "allowance",
+            (Self::allowance_request(contract, bearer, token_id)?,),
+        )
+        .await;
+
+        let allowance = call_res
+            .map_err(|err| call_error(contract, "allowance", err))?
             .0
-            .map_err(|_| MPApiError::TransferFungibleError)
-            .map(|res| convert_nat_to_u64(res).unwrap())
+            .map_err(|err| call_error(contract, "allowance", err))?;
+
+        match allowance > Nat::from(0) {
+            true => Ok(Some(self_id)),
+            false => Ok(None),
+        }
+    }
+
+    /// fungible EXT canisters answer `extensions` as well, so `@ext/nonfungible` is required
+    pub async fn probe(contract: &Principal) -> ProbeResult {
+        let call_res: Result<(Vec<Extension>,), (RejectionCode, String)> =
+            ic::call(*contract, "extensions", ()).await;
+
+        let extensions = call_res
+            .map_err(|err| standard_mismatch(contract, EXT, err))?
+            .0;
+
+        if !extensions.iter().any(|e| e == "@ext/nonfungible") {
+            return Err(standard_mismatch(
+                contract,
+                EXT,
+                "missing @ext/nonfungible".to_string(),
+            ));
+        }
+
+        let approvals = extensions.iter().any(|e| e == "@ext/allowance");
+        Ok((extensions, approvals))
+    }
+
+    /// EXT metadata is an opaque blob, returned as the `metadata` property
+    pub async fn properties(contract: &Principal, token_id: &Nat) -> PropertiesResult {
+        let call_res: Result<(MetadataResponse,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "metadata",
+            (ext_token_identifier(contract, token_id)?,),
+        )
+        .await;
+
+        let metadata = call_res
+            .map_err(|err| call_error(contract, "metadata", err))?
+            .0
+            .map_err(|err| call_error(contract, "metadata", err))?;
+
+        match metadata {
+            ExtMetadata::nonfungible {
+                metadata: Some(blob),
+            } => Ok(vec![("metadata".to_string(), GenericValue::BlobContent(blob))]),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+impl NonFungibleAdapter for EXTProxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, token_id, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(to, token_id, contract))
+    }
+
+    fn owner_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::owner_of(contract, token_id))
+    }
+
+    fn operator_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::operator_of(contract, token_id))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, ProbeResult> {
+        Box::pin(Self::probe(contract))
+    }
+
+    fn properties<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PropertiesResult> {
+        Box::pin(Self::properties(contract, token_id))
+    }
+}
+
+/// ICRC7 collections, using ICRC37 for approvals
+///
+/// Marketplace works with principals, which map to their default account, or to the subaccount
+/// they set with `setIcrcSubaccount`. Tokens held in other subaccounts are reported as having
+/// no owner.
+pub(crate) struct ICRC7Proxy {}
+
+impl ICRC7Proxy {
+    /// marketplace spends the token from the account `from` holds it in
+    pub fn transfer_from_arg(
+        from: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+    ) -> Icrc37TransferFromArg {
+        Icrc37TransferFromArg {
+            spender_subaccount: None,
+            from: IcrcProxy::account(from),
+            to: IcrcProxy::account(to),
+            token_id: token_id.clone(),
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    pub fn transfer_arg(to: &Principal, token_id: &Nat) -> Icrc7TransferArg {
+        Icrc7TransferArg {
+            from_subaccount: None,
+            to: IcrcProxy::account(to),
+            token_id: token_id.clone(),
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    /// ask whether marketplace is approved for a token, by the account holding it
+    pub fn is_approved_arg(owner: Account, token_id: &Nat) -> IsApprovedArg {
+        IsApprovedArg {
+            spender: IcrcProxy::account(&ic::id()),
+            from_subaccount: owner.subaccount,
+            token_id: token_id.clone(),
+        }
+    }
+
+    /// the result of the single transfer in a batch
+    pub fn batch_result<E: Into<CanisterError>>(
+        contract: &Principal,
+        method: &str,
+        mut results: Vec<Option<Result<Nat, E>>>,
+    ) -> NatResult {
+        results
+            .pop()
+            .flatten()
+            .ok_or_else(|| {
+                call_error(
+                    contract,
+                    method,
+                    CanisterError::Reply("transfer not processed".to_string()),
+                )
+            })?
+            .map_err(|err| call_error(contract, method, err))
+    }
+
+    pub async fn transfer_from(
+        from: &Principal,
+        to: &Principal,
+        token_id: &Nat,
+        contract: &Principal,
+    ) -> NatResult {
+        let call_res: Result<(Icrc37TransferFromResult,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "icrc37_transfer_from",
+            (vec![Self::transfer_from_arg(from, to, token_id)],),
+        )
+        .await;
+
+        let results = call_res
+            .map_err(|err| call_error(contract, "icrc37_transfer_from", err))?
+            .0;
+        Self::batch_result(contract, "icrc37_transfer_from", results)
     }
 
     pub async fn transfer(
@@ -171,12 +849,182 @@ impl EXTProxy {
         token_id: &Nat,
         contract: &Principal,
     ) -> Result<Nat, MPApiError> {
-        // // let res = DIP721v2Proxy::transfer_from(&ic::caller(), to, token_id.clone(), contract).await;
+        let call_res: Result<(Icrc7TransferResult,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "icrc7_transfer",
+            (vec![Self::transfer_arg(to, token_id)],),
+        )
+        .await;
+
+        let results = call_res
+            .map_err(|err| call_error(contract, "icrc7_transfer", err))?
+            .0;
+        Self::batch_result(contract, "icrc7_transfer", results)
+    }
+
+    pub async fn owner_of(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Principal>, MPApiError> {
+        let owner = Self::owner_account(contract, token_id).await?;
+
+        Ok(owner.as_ref().and_then(IcrcProxy::principal_of))
+    }
+
+    async fn owner_account(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Account>, MPApiError> {
+        let call_res: Result<(Vec<Option<Account>>,), (RejectionCode, String)> =
+            ic::call(*contract, "icrc7_owner_of", (vec![token_id.clone()],)).await;
+
+        Ok(call_res
+            .map_err(|err| call_error(contract, "icrc7_owner_of", err))?
+            .0
+            .pop()
+            .flatten())
+    }
+
+    /// ICRC37 has no operators, only approvals. Marketplace is reported as the operator
+    /// when the token's owner has approved marketplace for it, from the account holding it
+    pub async fn operator_of(
+        contract: &Principal,
+        token_id: &Nat,
+    ) -> Result<Option<Principal>, MPApiError> {
+        let self_id = ic::id();
+        let owner = match Self::owner_account(contract, token_id).await? {
+            Some(owner) => owner,
+            None => return Ok(None),
+        };
+
+        let call_res: Result<(Vec<bool>,), (RejectionCode, String)> = ic::call(
+            *contract,
+            "icrc37_is_approved",
+            (vec![Self::is_approved_arg(owner, token_id)],),
+        )
+        .await;
+
+        let approved = call_res
+            .map_err(|err| call_error(contract, "icrc37_is_approved", err))?
+            .0
+            .pop()
+            .unwrap_or(false);
+
+        match approved {
+            true => Ok(Some(self_id)),
+            false => Ok(None),
+        }
+    }
+
+    pub async fn probe(contract: &Principal) -> ProbeResult {
+        let call_res: Result<(Vec<SupportedStandard>,), (RejectionCode, String)> =
+            ic::call(*contract, "icrc10_supported_standards", ()).await;
+
+        let standards: Vec<String> = call_res
+            .map_err(|err| standard_mismatch(contract, ICRC7, err))?
+            .0
+            .into_iter()
+            .map(|standard| standard.name)
+            .collect();
+
+        if !standards.iter().any(|s| s == "ICRC-7") {
+            return Err(standard_mismatch(
+                contract,
+                ICRC7,
+                "ICRC-7 is not a supported standard".to_string(),
+            ));
+        }
+
+        let approvals = standards.iter().any(|s| s == "ICRC-37");
+        Ok((standards, approvals))
+    }
+
+    pub async fn properties(contract: &Principal, token_id: &Nat) -> PropertiesResult {
+        let call_res: Result<(Icrc7TokenMetadataResult,), (RejectionCode, String)> =
+            ic::call(*contract, "icrc7_token_metadata", (vec![token_id.clone()],)).await;
+
+        let metadata = call_res
+            .map_err(|err| call_error(contract, "icrc7_token_metadata", err))?
+            .0
+            .pop()
+            .flatten()
+            .unwrap_or_default();
+
+        Ok(metadata
+            .into_iter()
+            .map(|(key, value)| (key, Self::generic_value(value)))
+            .collect())
+    }
+
+    fn generic_value(value: Icrc7Value) -> GenericValue {
+        match value {
+            Icrc7Value::Nat(nat) => GenericValue::NatContent(nat),
+            Icrc7Value::Int(int) => GenericValue::IntContent(int),
+            Icrc7Value::Text(text) => GenericValue::TextContent(text),
+            Icrc7Value::Blob(blob) => GenericValue::BlobContent(blob),
+            // arrays are nested under their indexes
+            Icrc7Value::Array(values) => GenericValue::NestedContent(
+                values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, value)| (index.to_string(), Self::generic_value(value)))
+                    .collect(),
+            ),
+            Icrc7Value::Map(entries) => GenericValue::NestedContent(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, Self::generic_value(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl NonFungibleAdapter for ICRC7Proxy {
+    fn transfer_from<'a>(
+        &'a self,
+        contract: &'a Principal,
+        from: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer_from(from, to, token_id, contract))
+    }
+
+    fn transfer<'a>(
+        &'a self,
+        contract: &'a Principal,
+        to: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, NatResult> {
+        Box::pin(Self::transfer(to, token_id, contract))
+    }
+
+    fn owner_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::owner_of(contract, token_id))
+    }
+
+    fn operator_of<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PrincipalResult> {
+        Box::pin(Self::operator_of(contract, token_id))
+    }
+
+    fn probe<'a>(&'a self, contract: &'a Principal) -> BoxFuture<'a, ProbeResult> {
+        Box::pin(Self::probe(contract))
+    }
 
-        // match res {
-        //     Ok(val) => Ok(Nat::from(val)),
-        //     _ => Err(MPApiError::TransferFungibleError),
-        // }
-        Ok(Nat::from(0))
+    fn properties<'a>(
+        &'a self,
+        contract: &'a Principal,
+        token_id: &'a Nat,
+    ) -> BoxFuture<'a, PropertiesResult> {
+        Box::pin(Self::properties(contract, token_id))
     }
 }
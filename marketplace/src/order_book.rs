@@ -0,0 +1,334 @@
+use crate::stats::*;
+use crate::types::*;
+use crate::utils::*;
+
+use ic_kit::candid::{Nat, Principal};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::iter::Peekable;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+
+// Every listing is an ask and every offer a bid, indexed by price per collection so the floor,
+// the best bid and the market depth are read without scanning the collection. Listings are also
+// indexed by creation time, and pages of listings are read from both in order. The index is
+// derived from the listings and offers, it is kept in step by `remove_listing`, `remove_offer`
+// and where they are made, and rebuilt from them on upgrade.
+
+/// index a listing made at `price` and `created`
+pub(crate) fn index_ask(nft_canister_id: &Principal, token_id: &Nat, price: &Nat, created: u64) {
+    order_books_mut(|order_books| {
+        order_books
+            .asks
+            .entry(*nft_canister_id)
+            .or_default()
+            .insert((price.clone(), token_id.clone()));
+        order_books
+            .listed
+            .entry(*nft_canister_id)
+            .or_default()
+            .insert((created, token_id.clone()));
+    });
+    record_floor(nft_canister_id);
+}
+
+/// drop a listing made at `price` and `created` from the index
+pub(crate) fn unindex_ask(nft_canister_id: &Principal, token_id: &Nat, price: &Nat, created: u64) {
+    order_books_mut(|order_books| {
+        if let Some(asks) = order_books.asks.get_mut(nft_canister_id) {
+            asks.remove(&(price.clone(), token_id.clone()));
+
+            // drop empty books
+            if asks.is_empty() {
+                order_books.asks.remove(nft_canister_id);
+            }
+        }
+        if let Some(listed) = order_books.listed.get_mut(nft_canister_id) {
+            listed.remove(&(created, token_id.clone()));
+
+            if listed.is_empty() {
+                order_books.listed.remove(nft_canister_id);
+            }
+        }
+    });
+    record_floor(nft_canister_id);
+}
+
+/// index a buyer's offer at `price`
+pub(crate) fn index_bid(
+    nft_canister_id: &Principal,
+    token_id: &Nat,
+    price: &Nat,
+    buyer: &Principal,
+) {
+    order_books_mut(|order_books| {
+        order_books
+            .bids
+            .entry(*nft_canister_id)
+            .or_default()
+            .insert((price.clone(), token_id.clone(), *buyer));
+    });
+}
+
+/// drop a buyer's offer made at `price` from the index
+pub(crate) fn unindex_bid(
+    nft_canister_id: &Principal,
+    token_id: &Nat,
+    price: &Nat,
+    buyer: &Principal,
+) {
+    order_books_mut(|order_books| {
+        if let Some(bids) = order_books.bids.get_mut(nft_canister_id) {
+            bids.remove(&(price.clone(), token_id.clone(), *buyer));
+
+            // drop empty books
+            if bids.is_empty() {
+                order_books.bids.remove(nft_canister_id);
+            }
+        }
+    });
+}
+
+/// rebuild the index from the current listings and offers
+pub(crate) fn rebuild_order_books() {
+    let order_books = marketplace(|mp| {
+        let mut order_books = OrderBooks::default();
+
+        for (nft_canister_id, listings) in mp.listings.iter() {
+            let listings: Vec<(&Nat, &Listing)> = listings
+                .iter()
+                .filter(|(_, listing)| listing.status != ListingStatus::Uninitialized)
+                .collect();
+            if listings.is_empty() {
+                continue;
+            }
+
+            let asks: BTreeSet<(Nat, Nat)> = listings
+                .iter()
+                .map(|(token_id, listing)| (listing.price.clone(), (*token_id).clone()))
+                .collect();
+            let listed: BTreeSet<(u64, Nat)> = listings
+                .iter()
+                .map(|(token_id, listing)| (listing.created, (*token_id).clone()))
+                .collect();
+            order_books.asks.insert(*nft_canister_id, asks);
+            order_books.listed.insert(*nft_canister_id, listed);
+        }
+
+        for (nft_canister_id, offers) in mp.offers.iter() {
+            let bids: BTreeSet<(Nat, Nat, Principal)> = offers
+                .iter()
+                .flat_map(|(token_id, offers)| {
+                    offers
+                        .iter()
+                        .map(move |(buyer, offer)| (offer.price.clone(), token_id.clone(), *buyer))
+                })
+                .collect();
+            if !bids.is_empty() {
+                order_books.bids.insert(*nft_canister_id, bids);
+            }
+        }
+
+        order_books
+    });
+
+    order_books_mut(|current| *current = order_books);
+}
+
+/// up to `wanted` listings that `matching` accepts, in `sort` order from after `cursor`, of one
+/// collection or of all of them. Ties are broken by collection and token
+pub(crate) fn ordered_listings<F: Fn(&Principal, &Nat) -> bool>(
+    nft_canister_id: Option<Principal>,
+    sort: ListingSort,
+    cursor: Option<&ListingCursor>,
+    wanted: usize,
+    matching: F,
+) -> Vec<(Principal, Nat)> {
+    order_books(|order_books| match sort {
+        ListingSort::PriceAsc | ListingSort::PriceDesc => {
+            let cursor = cursor.map(|cursor| {
+                let price = cursor.price.clone();
+                let successor = price.clone() + Nat::from(1);
+                (
+                    price,
+                    successor,
+                    cursor.nft_canister_id,
+                    cursor.token_id.clone(),
+                )
+            });
+            let descending = sort == ListingSort::PriceDesc;
+            merge_books(
+                &order_books.asks,
+                nft_canister_id,
+                cursor,
+                descending,
+                wanted,
+                matching,
+            )
+        }
+        ListingSort::CreatedAsc | ListingSort::CreatedDesc => {
+            let cursor = cursor.map(|cursor| {
+                let successor = cursor.created.saturating_add(1);
+                (
+                    cursor.created,
+                    successor,
+                    cursor.nft_canister_id,
+                    cursor.token_id.clone(),
+                )
+            });
+            let descending = sort == ListingSort::CreatedDesc;
+            merge_books(
+                &order_books.listed,
+                nft_canister_id,
+                cursor,
+                descending,
+                wanted,
+                matching,
+            )
+        }
+    })
+}
+
+type Book<'a, K> = Peekable<Box<dyn Iterator<Item = &'a (K, Nat)> + 'a>>;
+
+/// walk the books of listings keyed by `K` in (key, collection, token) order, merging the books
+/// of every collection
+///
+/// `cursor` is the key, the key right after it, the collection and the token of the last
+/// listing of the previous page. Every book starts after it, so no book is scanned from its start
+fn merge_books<K: Ord + Clone, F: Fn(&Principal, &Nat) -> bool>(
+    books: &HashMap<Principal, BTreeSet<(K, Nat)>>,
+    nft_canister_id: Option<Principal>,
+    cursor: Option<(K, K, Principal, Nat)>,
+    descending: bool,
+    wanted: usize,
+    matching: F,
+) -> Vec<(Principal, Nat)> {
+    let mut books: Vec<(Principal, Book<K>)> = books
+        .iter()
+        .filter(|(collection, _)| nft_canister_id.map_or(true, |id| id == **collection))
+        .map(|(collection, book)| {
+            let bound = cursor
+                .as_ref()
+                .map(|(key, successor, cursor_collection, cursor_token)| {
+                    // the cursor's own book resumes right after it. The others resume at its
+                    // key if their listings at that key come after it, past its key otherwise
+                    let from = match collection.cmp(cursor_collection) {
+                        Ordering::Equal => return Excluded((key.clone(), cursor_token.clone())),
+                        Ordering::Less => successor.clone(),
+                        Ordering::Greater => key.clone(),
+                    };
+                    match descending {
+                        true => Excluded((from, Nat::from(0))),
+                        false => Included((from, Nat::from(0))),
+                    }
+                });
+
+            let entries: Box<dyn Iterator<Item = &(K, Nat)>> = match (descending, bound) {
+                (false, None) => Box::new(book.iter()),
+                (false, Some(bound)) => Box::new(book.range((bound, Unbounded))),
+                (true, None) => Box::new(book.iter().rev()),
+                (true, Some(bound)) => Box::new(book.range((Unbounded, bound)).rev()),
+            };
+            (*collection, entries.peekable())
+        })
+        .collect();
+
+    let mut listings = Vec::new();
+    while listings.len() < wanted {
+        // the book whose next listing comes first
+        let mut first: Option<(usize, &(K, Nat), Principal)> = None;
+        for (position, (collection, book)) in books.iter_mut().enumerate() {
+            if let Some(&entry) = book.peek() {
+                let comes_first = first.map_or(true, |(_, first_entry, first_collection)| {
+                    let order = (&entry.0, *collection, &entry.1).cmp(&(
+                        &first_entry.0,
+                        first_collection,
+                        &first_entry.1,
+                    ));
+                    match descending {
+                        true => order == Ordering::Greater,
+                        false => order == Ordering::Less,
+                    }
+                });
+                if comes_first {
+                    first = Some((position, entry, *collection));
+                }
+            }
+        }
+
+        let (position, (_, token_id), collection) = match first {
+            Some(first) => first,
+            None => break,
+        };
+        books[position].1.next();
+        if matching(&collection, token_id) {
+            listings.push((collection, token_id.clone()));
+        }
+    }
+
+    listings
+}
+
+/// lowest listing price of a collection
+pub(crate) fn best_ask(nft_canister_id: &Principal) -> Option<Nat> {
+    order_books(|order_books| {
+        order_books
+            .asks
+            .get(nft_canister_id)
+            .and_then(|asks| asks.iter().next())
+            .map(|(price, _)| price.clone())
+    })
+}
+
+/// highest offer price on any token of a collection
+pub(crate) fn best_bid(nft_canister_id: &Principal) -> Option<Nat> {
+    order_books(|order_books| {
+        order_books
+            .bids
+            .get(nft_canister_id)
+            .and_then(|bids| bids.iter().next_back())
+            .map(|(price, _, _)| price.clone())
+    })
+}
+
+/// up to `depth` price levels per side of a collection's order book
+pub(crate) fn order_book(nft_canister_id: &Principal, depth: u64) -> OrderBook {
+    let depth = depth.min(MAX_PAGE_SIZE) as usize;
+
+    let (asks, bids) = order_books(|order_books| {
+        let asks = order_books
+            .asks
+            .get(nft_canister_id)
+            .map(|asks| price_levels(asks.iter().map(|(price, _)| price), depth))
+            .unwrap_or_default();
+        let bids = order_books
+            .bids
+            .get(nft_canister_id)
+            .map(|bids| price_levels(bids.iter().rev().map(|(price, _, _)| price), depth))
+            .unwrap_or_default();
+
+        (asks, bids)
+    });
+
+    OrderBook::new(
+        asks,
+        bids,
+        best_ask(nft_canister_id),
+        best_bid(nft_canister_id),
+    )
+}
+
+/// group consecutive equal prices into levels, stopping after `depth` levels
+fn price_levels<'a>(prices: impl Iterator<Item = &'a Nat>, depth: usize) -> Vec<OrderBookLevel> {
+    let mut levels: Vec<OrderBookLevel> = Vec::new();
+
+    for price in prices {
+        match levels.last_mut() {
+            Some(level) if &level.price == price => level.orders += 1,
+            _ if levels.len() == depth => break,
+            _ => levels.push(OrderBookLevel::new(price.clone(), 1)),
+        }
+    }
+
+    levels
+}
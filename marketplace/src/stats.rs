@@ -0,0 +1,168 @@
+use crate::order_book::*;
+use crate::types::*;
+use crate::utils::*;
+
+use ic_kit::{
+    candid::{Nat, Principal},
+    ic,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Every sale is recorded in an hourly and a daily bucket of its collection, and so is the floor
+// whenever it may have changed. The trailing 24h window is read from the hourly buckets, the 7d
+// and 30d windows from the daily ones, so they start at the beginning of the oldest day.
+
+pub const HOUR: u64 = 60 * 60 * 1_000_000_000;
+pub const DAY: u64 = 24 * HOUR;
+
+/// how long hourly buckets are kept, in nanoseconds
+pub const HOURLY_STATS_RETENTION: u64 = 2 * DAY;
+
+/// how long daily buckets keep their sale prices, buyers and sellers, in nanoseconds
+pub const STATS_DETAIL_RETENTION: u64 = 30 * DAY;
+
+/// record a completed sale of a collection
+pub(crate) fn record_sale(
+    nft_canister_id: &Principal,
+    price: &Nat,
+    buyer: Principal,
+    seller: Principal,
+) {
+    let floor = best_ask(nft_canister_id);
+
+    update_buckets(nft_canister_id, |bucket| {
+        bucket.volume += price.clone();
+        bucket.sales += 1;
+        bucket.prices.push(price.clone());
+        bucket.buyers.insert(buyer);
+        bucket.sellers.insert(seller);
+        bucket.floor = floor.clone();
+    });
+}
+
+/// record the current floor of a collection, after its listings changed
+pub(crate) fn record_floor(nft_canister_id: &Principal) {
+    let floor = best_ask(nft_canister_id);
+
+    update_buckets(nft_canister_id, |bucket| bucket.floor = floor.clone());
+}
+
+/// update the current hourly and daily buckets of a collection, pruning the expired ones
+fn update_buckets<F: Fn(&mut StatsBucket)>(nft_canister_id: &Principal, update: F) {
+    let now = ic::time();
+
+    stats_mut(|stats| {
+        let hourly = stats.hourly.entry(*nft_canister_id).or_default();
+        update(hourly.entry(now - now % HOUR).or_default());
+        *hourly = hourly.split_off(&now.saturating_sub(HOURLY_STATS_RETENTION));
+
+        let daily = stats.daily.entry(*nft_canister_id).or_default();
+        update(daily.entry(now - now % DAY).or_default());
+        for (_, bucket) in daily.range_mut(..now.saturating_sub(STATS_DETAIL_RETENTION)) {
+            bucket.prices.clear();
+            bucket.buyers.clear();
+            bucket.sellers.clear();
+        }
+    });
+}
+
+/// sales of a collection in buckets starting at or after `since`
+fn window_stats(buckets: Option<&BTreeMap<u64, StatsBucket>>, since: u64) -> WindowStats {
+    let mut volume = Nat::from(0);
+    let mut sales = 0;
+    let mut prices: Vec<Nat> = Vec::new();
+    let mut buyers: BTreeSet<Principal> = BTreeSet::new();
+    let mut sellers: BTreeSet<Principal> = BTreeSet::new();
+
+    for (_, bucket) in buckets.into_iter().flat_map(|buckets| buckets.range(since..)) {
+        volume += bucket.volume.clone();
+        sales += bucket.sales;
+        prices.extend(bucket.prices.iter().cloned());
+        buyers.extend(bucket.buyers.iter());
+        sellers.extend(bucket.sellers.iter());
+    }
+
+    let average_price = match sales {
+        0 => None,
+        sales => Some(volume.clone() / Nat::from(sales)),
+    };
+
+    prices.sort();
+    let mid = prices.len() / 2;
+    let median_price = match prices.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((prices[mid - 1].clone() + prices[mid].clone()) / Nat::from(2)),
+        _ => Some(prices[mid].clone()),
+    };
+
+    WindowStats::new(
+        volume,
+        sales,
+        average_price,
+        median_price,
+        buyers.len() as u64,
+        sellers.len() as u64,
+    )
+}
+
+/// trailing 24h, 7d and 30d stats of a collection, with its current market
+pub(crate) fn collection_stats(collection: &Collection) -> CollectionStats {
+    let nft_canister_id = &collection.nft_canister_id;
+    let now = ic::time();
+    let hour = now - now % HOUR;
+    let day = now - now % DAY;
+
+    let (last_day, last_week, last_month) = stats(|stats| {
+        let hourly = stats.hourly.get(nft_canister_id);
+        let daily = stats.daily.get(nft_canister_id);
+
+        (
+            window_stats(hourly, hour.saturating_sub(23 * HOUR)),
+            window_stats(daily, day.saturating_sub(6 * DAY)),
+            window_stats(daily, day.saturating_sub(29 * DAY)),
+        )
+    });
+
+    let (active_listings, active_offers) = order_books(|order_books| {
+        (
+            order_books.asks.get(nft_canister_id).map_or(0, |asks| asks.len()),
+            order_books.bids.get(nft_canister_id).map_or(0, |bids| bids.len()),
+        )
+    });
+
+    CollectionStats::new(
+        last_day,
+        last_week,
+        last_month,
+        collection.fungible_volume.clone(),
+        best_ask(nft_canister_id),
+        active_listings as u64,
+        active_offers as u64,
+    )
+}
+
+/// volume, sales and floor of a collection per bucket, oldest first
+pub(crate) fn stats_history(
+    nft_canister_id: &Principal,
+    interval: StatsInterval,
+) -> Vec<StatsHistoryEntry> {
+    stats(|stats| {
+        let buckets = match interval {
+            StatsInterval::Hourly => stats.hourly.get(nft_canister_id),
+            StatsInterval::Daily => stats.daily.get(nft_canister_id),
+        };
+
+        buckets
+            .into_iter()
+            .flat_map(|buckets| buckets.iter())
+            .map(|(start, bucket)| {
+                StatsHistoryEntry::new(
+                    *start,
+                    bucket.volume.clone(),
+                    bucket.sales,
+                    bucket.floor.clone(),
+                )
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,1168 @@
+use super::*;
+use crate::fakes::{FakeLedger, FakeNft};
+use ic_kit::{mock_principals, MockContext};
+use num_bigint::BigUint;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+fn nft_canister() -> Principal {
+    Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+}
+
+fn ledger() -> Principal {
+    Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+}
+
+/// register a collection of `nft_canister` traded in `ledger`, with fakes for both canisters
+fn fake_collection(
+    nft_canister_standard: NFTStandard,
+    fungible_canister_standard: FungibleStandard,
+    transfer_fee: u64,
+) -> (Rc<FakeNft>, Rc<FakeLedger>) {
+    let nft = Rc::new(FakeNft::new(&[], true));
+    let fungible = Rc::new(FakeLedger::new(
+        fungible_canister_standard.clone(),
+        transfer_fee,
+    ));
+    register_nft_adapter(nft_canister_standard, nft.clone());
+    register_fungible_adapter(fungible_canister_standard.clone(), fungible.clone());
+
+    collections_mut(|collections| {
+        collections.insert(
+            nft_canister(),
+            Collection::new(
+                mock_principals::john(),
+                Nat::from(500),
+                0,
+                "fakes".to_string(),
+                nft_canister(),
+                nft_canister_standard,
+                ledger(),
+                fungible_canister_standard,
+                Nat::from(0),
+                CollectionCapabilities::new(Vec::new(), true, Nat::from(transfer_fee), true),
+                CollectionStatus::Active,
+            ),
+        );
+    });
+
+    (nft, fungible)
+}
+
+fn fee(label: &str, recipient: Principal, rate: u64) -> (String, Principal, Nat) {
+    (label.to_string(), recipient, Nat::from(rate))
+}
+
+/// 10^`exponent`, eg; a token amount with 18 decimals
+fn pow10(exponent: u32) -> Nat {
+    Nat::from(BigUint::from(10u32).pow(exponent))
+}
+
+fn balance_of(fungible_canister_id: Principal, user: Principal) -> Nat {
+    balances(|balances| {
+        balances
+            .balances
+            .get(&(fungible_canister_id, user))
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+/// what marketplace owes in a fungible, the balances it holds for users and the dust
+fn owed(fungible_canister_id: Principal) -> Nat {
+    balances(|balances| {
+        let held = balances
+            .balances
+            .iter()
+            .filter(|((fungible, _), _)| *fungible == fungible_canister_id)
+            .fold(Nat::from(0), |held, (_, balance)| held + balance.clone());
+        held + balances
+            .dust
+            .get(&fungible_canister_id)
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+/// let marketplace take `amount` from a user, with an allowance or a deposit
+fn fund(fungible: &FakeLedger, owner: &Principal, amount: u64) {
+    match fungible.standard {
+        FungibleStandard::ICP => fungible.deposit(owner, amount),
+        _ => {
+            fungible.mint(owner, amount);
+            fungible.approve(owner, amount);
+        }
+    }
+}
+
+/// what a user holds in a fake ledger, including their deposit
+fn holdings(fungible: &FakeLedger, owner: &Principal) -> Nat {
+    fungible.balance(owner) + fungible.deposit_of(owner)
+}
+
+#[test]
+fn process_fees_credits_amounts_beyond_u64() {
+    MockContext::new().inject();
+    let (nft_canister_id, fungible_canister_id) = (mock_principals::xtc(), mock_principals::bob());
+    let token_id = Nat::from(BigUint::from(u64::MAX) + 1u32);
+    // a million tokens with 18 decimals, about 5 * 10^4 times u64::MAX
+    let price = pow10(24);
+
+    let total_fee = process_fees(
+        1,
+        nft_canister_id,
+        &token_id,
+        fungible_canister_id,
+        price,
+        vec![
+            fee(PROTOCOL_FEE_LABEL, mock_principals::alice(), 250),
+            fee(COLLECTION_FEE_LABEL, mock_principals::john(), 500),
+        ],
+        None,
+    );
+
+    assert_eq!(total_fee, Nat::from(75u32) * pow10(21));
+    assert_eq!(
+        balance_of(fungible_canister_id, mock_principals::alice()),
+        Nat::from(25u32) * pow10(21)
+    );
+    assert_eq!(
+        balance_of(fungible_canister_id, mock_principals::john()),
+        Nat::from(5u32) * pow10(22)
+    );
+    fee_ledger(|fee_ledger| {
+        assert_eq!(fee_ledger.accruals.len(), 2);
+        assert!(fee_ledger
+            .accruals
+            .iter()
+            .all(|accrual| accrual.trade_id == 1 && accrual.token_id == token_id));
+    });
+    balances(|balances| {
+        assert_eq!(
+            balances.dust.get(&fungible_canister_id).cloned(),
+            Some(Nat::from(0))
+        );
+    });
+}
+
+#[test]
+fn process_fees_conserves_prices_near_u64_max() {
+    MockContext::new().inject();
+    let fungible_canister_id = mock_principals::bob();
+    let price = Nat::from(u64::MAX);
+
+    let total_fee = process_fees(
+        1,
+        mock_principals::xtc(),
+        &Nat::from(u64::MAX),
+        fungible_canister_id,
+        price.clone(),
+        vec![
+            fee(PROTOCOL_FEE_LABEL, mock_principals::alice(), 333),
+            fee(COLLECTION_FEE_LABEL, mock_principals::john(), 667),
+        ],
+        None,
+    );
+
+    let expected = (u64::MAX as u128 * 1000 + 10000 - 1) / 10000;
+    assert_eq!(total_fee, Nat::from(expected as u64));
+
+    let credited = balance_of(fungible_canister_id, mock_principals::alice())
+        + balance_of(fungible_canister_id, mock_principals::john());
+    let dust = balances(|balances| balances.dust[&fungible_canister_id].clone());
+    assert_eq!(credited + dust, total_fee);
+}
+
+#[test]
+fn nat_details_overflowing_u64_are_recorded_as_text() {
+    assert!(matches!(
+        nat_detail(Nat::from(u64::MAX)),
+        DetailValue::U64(value) if value == u64::MAX
+    ));
+    assert!(matches!(
+        nat_detail(Nat::from(BigUint::from(u64::MAX) + 1u32)),
+        DetailValue::Text(value) if value == "18446744073709551616"
+    ));
+    assert!(matches!(
+        nat_detail(pow10(30)),
+        DetailValue::Text(value) if value == format!("1{}", "0".repeat(30))
+    ));
+}
+
+#[async_std::test]
+async fn registered_fakes_serve_proxy_calls() {
+    MockContext::new().inject();
+    let (nft, fungible) = fake_collection(NFTStandard::DIP721v2, FungibleStandard::DIP20, 0);
+    let alice = mock_principals::alice();
+    nft.mint(1, &alice);
+    fungible.mint(&alice, 1_000);
+
+    let balance = balance_of_fungible(&ledger(), &alice, FungibleStandard::DIP20).await;
+    assert_eq!(balance.unwrap(), Nat::from(1_000));
+
+    let owner = owner_of_non_fungible(&nft_canister(), &Nat::from(1), NFTStandard::DIP721v2).await;
+    assert_eq!(owner.unwrap(), Some(alice));
+
+    let custody =
+        verify_custody_non_fungible(&nft_canister(), &Nat::from(1), NFTStandard::DIP721v2).await;
+    assert!(matches!(custody, Err(MPApiError::InvalidOperator)));
+
+    nft.approve(1);
+    let custody =
+        verify_custody_non_fungible(&nft_canister(), &Nat::from(1), NFTStandard::DIP721v2).await;
+    assert_eq!(custody.unwrap(), alice);
+}
+
+#[async_std::test]
+async fn rejected_calls_open_the_breaker_and_pause_trading() {
+    MockContext::new().inject();
+    let (_, fungible) = fake_collection(NFTStandard::DIP721v2, FungibleStandard::ICRC2, 0);
+    fungible.rejecting.set(true);
+
+    for _ in 0..BREAKER_THRESHOLD {
+        let balance = balance_of_fungible(
+            &ledger(),
+            &mock_principals::alice(),
+            FungibleStandard::ICRC2,
+        );
+        assert!(matches!(
+            balance.await,
+            Err(MPApiError::CanisterCallError { .. })
+        ));
+    }
+
+    // calls fail fast without reaching the canister
+    let balance = balance_of_fungible(
+        &ledger(),
+        &mock_principals::alice(),
+        FungibleStandard::ICRC2,
+    );
+    assert!(
+        matches!(balance.await, Err(MPApiError::CanisterPaused(canister)) if canister == ledger())
+    );
+    assert_eq!(fungible.calls.borrow().len(), BREAKER_THRESHOLD as usize);
+
+    collections(|collections| {
+        let collection = &collections[&nft_canister()];
+        assert!(matches!(
+            ensure_trading(collection),
+            Err(MPApiError::CanisterPaused(_))
+        ));
+    });
+}
+
+#[async_std::test]
+async fn input_caused_traps_never_trip_a_breaker() {
+    MockContext::new().inject();
+    fake_collection(NFTStandard::DIP721v2, FungibleStandard::ICRC2, 0);
+    let canister = ledger();
+    let trapped = |code, message: String| {
+        guarded(&canister, async move {
+            Err::<(), _>(call_error(&ledger(), "transfer", (code, message)))
+        })
+    };
+
+    for _ in 0..2 * BREAKER_THRESHOLD {
+        let traps = [
+            (
+                RejectionCode::CanisterError,
+                format!("Canister {} trapped explicitly: token not found", ledger()),
+            ),
+            (
+                RejectionCode::CanisterError,
+                format!(
+                    "IC0503: Canister {} trapped: Canister {} is stopped",
+                    ledger(),
+                    ledger()
+                ),
+            ),
+            (
+                RejectionCode::CanisterReject,
+                "insufficient allowance".to_string(),
+            ),
+        ];
+        for (code, message) in traps {
+            assert!(trapped(code, message).await.is_err());
+        }
+    }
+    assert!(check_breaker(&ledger()).is_ok());
+
+    // a stopped canister still does
+    for _ in 0..BREAKER_THRESHOLD {
+        let stopped = format!("IC0508: Canister {} is stopped", ledger());
+        assert!(trapped(RejectionCode::CanisterError, stopped)
+            .await
+            .is_err());
+    }
+    assert!(matches!(
+        check_breaker(&ledger()),
+        Err(MPApiError::CanisterPaused(_))
+    ));
+}
+
+#[async_std::test]
+async fn migrated_collections_are_probed_by_the_heartbeat() {
+    MockContext::new().inject();
+    let (_, fungible) = fake_collection(NFTStandard::EXT, FungibleStandard::ICRC2, 10);
+    collections_mut(|collections| {
+        let collection = collections.get_mut(&nft_canister()).unwrap();
+        collection.capabilities = CollectionCapabilities::default();
+    });
+
+    // payouts would be sent without the ledger fee, trading waits for the probe
+    assert!(recorded_transfer_fee(&ledger()).is_none());
+    collections(|collections| {
+        assert!(matches!(
+            ensure_trading(&collections[&nft_canister()]),
+            Err(MPApiError::CanisterPaused(_))
+        ));
+    });
+
+    // a canister failing its probe is paused like an unhealthy one
+    fungible.rejecting.set(true);
+    probe_collections().await;
+    assert!(matches!(
+        check_breaker(&ledger()),
+        Err(MPApiError::CanisterPaused(_))
+    ));
+    collections(|collections| {
+        let collection = &collections[&nft_canister()];
+        assert!(!collection.capabilities.probed);
+        assert!(collection.status != CollectionStatus::Active);
+    });
+
+    // and probed again once `reprobe_breakers` closes its breaker
+    fungible.rejecting.set(false);
+    breakers_mut(|breakers| breakers.breakers.clear());
+    collections_mut(|collections| {
+        collections.get_mut(&nft_canister()).unwrap().status = CollectionStatus::Active;
+    });
+    probe_collections().await;
+
+    assert_eq!(recorded_transfer_fee(&ledger()), Some(Nat::from(10)));
+    collections(|collections| {
+        assert!(ensure_trading(&collections[&nft_canister()]).is_ok());
+    });
+}
+
+#[async_std::test]
+async fn withdraw_deposit_refunds_through_the_adapter() {
+    let alice = mock_principals::alice();
+    MockContext::new().with_caller(alice).inject();
+    let (_, fungible) = fake_collection(NFTStandard::EXT, FungibleStandard::ICP, 10_000);
+    fungible.deposit(&alice, 50_000);
+
+    withdraw_deposit(ledger()).await.unwrap();
+
+    assert_eq!(fungible.balance(&alice), Nat::from(40_000));
+    assert_eq!(fungible.deposit_of(&alice), Nat::from(0));
+    assert_eq!(*fungible.calls.borrow(), vec!["refund_deposit"]);
+}
+
+#[async_std::test]
+async fn withdraw_deposit_goes_through_the_breaker() {
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    let (_, fungible) = fake_collection(NFTStandard::EXT, FungibleStandard::ICP, 10_000);
+    fungible.rejecting.set(true);
+
+    for _ in 0..BREAKER_THRESHOLD {
+        assert!(matches!(
+            withdraw_deposit(ledger()).await,
+            Err(MPApiError::CanisterCallError { .. })
+        ));
+    }
+
+    assert!(matches!(
+        withdraw_deposit(ledger()).await,
+        Err(MPApiError::CanisterPaused(_))
+    ));
+    assert_eq!(fungible.calls.borrow().len(), BREAKER_THRESHOLD as usize);
+}
+
+#[async_std::test]
+async fn withdraw_deposit_needs_a_ledger_paid_through_deposits() {
+    MockContext::new()
+        .with_caller(mock_principals::alice())
+        .inject();
+    let (_, fungible) = fake_collection(NFTStandard::EXT, FungibleStandard::DIP20, 0);
+
+    assert!(matches!(
+        withdraw_deposit(ledger()).await,
+        Err(MPApiError::Other(_))
+    ));
+    assert!(matches!(
+        withdraw_deposit(mock_principals::xtc()).await,
+        Err(MPApiError::NonExistentCollection)
+    ));
+    assert!(fungible.calls.borrow().is_empty());
+}
+
+#[async_std::test]
+async fn accepted_offers_are_charged_the_fees_they_were_made_under() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    let protocol = mock_principals::xtc();
+    init_data_mut(|init_data| {
+        init_data.owner = protocol;
+        init_data.protocol_fee = Nat::from(200);
+    });
+    let (nft, fungible) = fake_collection(NFTStandard::DIP721v2, FungibleStandard::DIP20, 0);
+    nft.mint(1, &bob);
+    nft.approve(1);
+    fungible.mint(&alice, 10_000);
+    fungible.approve(&alice, 10_000);
+
+    place_offer(nft_canister(), Nat::from(1), Nat::from(10_000), alice, None)
+        .await
+        .unwrap();
+
+    // both fees go up before the offer is accepted
+    init_data_mut(|init_data| init_data.protocol_fee = Nat::from(1_000));
+    collections_mut(|collections| {
+        collections.get_mut(&nft_canister()).unwrap().collection_fee = Nat::from(1_500);
+    });
+
+    let (sale, _) = sell_to_offer(nft_canister(), Nat::from(1), alice, bob, None)
+        .await
+        .unwrap();
+
+    assert_eq!(sale.fees, Nat::from(700));
+    assert_eq!(balance_of(ledger(), protocol), Nat::from(200));
+    assert_eq!(
+        balance_of(ledger(), mock_principals::john()),
+        Nat::from(500)
+    );
+    assert_eq!(fungible.balance(&bob), Nat::from(9_300));
+    assert_eq!(nft.owner(1), Some(alice));
+}
+
+/// bob buys alice's token for 10_000 through a ledger charging a fee of 10
+async fn buy_through_ledger(standard: FungibleStandard) {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    let (nft, fungible) = fake_collection(NFTStandard::DIP721v2, standard, 10);
+    nft.mint(1, &alice);
+    nft.approve(1);
+    fund(&fungible, &bob, 10_010);
+
+    list_token(nft_canister(), Nat::from(1), Nat::from(10_000), alice)
+        .await
+        .unwrap();
+    let sale = buy_listing(nft_canister(), Nat::from(1), bob, None)
+        .await
+        .unwrap();
+
+    assert_eq!(sale.fees, Nat::from(500));
+    assert_eq!(nft.owner(1), Some(bob));
+    // the buyer paid the fee on top of the price, the seller's payout paid its own
+    assert_eq!(holdings(&fungible, &bob), Nat::from(0));
+    assert_eq!(fungible.balance(&alice), Nat::from(9_490));
+    // marketplace holds exactly what it owes, the collection fee
+    assert_eq!(owed(ledger()), Nat::from(500));
+    assert_eq!(fungible.balance(&ic::id()), owed(ledger()));
+}
+
+#[async_std::test]
+async fn dip20_ledgers_charge_the_fee_on_top_of_transfers() {
+    buy_through_ledger(FungibleStandard::DIP20).await;
+}
+
+#[async_std::test]
+async fn icrc2_ledgers_are_declared_the_recorded_fee() {
+    buy_through_ledger(FungibleStandard::ICRC2).await;
+}
+
+#[async_std::test]
+async fn icp_payments_are_swept_from_deposits() {
+    buy_through_ledger(FungibleStandard::ICP).await;
+}
+
+#[test]
+fn payouts_are_sent_less_the_ledger_fee() {
+    MockContext::new().inject();
+    let bob = mock_principals::bob();
+    let (amount, fee) = (Nat::from(1_000), Nat::from(10));
+
+    // DIP20 charges its fee on top of the value sent
+    let (to, value) = Dip20Proxy::transfer_args(&bob, &amount, &fee).unwrap();
+    assert_eq!((to, value), (bob, Nat::from(990)));
+
+    // ICRC ledgers are declared the fee, and reject transfers when it changed
+    let arg = IcrcProxy::transfer_arg(&bob, &amount, &fee).unwrap();
+    assert_eq!(arg.to, IcrcProxy::account(&bob));
+    assert_eq!((arg.amount, arg.fee), (Nat::from(990), Some(fee.clone())));
+
+    assert!(matches!(
+        Dip20Proxy::transfer_args(&bob, &fee, &fee),
+        Err(MPApiError::TransferFungibleError)
+    ));
+    assert!(matches!(
+        IcrcProxy::transfer_arg(&bob, &fee, &fee),
+        Err(MPApiError::TransferFungibleError)
+    ));
+}
+
+#[async_std::test]
+async fn icp_transfers_use_deposit_subaccounts() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    assert_ne!(deposit_subaccount(&alice), deposit_subaccount(&bob));
+
+    // payments are swept from the account users are told to deposit into
+    let args = IcpProxy::transfer_from_args(&alice, &ic::id(), &Nat::from(1_000), 10).unwrap();
+    let mut subaccount = [0; 32];
+    subaccount.copy_from_slice(&args.from_subaccount.unwrap());
+    assert_eq!(
+        get_deposit_account(alice).await,
+        account_identifier(&ic::id(), Some(subaccount))
+    );
+    assert_eq!(args.to, account_identifier_bytes(&ic::id(), None).to_vec());
+    assert_eq!((args.amount.e8s, args.fee.e8s), (1_000, 10));
+
+    // payouts are sent from marketplace's main account
+    let args = IcpProxy::transfer_args(&bob, &Nat::from(1_000), &Nat::from(10)).unwrap();
+    assert_eq!(args.from_subaccount, None);
+    assert_eq!(args.to, account_identifier_bytes(&bob, None).to_vec());
+    assert_eq!((args.amount.e8s, args.fee.e8s), (990, 10));
+
+    // refunds go from the deposit to the user's main account
+    let args = IcpProxy::refund_args(&alice, 1_000, 10).unwrap();
+    assert_eq!(args.from_subaccount, Some(subaccount.to_vec()));
+    assert_eq!(args.to, account_identifier_bytes(&alice, None).to_vec());
+    assert_eq!(args.amount.e8s, 990);
+    assert!(matches!(
+        IcpProxy::refund_args(&alice, 10, 10),
+        Err(MPApiError::InsufficientFungibleBalance)
+    ));
+}
+
+#[test]
+fn ext_requests_name_tokens_by_identifier_and_owners_by_account() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    let token = ext_token_identifier(&nft_canister(), &Nat::from(1)).unwrap();
+
+    assert_eq!(
+        EXTProxy::transfer_request(&nft_canister(), &alice, &bob, &Nat::from(1)).unwrap(),
+        TransferRequest {
+            from: User::principal(alice),
+            to: User::principal(bob),
+            token: token.clone(),
+            amount: Nat::from(1),
+            memo: vec![],
+            notify: false,
+            subaccount: None,
+        }
+    );
+    // EXT token indexes are nat32
+    let beyond_u32 = Nat::from(u64::from(u32::MAX) + 1);
+    assert!(EXTProxy::transfer_request(&nft_canister(), &alice, &bob, &beyond_u32).is_err());
+
+    let bearer = account_identifier(&alice, None);
+    assert_eq!(
+        EXTProxy::allowance_request(&nft_canister(), bearer.clone(), &Nat::from(1)).unwrap(),
+        AllowanceRequest {
+            owner: User::address(bearer.clone()),
+            spender: ic::id(),
+            token,
+        }
+    );
+
+    // bearers map back to the principals marketplace has seen
+    assert_eq!(EXTProxy::owner_of_bearer(bearer.clone()), None);
+    remember_account(&alice);
+    assert_eq!(EXTProxy::owner_of_bearer(bearer), Some(alice));
+}
+
+#[test]
+fn dip721v1_operators_include_approvals_for_all() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+
+    assert_eq!(DIP721v1Proxy::operator(Some(bob), false), Some(bob));
+    assert_eq!(DIP721v1Proxy::operator(Some(bob), true), Some(ic::id()));
+    assert_eq!(DIP721v1Proxy::operator(None, true), Some(ic::id()));
+    assert_eq!(DIP721v1Proxy::operator(None, false), None);
+
+    // v1 token ids are nat64
+    let args = DIP721v1Proxy::transfer_from_args(&alice, &bob, &Nat::from(u64::MAX)).unwrap();
+    assert_eq!(args, (alice, bob, u64::MAX));
+    let beyond_u64 = Nat::from(BigUint::from(u64::MAX) + 1u32);
+    assert!(DIP721v1Proxy::transfer_from_args(&alice, &bob, &beyond_u64).is_err());
+}
+
+#[test]
+fn icrc7_requests_use_the_accounts_users_set() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    let subaccount = vec![1; 32];
+    icrc_subaccounts_mut(|icrc_subaccounts| icrc_subaccounts.insert(alice, subaccount.clone()));
+    let alice_account = Account {
+        owner: alice,
+        subaccount: Some(subaccount.clone()),
+    };
+
+    let arg = ICRC7Proxy::transfer_from_arg(&alice, &bob, &Nat::from(1));
+    assert_eq!(arg.from, alice_account);
+    assert_eq!(
+        arg.to,
+        Account {
+            owner: bob,
+            subaccount: None,
+        }
+    );
+    assert_eq!(arg.token_id, Nat::from(1));
+    assert_eq!(
+        ICRC7Proxy::transfer_arg(&alice, &Nat::from(1)).to,
+        alice_account
+    );
+
+    // approvals are checked from the account holding the token
+    let arg = ICRC7Proxy::is_approved_arg(alice_account.clone(), &Nat::from(1));
+    assert_eq!(arg.from_subaccount, Some(subaccount));
+    assert_eq!(arg.spender, IcrcProxy::account(&ic::id()));
+
+    // holders map back to users from the account they set only
+    assert_eq!(IcrcProxy::principal_of(&alice_account), Some(alice));
+    assert_eq!(
+        IcrcProxy::principal_of(&Account {
+            owner: alice,
+            subaccount: None,
+        }),
+        None
+    );
+}
+
+#[test]
+fn icrc7_batches_answer_one_result_per_transfer() {
+    MockContext::new().inject();
+    let batch_result = |results: Vec<Option<Result<Nat, Icrc7TransferError>>>| {
+        ICRC7Proxy::batch_result(&nft_canister(), "icrc7_transfer", results)
+    };
+
+    assert_eq!(
+        batch_result(vec![Some(Ok(Nat::from(7)))]).unwrap(),
+        Nat::from(7)
+    );
+    // not processed
+    assert!(matches!(
+        batch_result(vec![None]),
+        Err(MPApiError::CanisterCallError { .. })
+    ));
+    assert!(matches!(
+        batch_result(Vec::new()),
+        Err(MPApiError::CanisterCallError { .. })
+    ));
+    assert!(matches!(
+        batch_result(vec![Some(Err(Icrc7TransferError::Unauthorized))]),
+        Err(MPApiError::CanisterCallError {
+            error: CanisterError::Icrc7Transfer(Icrc7TransferError::Unauthorized),
+            ..
+        })
+    ));
+}
+
+#[async_std::test]
+async fn icrc1_only_ledgers_are_rejected() {
+    MockContext::new().inject();
+
+    assert!(matches!(
+        probe_fungible(&ledger(), FungibleStandard::ICRC1).await,
+        Err(MPApiError::StandardMismatch {
+            standard: TokenStandard::Fungible(FungibleStandard::ICRC1),
+            reason: MismatchReason::Unsupported(_),
+            ..
+        })
+    ));
+}
+
+#[async_std::test]
+async fn offers_must_cover_the_ledger_fee() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    let (nft, fungible) = fake_collection(NFTStandard::DIP721v2, FungibleStandard::DIP20, 10);
+    nft.mint(1, &bob);
+    fungible.mint(&alice, 10_010);
+    fungible.approve(&alice, 10_000);
+
+    assert!(matches!(
+        place_offer(nft_canister(), Nat::from(1), Nat::from(10_000), alice, None).await,
+        Err(MPApiError::InsufficientFungibleAllowance)
+    ));
+
+    fungible.approve(&alice, 10_010);
+    place_offer(nft_canister(), Nat::from(1), Nat::from(10_000), alice, None)
+        .await
+        .unwrap();
+}
+
+#[async_std::test]
+async fn listings_and_offers_cache_what_they_fetched() {
+    MockContext::new().inject();
+    let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+    let (nft, fungible) = fake_collection(NFTStandard::DIP721v2, FungibleStandard::DIP20, 0);
+    nft.mint(1, &alice);
+    nft.mint(2, &alice);
+    nft.approve(1);
+    fungible.mint(&bob, 1_000);
+    fungible.approve(&bob, 1_000);
+
+    list_token(nft_canister(), Nat::from(1), Nat::from(1_000), alice)
+        .await
+        .unwrap();
+    assert_eq!(
+        *nft.calls.borrow(),
+        vec!["owner_of", "operator_of", "properties"]
+    );
+
+    // the listed token is cached already, an offer on it only fetches the owner
+    nft.calls.borrow_mut().clear();
+    place_offer(nft_canister(), Nat::from(1), Nat::from(500), bob, None)
+        .await
+        .unwrap();
+    assert_eq!(*nft.calls.borrow(), vec!["owner_of"]);
+
+    // an offer on a token that is not cached leaves its operator to the next sweep
+    place_offer(nft_canister(), Nat::from(2), Nat::from(500), bob, None)
+        .await
+        .unwrap();
+    token_cache(|token_cache| {
+        let tokens = &token_cache.tokens[&nft_canister()];
+        assert_eq!(tokens[&Nat::from(1)].owner, Some(alice));
+        assert_eq!(tokens[&Nat::from(1)].operator, Some(ic::id()));
+        assert_eq!(tokens[&Nat::from(2)].owner, Some(alice));
+        assert_eq!(tokens[&Nat::from(2)].refreshed, 0);
+    });
+}
+
+#[test]
+fn dip721v1_metadata_is_flattened_into_properties() {
+    let properties = DIP721v1Proxy::properties_of(vec![MetadataPart {
+        purpose: MetadataPurpose::Rendered,
+        key_val_data: vec![
+            MetadataKeyVal {
+                key: "name".to_string(),
+                val: MetadataVal::TextContent("punk".to_string()),
+            },
+            MetadataKeyVal {
+                key: "rarity".to_string(),
+                val: MetadataVal::Nat8Content(3),
+            },
+        ],
+        data: vec![1, 2],
+    }]);
+
+    assert!(matches!(
+        properties.as_slice(),
+        [
+            (name, GenericValue::TextContent(text)),
+            (rarity, GenericValue::Nat8Content(3)),
+            (rendered, GenericValue::BlobContent(data)),
+        ] if name == "name"
+            && text == "punk"
+            && rarity == "rarity"
+            && rendered == "Rendered"
+            && data == &vec![1, 2]
+    ));
+}
+
+/// commit a listing the way `makeListing` does, without calling the collection
+fn insert_listing(nft_canister_id: Principal, token_id: u64, price: u64, created: u64) {
+    let token_id = Nat::from(token_id);
+    let listing = Listing::new(
+        Nat::from(price),
+        mock_principals::alice(),
+        ListingStatus::Created,
+        created,
+        Vec::new(),
+    );
+    index_ask(&nft_canister_id, &token_id, &listing.price, created);
+    marketplace_mut(|mp| {
+        mp.listings
+            .entry(nft_canister_id)
+            .or_default()
+            .insert(token_id, listing);
+    });
+}
+
+/// every page of `sort`, `limit` listings at a time, as (collection, token)
+fn all_pages(
+    nft_canister_id: Option<Principal>,
+    sort: ListingSort,
+    limit: u64,
+) -> Vec<(Principal, u64)> {
+    let mut listings = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = listings_page(
+            nft_canister_id,
+            &ListingFilter::default(),
+            sort,
+            cursor,
+            limit,
+        );
+        assert!(page.listings.len() <= limit as usize);
+        listings.extend(page.listings.iter().map(|entry| {
+            (
+                entry.nft_canister_id,
+                convert_nat_to_u64(entry.token_id.clone()).unwrap(),
+            )
+        }));
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => return listings,
+        }
+    }
+}
+
+#[test]
+fn listings_are_paged_in_order_across_collections() {
+    MockContext::new().inject();
+    let (a, b) = (mock_principals::bob(), mock_principals::xtc());
+    let (a, b) = (a.min(b), a.max(b));
+    // (collection, token, price, created)
+    for (collection, token_id, price, created) in [
+        (a, 1, 300, 5),
+        (a, 2, 100, 1),
+        (a, 3, 200, 3),
+        (b, 1, 200, 2),
+        (b, 2, 100, 4),
+        (b, 3, 400, 6),
+    ] {
+        insert_listing(collection, token_id, price, created);
+    }
+
+    let by_price = vec![(a, 2), (b, 2), (a, 3), (b, 1), (a, 1), (b, 3)];
+    let by_created = vec![(a, 2), (b, 1), (a, 3), (b, 2), (a, 1), (b, 3)];
+    for limit in 1..=7 {
+        assert_eq!(all_pages(None, ListingSort::PriceAsc, limit), by_price);
+        assert_eq!(all_pages(None, ListingSort::CreatedAsc, limit), by_created);
+
+        let mut descending = by_price.clone();
+        descending.reverse();
+        assert_eq!(all_pages(None, ListingSort::PriceDesc, limit), descending);
+        let mut descending = by_created.clone();
+        descending.reverse();
+        assert_eq!(all_pages(None, ListingSort::CreatedDesc, limit), descending);
+
+        assert_eq!(
+            all_pages(Some(b), ListingSort::PriceAsc, limit),
+            vec![(b, 2), (b, 1), (b, 3)]
+        );
+    }
+
+    // a relisted token moves in both indexes
+    unindex_ask(&a, &Nat::from(2), &Nat::from(100), 1);
+    insert_listing(a, 2, 500, 7);
+    assert_eq!(all_pages(Some(a), ListingSort::PriceDesc, 2)[0], (a, 2));
+    assert_eq!(all_pages(Some(a), ListingSort::CreatedDesc, 2)[0], (a, 2));
+}
+
+/// (user, collection, entry) of every entry of a user index
+fn index_entries<T: Clone + Ord>(
+    index: &HashMap<Principal, HashMap<Principal, Vec<T>>>,
+) -> BTreeSet<(Principal, Principal, T)> {
+    index
+        .iter()
+        .flat_map(|(user, collections)| {
+            collections.iter().flat_map(move |(collection, entries)| {
+                entries
+                    .iter()
+                    .map(move |entry| (*user, *collection, entry.clone()))
+            })
+        })
+        .collect()
+}
+
+/// the indexes match the listings and offers they are derived from
+fn assert_indexes_consistent() {
+    let (seller_listings, received_offers, user_offers) = marketplace(|mp| {
+        (
+            index_entries(&mp.seller_listings),
+            index_entries(&mp.received_offers),
+            index_entries(&mp.user_offers),
+        )
+    });
+    let (asks, bids, listed) = order_books(|order_books| {
+        (
+            order_books.asks.clone(),
+            order_books.bids.clone(),
+            order_books.listed.clone(),
+        )
+    });
+
+    rebuild_user_indexes();
+    rebuild_order_books();
+
+    marketplace(|mp| {
+        assert_eq!(index_entries(&mp.seller_listings), seller_listings);
+        assert_eq!(index_entries(&mp.received_offers), received_offers);
+
+        let offered: BTreeSet<(Principal, Principal, Nat)> = mp
+            .offers
+            .iter()
+            .flat_map(|(collection, offers)| {
+                offers.iter().flat_map(move |(token_id, token_offers)| {
+                    token_offers
+                        .keys()
+                        .map(move |buyer| (*buyer, *collection, token_id.clone()))
+                })
+            })
+            .collect();
+        assert_eq!(user_offers, offered);
+    });
+    order_books(|order_books| {
+        assert_eq!(order_books.asks, asks);
+        assert_eq!(order_books.bids, bids);
+        assert_eq!(order_books.listed, listed);
+    });
+}
+
+fn received_offers_of(owner: Principal) -> Vec<(Nat, Principal)> {
+    marketplace(|mp| {
+        mp.received_offers
+            .get(&owner)
+            .and_then(|collections| collections.get(&nft_canister()))
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+#[async_std::test]
+async fn offers_follow_the_token_to_its_new_owner() {
+    MockContext::new().inject();
+    let (alice, bob, john) = (
+        mock_principals::alice(),
+        mock_principals::bob(),
+        mock_principals::john(),
+    );
+    let (nft, fungible) = fake_collection(NFTStandard::DIP721v2, FungibleStandard::DIP20, 0);
+    nft.mint(1, &alice);
+    nft.mint(2, &alice);
+    for buyer in [bob, john] {
+        fungible.mint(&buyer, 2_000);
+        fungible.approve(&buyer, 2_000);
+    }
+
+    nft.approve(1);
+    list_token(nft_canister(), Nat::from(1), Nat::from(1_000), alice)
+        .await
+        .unwrap();
+    for (buyer, price) in [(bob, 800), (john, 900)] {
+        place_offer(nft_canister(), Nat::from(1), Nat::from(price), buyer, None)
+            .await
+            .unwrap();
+    }
+    place_offer(nft_canister(), Nat::from(2), Nat::from(500), john, None)
+        .await
+        .unwrap();
+    assert_eq!(received_offers_of(alice).len(), 3);
+    assert_indexes_consistent();
+
+    // bob buys the listing, john's offer on it is now made to bob
+    buy_listing(nft_canister(), Nat::from(1), bob, None)
+        .await
+        .unwrap();
+    assert_eq!(received_offers_of(alice), vec![(Nat::from(2), john)]);
+    assert_eq!(received_offers_of(bob), vec![(Nat::from(1), john)]);
+    marketplace(|mp| {
+        let offer = &mp.offers[&nft_canister()][&Nat::from(1)][&john];
+        assert_eq!(offer.token_owner, bob);
+    });
+    assert_indexes_consistent();
+
+    // bob accepts it
+    nft.approve(1);
+    sell_to_offer(nft_canister(), Nat::from(1), john, bob, None)
+        .await
+        .unwrap();
+    assert!(received_offers_of(bob).is_empty());
+    assert_eq!(nft.owner(1), Some(john));
+    assert_indexes_consistent();
+
+    // the token moved outside marketplace, an updated offer is made to its current owner
+    nft.owners.borrow_mut().insert(Nat::from(2), bob);
+    place_offer(nft_canister(), Nat::from(2), Nat::from(600), john, None)
+        .await
+        .unwrap();
+    assert!(received_offers_of(alice).is_empty());
+    assert_eq!(received_offers_of(bob), vec![(Nat::from(2), john)]);
+    assert_indexes_consistent();
+
+    remove_offer(&nft_canister(), &Nat::from(2), &john);
+    assert!(received_offers_of(bob).is_empty());
+    assert_indexes_consistent();
+}
+
+fn sale(trade_id: u64, token_id: u64, buyer: Principal, seller: Principal) -> Sale {
+    Sale::new(
+        trade_id,
+        nft_canister(),
+        Nat::from(token_id),
+        ledger(),
+        buyer,
+        seller,
+        Nat::from(1_000),
+        Nat::from(0),
+        TradeType::DirectBuy,
+        trade_id,
+    )
+}
+
+/// every page of sales, `limit` at a time, as trade ids
+async fn all_sales(user: Principal, limit: u64) -> Vec<u64> {
+    let mut trade_ids = Vec::new();
+    let mut before = None;
+    loop {
+        let page = get_user_sales(user, before, limit).await;
+        assert!(page.sales.len() <= limit as usize);
+        trade_ids.extend(page.sales.iter().map(|sale| sale.trade_id));
+        match page.next {
+            Some(next) => before = Some(next),
+            None => return trade_ids,
+        }
+    }
+}
+
+#[async_std::test]
+async fn sales_are_served_from_their_indexes() {
+    MockContext::new().inject();
+    let (alice, bob, john) = (
+        mock_principals::alice(),
+        mock_principals::bob(),
+        mock_principals::john(),
+    );
+    for (trade_id, token_id, buyer, seller) in [
+        (1, 1, bob, alice),
+        (2, 2, john, alice),
+        (3, 1, john, bob),
+        (4, 3, alice, alice),
+        (5, 1, alice, john),
+    ] {
+        log_sale(sale(trade_id, token_id, buyer, seller));
+        process_fees(
+            trade_id,
+            nft_canister(),
+            &Nat::from(token_id),
+            ledger(),
+            Nat::from(1_000),
+            vec![
+                fee(PROTOCOL_FEE_LABEL, john, 100),
+                fee(COLLECTION_FEE_LABEL, bob, 200),
+            ],
+            None,
+        );
+    }
+
+    for round in 0..2 {
+        let last = get_last_sale(nft_canister(), Nat::from(1)).await.unwrap();
+        assert_eq!(last.trade_id, 5);
+        assert!(get_last_sale(nft_canister(), Nat::from(4)).await.is_none());
+
+        let token = get_token_sales(nft_canister(), Nat::from(1), None, 10).await;
+        let token: Vec<u64> = token.sales.iter().map(|sale| sale.trade_id).collect();
+        assert_eq!(token, vec![5, 3, 1]);
+
+        let collection = get_collection_sales(nft_canister(), None, 2).await;
+        assert_eq!(collection.sales.len(), 2);
+        assert_eq!(collection.sales[0].trade_id, 5);
+
+        for limit in 1..=6 {
+            // a sale to oneself is listed once
+            assert_eq!(all_sales(alice, limit).await, vec![5, 4, 2, 1]);
+            assert_eq!(all_sales(bob, limit).await, vec![3, 1]);
+        }
+
+        let fees = get_trade_fees(3).await;
+        assert_eq!(fees.len(), 2);
+        assert!(fees.iter().all(|accrual| accrual.trade_id == 3));
+
+        // the index is derived, rebuilding it changes nothing
+        if round == 0 {
+            sales_index_mut(|sales_index| *sales_index = SalesIndex::default());
+            rebuild_sales_index();
+        }
+    }
+}
+
+#[async_std::test]
+async fn revenue_is_served_from_running_totals_and_pages() {
+    MockContext::new().inject();
+    let (alice, bob, john) = (
+        mock_principals::alice(),
+        mock_principals::bob(),
+        mock_principals::john(),
+    );
+    let other = mock_principals::xtc();
+    let second = 1_000_000_000;
+    for (trade_id, nft_canister_id, fee_label, recipient, amount, time) in [
+        (1, nft_canister(), PROTOCOL_FEE_LABEL, john, 10, second / 2),
+        (1, nft_canister(), COLLECTION_FEE_LABEL, bob, 20, second / 2),
+        (2, nft_canister(), REFERRAL_FEE_LABEL, alice, 5, second + 1),
+        (3, other, COLLECTION_FEE_LABEL, bob, 30, 2 * second),
+        (4, nft_canister(), COLLECTION_FEE_LABEL, bob, 40, 3 * second),
+    ] {
+        log_accrual(FeeAccrual::new(
+            trade_id,
+            nft_canister_id,
+            Nat::from(1),
+            ledger(),
+            fee_label.to_string(),
+            recipient,
+            Nat::from(amount),
+            time,
+        ));
+    }
+    let buckets = |page: &RevenuePage| -> Vec<(u64, Nat)> {
+        page.buckets
+            .iter()
+            .map(|bucket| (bucket.start / second, bucket.amount.clone()))
+            .collect()
+    };
+
+    for round in 0..2 {
+        let collection = get_collection_revenue(nft_canister()).await;
+        assert_eq!(collection.len(), 3);
+        assert!(collection
+            .iter()
+            .any(|revenue| revenue.recipient == bob && revenue.amount == Nat::from(60)));
+
+        let recipient = get_recipient_revenue(bob).await;
+        let amounts: Vec<Nat> = recipient.iter().map(|r| r.amount.clone()).collect();
+        assert_eq!(recipient.len(), 2);
+        assert!(amounts.contains(&Nat::from(60)) && amounts.contains(&Nat::from(30)));
+
+        let referrals = get_referrer_earnings(alice).await;
+        assert_eq!(referrals.len(), 1);
+        assert_eq!(referrals[0].amount, Nat::from(5));
+        assert!(get_referrer_earnings(bob).await.is_empty());
+
+        // newest bucket first, a page holds whole buckets
+        let page = get_revenue_history(None, None, 1, None, 2).await.unwrap();
+        assert_eq!(buckets(&page), vec![(3, Nat::from(40)), (2, Nat::from(30))]);
+        assert_eq!(page.next, Some(2 * second));
+        let page = get_revenue_history(None, None, 1, page.next, 2)
+            .await
+            .unwrap();
+        assert_eq!(buckets(&page), vec![(1, Nat::from(5)), (0, Nat::from(30))]);
+        assert_eq!(page.next, None);
+
+        let page = get_revenue_history(Some(nft_canister()), Some(bob), 1, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(buckets(&page), vec![(3, Nat::from(40)), (0, Nat::from(20))]);
+        let page = get_revenue_history(None, Some(bob), 3600, None, 10)
+            .await
+            .unwrap();
+        assert_eq!(buckets(&page), vec![(0, Nat::from(90))]);
+        assert!(get_revenue_history(None, None, 0, None, 10).await.is_err());
+
+        // the totals are derived, rebuilding them changes nothing
+        if round == 0 {
+            sales_index_mut(|sales_index| *sales_index = SalesIndex::default());
+            rebuild_sales_index();
+        }
+    }
+}
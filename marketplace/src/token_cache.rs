@@ -0,0 +1,171 @@
+use crate::non_fungible_proxy::*;
+use crate::types::*;
+use crate::utils::*;
+
+use ic_kit::{
+    candid::{Nat, Principal},
+    ic,
+};
+use std::collections::HashSet;
+
+// Marketplace caches the owner, operator and properties of listed and offered tokens, so
+// frontends can read them in batch instead of calling every collection. Entries are recorded on
+// listing and offering from the owner and operator those already fetched, updated at settlement,
+// and refreshed by the heartbeat once stale. The cache is informational only, settlement always
+// verifies ownership with the collection.
+
+/// how long a cached token is fresh, in nanoseconds
+pub const TOKEN_CACHE_TTL: u64 = 60 * 60 * 1_000_000_000;
+
+/// how often the heartbeat sweeps the cache, in nanoseconds
+pub const TOKEN_CACHE_SWEEP_INTERVAL: u64 = 60 * 1_000_000_000;
+
+/// most tokens refreshed per sweep
+pub const TOKEN_CACHE_BATCH: usize = 10;
+
+/// fetch a token's owner, operator and properties into the cache
+pub(crate) async fn refresh_token(
+    nft_canister_id: &Principal,
+    token_id: &Nat,
+    nft_type: NFTStandard,
+) -> MPApiResult {
+    let owner = owner_of_non_fungible(nft_canister_id, token_id, nft_type).await?;
+    let operator = operator_of_non_fungible(nft_canister_id, token_id, nft_type).await?;
+    let properties = properties_non_fungible(nft_canister_id, token_id, nft_type).await?;
+
+    token_cache_mut(|token_cache| {
+        token_cache
+            .tokens
+            .entry(*nft_canister_id)
+            .or_default()
+            .insert(
+                token_id.clone(),
+                CachedToken::new(owner, operator, properties, ic::time()),
+            );
+    });
+
+    Ok(())
+}
+
+/// record the owner and operator a listing or offer fetched, and fetch the token's properties if
+/// it is not cached yet
+///
+/// offers do not fetch the operator, `None` keeps the cached one. A new entry without an operator
+/// is recorded stale, so the next sweep completes it
+pub(crate) async fn cache_token(
+    nft_canister_id: &Principal,
+    token_id: &Nat,
+    nft_type: NFTStandard,
+    owner: Principal,
+    operator: Option<Principal>,
+) -> MPApiResult {
+    let cached = token_cache_mut(|token_cache| {
+        token_cache
+            .tokens
+            .get_mut(nft_canister_id)
+            .and_then(|tokens| tokens.get_mut(token_id))
+            .map(|token| {
+                token.owner = Some(owner);
+                if operator.is_some() {
+                    token.operator = operator;
+                }
+            })
+            .is_some()
+    });
+    if cached {
+        return Ok(());
+    }
+
+    let properties = properties_non_fungible(nft_canister_id, token_id, nft_type).await?;
+    let refreshed = match operator {
+        Some(_) => ic::time(),
+        None => 0,
+    };
+
+    token_cache_mut(|token_cache| {
+        token_cache
+            .tokens
+            .entry(*nft_canister_id)
+            .or_default()
+            .insert(
+                token_id.clone(),
+                CachedToken::new(Some(owner), operator, properties, refreshed),
+            );
+    });
+
+    Ok(())
+}
+
+/// a cached token changed hands at settlement, marketplace is no longer its operator
+pub(crate) fn settle_cached_token(nft_canister_id: &Principal, token_id: &Nat, owner: Principal) {
+    token_cache_mut(|token_cache| {
+        if let Some(token) = token_cache
+            .tokens
+            .get_mut(nft_canister_id)
+            .and_then(|tokens| tokens.get_mut(token_id))
+        {
+            token.owner = Some(owner);
+            token.operator = None;
+            token.refreshed = ic::time();
+        }
+    });
+}
+
+/// drop tokens that are neither listed nor offered, and refresh a batch of stale ones
+pub(crate) async fn sweep_token_cache() {
+    let now = ic::time();
+    if token_cache(|token_cache| token_cache.last_sweep + TOKEN_CACHE_SWEEP_INTERVAL > now) {
+        return;
+    }
+
+    let tracked: HashSet<(Principal, Nat)> = marketplace(|mp| {
+        let listed = mp.listings.iter().flat_map(|(nft_canister_id, listings)| {
+            listings
+                .keys()
+                .map(move |token_id| (*nft_canister_id, token_id.clone()))
+        });
+        let offered = mp.offers.iter().flat_map(|(nft_canister_id, offers)| {
+            offers
+                .iter()
+                .filter(|(_, token_offers)| !token_offers.is_empty())
+                .map(move |(token_id, _)| (*nft_canister_id, token_id.clone()))
+        });
+
+        listed.chain(offered).collect()
+    });
+
+    // stale entries are marked refreshed up front, so the next sweep does not pick them again
+    let due: Vec<(Principal, Nat)> = token_cache_mut(|token_cache| {
+        token_cache.last_sweep = now;
+
+        for (nft_canister_id, tokens) in token_cache.tokens.iter_mut() {
+            tokens.retain(|token_id, _| tracked.contains(&(*nft_canister_id, token_id.clone())));
+        }
+        token_cache.tokens.retain(|_, tokens| !tokens.is_empty());
+
+        let mut due = Vec::new();
+        for (nft_canister_id, tokens) in token_cache.tokens.iter_mut() {
+            for (token_id, token) in tokens.iter_mut() {
+                if due.len() < TOKEN_CACHE_BATCH && token.refreshed + TOKEN_CACHE_TTL <= now {
+                    token.refreshed = now;
+                    due.push((*nft_canister_id, token_id.clone()));
+                }
+            }
+        }
+
+        due
+    });
+
+    for (nft_canister_id, token_id) in due {
+        let nft_type = collections(|collections| {
+            collections
+                .get(&nft_canister_id)
+                .map(|collection| collection.nft_canister_standard)
+        });
+
+        if let Some(nft_type) = nft_type {
+            // failures keep the stale entry, and count towards the collection's breaker
+            refresh_token(&nft_canister_id, &token_id, nft_type).await.ok();
+        }
+    }
+}
@@ -3,10 +3,12 @@ use derive_new::*;
 use ic_kit::{
     candid::{CandidType, Deserialize, Int, Nat},
     macros::*,
-    Principal,
+    Principal, RejectionCode,
 };
 use std::cmp::{Eq, PartialEq};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 
 /* THREAD_LOCAL TYPES */
 
@@ -28,6 +30,28 @@ pub struct Collection {
     pub fungible_canister_id: Principal,
     pub fungible_canister_standard: FungibleStandard,
     pub fungible_volume: Nat,
+    pub capabilities: CollectionCapabilities,
+    pub status: CollectionStatus,
+}
+
+/// Trading is paused while the breaker of one of a collection's canisters is not closed
+#[derive(Clone, CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum CollectionStatus {
+    Active,
+    Paused { canister: Principal, since: u64 },
+}
+
+/// What marketplace detected about a collection's canisters when it probed them
+#[derive(Clone, CandidType, Debug, Default, Deserialize, new)]
+pub struct CollectionCapabilities {
+    // interfaces, extensions or standards reported by the nft canister
+    pub nft_interfaces: Vec<String>,
+    // nft canister lets owners approve marketplace to move their tokens
+    pub approvals: bool,
+    // fee the fungible canister charges per transfer
+    pub transfer_fee: Nat,
+    // false until the canisters are probed, eg; for collections migrated from the baseline release
+    pub probed: bool,
 }
 
 pub type Collections = HashMap<Principal, Collection>;
@@ -37,6 +61,8 @@ pub struct Balances {
     // (collection, user pid): value
     pub balances: HashMap<(Principal, Principal), Nat>,
     pub failed_tx_log_entries: Vec<TxLogEntry>,
+    // fungible: rounding dust left over from fee splits, see `fees`
+    pub dust: HashMap<Principal, Nat>,
 }
 
 #[derive(Default, CandidType, Clone, Deserialize, new)]
@@ -49,6 +75,48 @@ pub struct Marketplace {
 
     // user: (collection, token)
     pub user_offers: HashMap<Principal, HashMap<Principal, Vec<Nat>>>,
+
+    // seller: (collection, token)
+    pub seller_listings: HashMap<Principal, HashMap<Principal, Vec<Nat>>>,
+
+    // token owner, as of the offer or the last sale: (collection, (token, buyer))
+    pub received_offers: HashMap<Principal, HashMap<Principal, Vec<(Nat, Principal)>>>,
+
+    // incremented on every completed sale, used to tie fee accruals to a trade
+    pub trade_count: u64,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct FeeLedger {
+    // append only log of every fee credited by `process_fees`
+    pub accruals: Vec<FeeAccrual>,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct SalesLedger {
+    // append only log of every completed trade, in order of completion
+    pub sales: Vec<Sale>,
+}
+
+/// Positions in the sales and fee ledgers, oldest first, and fee totals
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct SalesIndex {
+    // collection: { token: [sale] }
+    pub token_sales: HashMap<Principal, HashMap<Nat, Vec<u64>>>,
+    // collection: [sale]
+    pub collection_sales: HashMap<Principal, Vec<u64>>,
+    // user, as buyer or seller: [sale]
+    pub user_sales: HashMap<Principal, Vec<u64>>,
+    // trade: [fee accrual]
+    pub trade_fees: HashMap<u64, Vec<u64>>,
+    // collection: [fee accrual]
+    pub collection_fees: HashMap<Principal, Vec<u64>>,
+    // recipient: [fee accrual]
+    pub recipient_fees: HashMap<Principal, Vec<u64>>,
+    // collection: { (fungible, fee label, recipient): total }
+    pub collection_revenue: HashMap<Principal, BTreeMap<(Principal, String, Principal), Nat>>,
+    // recipient: { (collection, fungible, fee label): total }
+    pub recipient_revenue: HashMap<Principal, BTreeMap<(Principal, Principal, String), Nat>>,
 }
 
 /* Data types */
@@ -62,6 +130,59 @@ pub struct Listing {
     pub fee: Vec<(String, Principal, Nat)>,
 }
 
+/// Narrows a listings query, unset fields match every listing
+#[derive(Clone, CandidType, Debug, Default, Deserialize, new)]
+pub struct ListingFilter {
+    pub min_price: Option<Nat>,
+    pub max_price: Option<Nat>,
+    pub seller: Option<Principal>,
+}
+
+impl ListingFilter {
+    /// whether a listing is within the price range and of the seller
+    pub fn matches(&self, listing: &Listing) -> bool {
+        if matches!(&self.min_price, Some(min) if &listing.price < min) {
+            return false;
+        }
+        if matches!(&self.max_price, Some(max) if &listing.price > max) {
+            return false;
+        }
+
+        self.seller.map_or(true, |seller| listing.seller == seller)
+    }
+}
+
+#[derive(Clone, Copy, CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum ListingSort {
+    PriceAsc,
+    PriceDesc,
+    CreatedAsc,
+    CreatedDesc,
+}
+
+/// Position of the last listing of a page, the next page starts after it
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct ListingCursor {
+    pub price: Nat,
+    pub created: u64,
+    pub nft_canister_id: Principal,
+    pub token_id: Nat,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct ListingEntry {
+    pub nft_canister_id: Principal,
+    pub token_id: Nat,
+    pub listing: Listing,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct ListingsPage {
+    pub listings: Vec<ListingEntry>,
+    // `None` on the last page
+    pub next: Option<ListingCursor>,
+}
+
 impl Default for Listing {
     fn default() -> Self {
         Listing::new(
@@ -83,6 +204,253 @@ pub struct Offer {
     pub token_owner: Principal,
     pub status: OfferStatus,
     pub created: u64,
+    pub fee: Vec<(String, Principal, Nat)>,
+    pub referrer: Option<Principal>,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct TokenCache {
+    // collection { token: metadata }
+    pub tokens: HashMap<Principal, HashMap<Nat, CachedToken>>,
+    // when the cache was last swept, see `token_cache`
+    pub last_sweep: u64,
+}
+
+/// Metadata of a listed or offered token, as last fetched from its collection
+#[derive(Clone, CandidType, Debug, Default, Deserialize, new)]
+pub struct CachedToken {
+    pub owner: Option<Principal>,
+    pub operator: Option<Principal>,
+    pub properties: Vec<(String, GenericValue)>,
+    // when the token was last fetched, in nanoseconds
+    pub refreshed: u64,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct OrderBooks {
+    // collection: { (price, token) }, one ask per listing
+    pub asks: HashMap<Principal, BTreeSet<(Nat, Nat)>>,
+    // collection: { (price, token, buyer) }, one bid per offer
+    pub bids: HashMap<Principal, BTreeSet<(Nat, Nat, Principal)>>,
+    // collection: { (created, token) }, one entry per listing
+    pub listed: HashMap<Principal, BTreeSet<(u64, Nat)>>,
+}
+
+/// Listings or offers of a collection at one price
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct OrderBookLevel {
+    pub price: Nat,
+    pub orders: u64,
+}
+
+/// Market depth of a collection, asks from the lowest price and bids from the highest
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct OrderBook {
+    pub asks: Vec<OrderBookLevel>,
+    pub bids: Vec<OrderBookLevel>,
+    pub best_ask: Option<Nat>,
+    pub best_bid: Option<Nat>,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct MarketStats {
+    // collection: { hour start: bucket }, pruned after `HOURLY_STATS_RETENTION`
+    pub hourly: HashMap<Principal, BTreeMap<u64, StatsBucket>>,
+    // collection: { day start: bucket }, kept for the collection's history
+    pub daily: HashMap<Principal, BTreeMap<u64, StatsBucket>>,
+}
+
+/// Sales and floor of a collection during one hour or day
+#[derive(Clone, CandidType, Debug, Default, Deserialize, new)]
+pub struct StatsBucket {
+    pub volume: Nat,
+    pub sales: u64,
+    // sale prices, buyers and sellers, cleared from daily buckets after `STATS_DETAIL_RETENTION`
+    pub prices: Vec<Nat>,
+    pub buyers: BTreeSet<Principal>,
+    pub sellers: BTreeSet<Principal>,
+    // floor after the last listing change or sale in the bucket, `None` when nothing was listed
+    pub floor: Option<Nat>,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum StatsInterval {
+    Hourly,
+    Daily,
+}
+
+/// Sales of a collection over a trailing window
+#[derive(Clone, CandidType, Debug, Default, Deserialize, new)]
+pub struct WindowStats {
+    pub volume: Nat,
+    pub sales: u64,
+    pub average_price: Option<Nat>,
+    pub median_price: Option<Nat>,
+    pub unique_buyers: u64,
+    pub unique_sellers: u64,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct CollectionStats {
+    pub day: WindowStats,
+    pub week: WindowStats,
+    pub month: WindowStats,
+    pub lifetime_volume: Nat,
+    pub floor: Option<Nat>,
+    pub active_listings: u64,
+    pub active_offers: u64,
+}
+
+/// A bucket of a collection's history, without its sale details
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct StatsHistoryEntry {
+    // bucket start, in nanoseconds
+    pub start: u64,
+    pub volume: Nat,
+    pub sales: u64,
+    pub floor: Option<Nat>,
+}
+
+/// A token's listing, with the certificate and witness to verify it, see `certification`
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct CertifiedListing {
+    pub listing: Option<Listing>,
+    // `None` when called as an update
+    pub certificate: Option<Vec<u8>>,
+    // cbor encoded hash tree, revealing the candid encoded listing at its key
+    pub witness: Vec<u8>,
+}
+
+/// A floor price or balance, with the certificate and witness to verify it, see `certification`
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct CertifiedNat {
+    pub value: Option<Nat>,
+    // `None` when called as an update
+    pub certificate: Option<Vec<u8>>,
+    // cbor encoded hash tree, revealing the candid encoded value at its key
+    pub witness: Vec<u8>,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct Breakers {
+    // external canister: breaker, only canisters that failed since their last success
+    pub breakers: HashMap<Principal, Breaker>,
+}
+
+/// Consecutive failed calls into an external canister, see `circuit_breaker`
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct Breaker {
+    pub status: BreakerStatus,
+    // consecutive calls the canister rejected
+    pub failures: u32,
+    pub last_error: Option<CanisterError>,
+    // when the breaker last opened, in nanoseconds
+    pub opened: u64,
+}
+
+#[derive(Clone, Copy, CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum BreakerStatus {
+    // calls go through
+    Closed,
+    // calls fail fast until the cooldown is over
+    Open,
+    // the canister is being probed, calls fail fast until it answers
+    Probing,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct Referrals {
+    // referrer: registration
+    pub referrers: HashMap<Principal, Referrer>,
+
+    // share of the protocol fee credited to the referrer of a trade, e2
+    pub share: Nat,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct Referrer {
+    pub enabled: bool,
+    pub registered: u64,
+}
+
+#[derive(Clone, CandidType, Default, Deserialize, new)]
+pub struct Treasury {
+    // every split configuration set, the last one is in effect
+    pub history: Vec<TreasurySplitConfig>,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct TreasurySplitConfig {
+    // (treasury purpose, treasury, share (e2))
+    pub splits: Vec<(String, Principal, Nat)>,
+    pub set_by: Principal,
+    pub time: u64,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct FeeAccrual {
+    pub trade_id: u64,
+    pub nft_canister_id: Principal,
+    pub token_id: Nat,
+    pub fungible_canister_id: Principal,
+    pub fee_label: String,
+    pub recipient: Principal,
+    pub amount: Nat,
+    pub time: u64,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, Eq, PartialEq)]
+pub enum TradeType {
+    DirectBuy,
+    AcceptOffer,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct Sale {
+    pub trade_id: u64,
+    pub nft_canister_id: Principal,
+    pub token_id: Nat,
+    pub fungible_canister_id: Principal,
+    pub buyer: Principal,
+    pub seller: Principal,
+    pub price: Nat,
+    // total fees charged, see `getTradeFees` for the breakdown
+    pub fees: Nat,
+    pub trade_type: TradeType,
+    pub time: u64,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct SalesPage {
+    // newest first
+    pub sales: Vec<Sale>,
+    // `before` of the next page, `None` on the last page
+    pub next: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct FeeRevenue {
+    pub nft_canister_id: Principal,
+    pub fungible_canister_id: Principal,
+    pub fee_label: String,
+    pub recipient: Principal,
+    pub amount: Nat,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct RevenueBucket {
+    // bucket start, in nanoseconds
+    pub start: u64,
+    pub fungible_canister_id: Principal,
+    pub amount: Nat,
+}
+
+#[derive(Clone, CandidType, Debug, Deserialize, new)]
+pub struct RevenuePage {
+    // newest first
+    pub buckets: Vec<RevenueBucket>,
+    // `before` of the next page, `None` on the last page
+    pub next: Option<u64>,
 }
 
 #[derive(CandidType, Deserialize, Debug, Clone)]
@@ -105,9 +473,213 @@ pub enum MPApiError {
     NoDeposit,
     CAPInsertionError,
     NonExistentCollection,
+    InvalidReferrer,
+    // the caller is not a controller of the marketplace
+    NotController(Principal),
+    // a canister does not implement the standard it is registered or probed as
+    StandardMismatch {
+        canister: Principal,
+        standard: TokenStandard,
+        reason: MismatchReason,
+    },
+    // the breaker of a canister is open, trading that depends on it is paused
+    CanisterPaused(Principal),
+    // a call to a fungible or nft canister failed
+    CanisterCallError {
+        canister: Principal,
+        method: String,
+        error: CanisterError,
+    },
     Other(String),
 }
 
+/// Why a call to another canister failed
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum CanisterError {
+    // the call was rejected, eg; the canister trapped, is stopped or does not exist
+    Rejected { code: RejectionKind, message: String },
+    // a DIP721v2 canister answered with an error
+    Nft(NftError),
+    // a DIP20 canister answered with an error
+    Tx(TxError),
+    // a DIP721v1 canister answered with an error
+    Dip721(ApiError),
+    // an EXT canister answered with an error
+    Ext(CommonError),
+    // an EXT canister refused a transfer
+    ExtTransfer(TransferResponseErrors),
+    // an ICRC1 ledger refused a transfer
+    Icrc1Transfer(TransferError),
+    // an ICRC2 ledger refused a transfer from an allowance
+    Icrc2TransferFrom(TransferFromError),
+    // the ICP ledger refused a transfer
+    IcpTransfer(IcpTransferError),
+    // an ICRC7 canister refused a transfer
+    Icrc7Transfer(Icrc7TransferError),
+    // an ICRC37 canister refused a transfer from an approval
+    Icrc37TransferFrom(Icrc37TransferFromError),
+    // a canister answered in a way marketplace cannot use, eg; a batch without a result
+    Reply(String),
+}
+
+impl CanisterError {
+    pub fn reply(err: impl std::fmt::Debug) -> Self {
+        CanisterError::Reply(format!("{:?}", err))
+    }
+
+    /// whether retrying the call later may succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            CanisterError::Rejected {
+                code: RejectionKind::SysTransient,
+                ..
+            }
+        )
+    }
+}
+
+impl From<(RejectionCode, String)> for CanisterError {
+    fn from((code, message): (RejectionCode, String)) -> Self {
+        CanisterError::Rejected {
+            code: code.into(),
+            message,
+        }
+    }
+}
+
+impl From<NftError> for CanisterError {
+    fn from(err: NftError) -> Self {
+        CanisterError::Nft(err)
+    }
+}
+
+impl From<TxError> for CanisterError {
+    fn from(err: TxError) -> Self {
+        CanisterError::Tx(err)
+    }
+}
+
+impl From<ApiError> for CanisterError {
+    fn from(err: ApiError) -> Self {
+        CanisterError::Dip721(err)
+    }
+}
+
+impl From<CommonError> for CanisterError {
+    fn from(err: CommonError) -> Self {
+        CanisterError::Ext(err)
+    }
+}
+
+impl From<TransferResponseErrors> for CanisterError {
+    fn from(err: TransferResponseErrors) -> Self {
+        CanisterError::ExtTransfer(err)
+    }
+}
+
+impl From<TransferError> for CanisterError {
+    fn from(err: TransferError) -> Self {
+        CanisterError::Icrc1Transfer(err)
+    }
+}
+
+impl From<TransferFromError> for CanisterError {
+    fn from(err: TransferFromError) -> Self {
+        CanisterError::Icrc2TransferFrom(err)
+    }
+}
+
+impl From<IcpTransferError> for CanisterError {
+    fn from(err: IcpTransferError) -> Self {
+        CanisterError::IcpTransfer(err)
+    }
+}
+
+impl From<Icrc7TransferError> for CanisterError {
+    fn from(err: Icrc7TransferError) -> Self {
+        CanisterError::Icrc7Transfer(err)
+    }
+}
+
+impl From<Icrc37TransferFromError> for CanisterError {
+    fn from(err: Icrc37TransferFromError) -> Self {
+        CanisterError::Icrc37TransferFrom(err)
+    }
+}
+
+/// A standard a canister is registered or probed as
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum TokenStandard {
+    Fungible(FungibleStandard),
+    NonFungible(NFTStandard),
+}
+
+impl From<FungibleStandard> for TokenStandard {
+    fn from(standard: FungibleStandard) -> Self {
+        TokenStandard::Fungible(standard)
+    }
+}
+
+impl From<NFTStandard> for TokenStandard {
+    fn from(standard: NFTStandard) -> Self {
+        TokenStandard::NonFungible(standard)
+    }
+}
+
+/// Why a canister does not implement a standard
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum MismatchReason {
+    // a call the standard defines was rejected or answered with an error
+    Failed(CanisterError),
+    // the canister lacks something the standard, or marketplace, requires
+    Unsupported(String),
+}
+
+impl From<CanisterError> for MismatchReason {
+    fn from(err: CanisterError) -> Self {
+        MismatchReason::Failed(err)
+    }
+}
+
+impl From<(RejectionCode, String)> for MismatchReason {
+    fn from(err: (RejectionCode, String)) -> Self {
+        MismatchReason::Failed(err.into())
+    }
+}
+
+impl From<String> for MismatchReason {
+    fn from(reason: String) -> Self {
+        MismatchReason::Unsupported(reason)
+    }
+}
+
+/// `RejectionCode` of a rejected call, which is not a candid type
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RejectionKind {
+    NoError,
+    SysFatal,
+    SysTransient,
+    DestinationInvalid,
+    CanisterReject,
+    CanisterError,
+    Unknown,
+}
+
+impl From<RejectionCode> for RejectionKind {
+    fn from(code: RejectionCode) -> Self {
+        match code {
+            RejectionCode::NoError => RejectionKind::NoError,
+            RejectionCode::SysFatal => RejectionKind::SysFatal,
+            RejectionCode::SysTransient => RejectionKind::SysTransient,
+            RejectionCode::DestinationInvalid => RejectionKind::DestinationInvalid,
+            RejectionCode::CanisterReject => RejectionKind::CanisterReject,
+            RejectionCode::CanisterError => RejectionKind::CanisterError,
+            RejectionCode::Unknown => RejectionKind::Unknown,
+        }
+    }
+}
+
 #[derive(Clone, CandidType, Debug, Deserialize, Eq, PartialEq)]
 pub enum OfferStatus {
     Uninitialized,
@@ -136,13 +708,21 @@ pub type PrincipalResult = Result<Option<Principal>, MPApiError>;
 pub type U64Result = Result<u64, MPApiError>;
 pub type NatResult = Result<Nat, MPApiError>;
 
-#[derive(Clone, CandidType, Deserialize)]
+// adapters return boxed futures, async fns cannot be called through trait objects
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+#[derive(Clone, CandidType, Debug, Deserialize, Eq, Hash, PartialEq)]
 pub enum FungibleStandard {
     DIP20,
+    ICRC1,
+    ICRC2,
+    ICP,
 }
 
-#[derive(Copy, Clone, CandidType, Deserialize)]
+#[derive(Copy, Clone, CandidType, Debug, Deserialize, Eq, Hash, PartialEq)]
 pub enum NFTStandard {
+    DIP721v1,
     DIP721v2,
     EXT,
+    ICRC7,
 }
@@ -1,50 +1,242 @@
 use crate::*;
 use ic_kit::{
+  candid::{CandidType, Deserialize},
   ic::{stable_restore, stable_store},
   macros::*,
 };
 
+// The stable state is tagged with the version of its layout. Every change to a stored type gets
+// a new variant, and `post_upgrade` migrates older ones. The baseline release stored an untagged
+// tuple, which is decoded when the tagged state is not found, see `baseline`.
+//
+// The order books, the sales index, the dashboard indexes of baseline state and the certified
+// tree are derived, they are rebuilt after restoring.
+
+#[derive(CandidType, Deserialize)]
+enum StableState {
+  V1(StableStateV1),
+}
+
+#[derive(CandidType, Deserialize)]
+struct StableStateV1 {
+  marketplace: Marketplace,
+  collections: Collections,
+  balances: Balances,
+  init_data: InitData,
+  fee_ledger: FeeLedger,
+  referrals: Referrals,
+  treasury: Treasury,
+  accounts: HashMap<AccountIdentifier, Principal>,
+  icrc_subaccounts: HashMap<Principal, Subaccount>,
+  breakers: Breakers,
+  token_cache: TokenCache,
+  stats: MarketStats,
+  sales_ledger: SalesLedger,
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
-  let marketplace = marketplace(|marketplace| marketplace.clone());
-  let collections = collections(|collections| collections.clone());
-  let balances = balances(|balances| balances.clone());
-  let init_data = init_data(|init_data| init_data.clone());
-  stable_store((
-    marketplace,
-    collections,
-    balances,
-    init_data,
-    cap_sdk::archive(),
-  ))
-  .unwrap();
+  let state = StableStateV1 {
+    marketplace: marketplace(|marketplace| marketplace.clone()),
+    collections: collections(|collections| collections.clone()),
+    balances: balances(|balances| balances.clone()),
+    init_data: init_data(|init_data| init_data.clone()),
+    fee_ledger: fee_ledger(|fee_ledger| fee_ledger.clone()),
+    referrals: referrals(|referrals| referrals.clone()),
+    treasury: treasury(|treasury| treasury.clone()),
+    accounts: accounts(|accounts| accounts.clone()),
+    icrc_subaccounts: icrc_subaccounts(|icrc_subaccounts| icrc_subaccounts.clone()),
+    breakers: breakers(|breakers| breakers.clone()),
+    token_cache: token_cache(|token_cache| token_cache.clone()),
+    stats: stats(|stats| stats.clone()),
+    sales_ledger: sales_ledger(|sales_ledger| sales_ledger.clone()),
+  };
+  stable_store((StableState::V1(state), cap_sdk::archive())).unwrap();
 }
 
 #[post_upgrade]
 fn post_upgrade_a() {
-  let (
-      marketplace_stored,
-      collections_stored,
-      balances_stored,
-      init_data_stored,
-      cap_env_stored,
-  ): (Marketplace, Collections, Balances, InitData, cap_sdk::Archive) = stable_restore().unwrap();
-  marketplace_mut(|marketplace| {
-    marketplace.listings = marketplace_stored.listings;
-    marketplace.offers = marketplace_stored.offers;
-    marketplace.user_offers = marketplace_stored.user_offers;
-  });
-  collections_mut(|collections| {
-    collections.extend(collections_stored);
-  });
-  balances_mut(|balances| {
-    balances.balances = balances_stored.balances;
-    balances.failed_tx_log_entries = balances_stored.failed_tx_log_entries;
-  });
-  init_data_mut(|init_data| {
-    init_data.cap = init_data_stored.cap;
-    init_data.owner = init_data_stored.owner;
-    init_data.protocol_fee = init_data_stored.protocol_fee;
-  });
+  let restored: Result<(StableState, cap_sdk::Archive), String> = stable_restore();
+  let cap_env_stored = match restored {
+    Ok((StableState::V1(state), cap_env_stored)) => {
+      restore_v1(state);
+      cap_env_stored
+    }
+    Err(_) => {
+      let (
+          marketplace_stored,
+          collections_stored,
+          balances_stored,
+          init_data_stored,
+          cap_env_stored,
+      ): (
+        baseline::Marketplace,
+        HashMap<Principal, baseline::Collection>,
+        baseline::Balances,
+        InitData,
+        cap_sdk::Archive,
+      ) = stable_restore().unwrap();
+      baseline::migrate(
+        marketplace_stored,
+        collections_stored,
+        balances_stored,
+        init_data_stored,
+      );
+      cap_env_stored
+    }
+  };
   cap_sdk::from_archive(cap_env_stored);
+
+  // the order books are derived from the listings and offers, not stored
+  rebuild_order_books();
+  // the sales index is derived from the sales and fee ledgers, not stored
+  rebuild_sales_index();
+  // the certified tree is derived from the listings and balances, not stored
+  certify_all();
+}
+
+fn restore_v1(state: StableStateV1) {
+  marketplace_mut(|marketplace| *marketplace = state.marketplace);
+  collections_mut(|collections| *collections = state.collections);
+  balances_mut(|balances| *balances = state.balances);
+  init_data_mut(|init_data| *init_data = state.init_data);
+  fee_ledger_mut(|fee_ledger| *fee_ledger = state.fee_ledger);
+  referrals_mut(|referrals| *referrals = state.referrals);
+  treasury_mut(|treasury| *treasury = state.treasury);
+  accounts_mut(|accounts| *accounts = state.accounts);
+  icrc_subaccounts_mut(|icrc_subaccounts| *icrc_subaccounts = state.icrc_subaccounts);
+  breakers_mut(|breakers| *breakers = state.breakers);
+  token_cache_mut(|token_cache| *token_cache = state.token_cache);
+  stats_mut(|stats| *stats = state.stats);
+  sales_ledger_mut(|sales_ledger| *sales_ledger = state.sales_ledger);
+}
+
+/// The types stored by the baseline release, as `(Marketplace, Collections, Balances, InitData,
+/// cap_sdk::Archive)`. `Listing`, `InitData` and `TxLogEntry` are unchanged since, and reused
+mod baseline {
+  use crate::*;
+  use ic_kit::candid::{CandidType, Deserialize};
+
+  #[derive(CandidType, Deserialize)]
+  pub struct Marketplace {
+    pub listings: HashMap<Principal, HashMap<Nat, Listing>>,
+    pub offers: HashMap<Principal, HashMap<Nat, HashMap<Principal, Offer>>>,
+    pub user_offers: HashMap<Principal, HashMap<Principal, Vec<Nat>>>,
+  }
+
+  #[derive(CandidType, Deserialize)]
+  pub struct Offer {
+    pub nft_canister_id: Principal,
+    pub token_id: Nat,
+    pub price: Nat,
+    pub buyer: Principal,
+    pub token_owner: Principal,
+    pub status: OfferStatus,
+    pub created: u64,
+  }
+
+  #[derive(CandidType, Deserialize)]
+  pub struct Collection {
+    pub owner: Principal,
+    pub collection_fee: Nat,
+    pub creation_time: u64,
+    pub collection_name: String,
+    pub nft_canister_id: Principal,
+    pub nft_canister_standard: NFTStandard,
+    pub fungible_canister_id: Principal,
+    pub fungible_canister_standard: FungibleStandard,
+    pub fungible_volume: Nat,
+  }
+
+  #[derive(CandidType, Deserialize)]
+  pub struct Balances {
+    pub balances: HashMap<(Principal, Principal), Nat>,
+    pub failed_tx_log_entries: Vec<TxLogEntry>,
+  }
+
+  /// move baseline state into the current types
+  ///
+  /// * collections were never probed, they are recorded without capabilities until the heartbeat
+  ///   probes them, see `probe_collections`
+  /// * offers were charged the fees in effect at acceptance, they are snapshotted now
+  /// * listings by seller and offers by token owner are indexed from scratch
+  pub fn migrate(
+    marketplace_stored: Marketplace,
+    collections_stored: HashMap<Principal, Collection>,
+    balances_stored: Balances,
+    init_data_stored: InitData,
+  ) {
+    init_data_mut(|init_data| *init_data = init_data_stored);
+
+    collections_mut(|collections| {
+      for (nft_canister_id, collection) in collections_stored {
+        collections.insert(
+          nft_canister_id,
+          crate::Collection::new(
+            collection.owner,
+            collection.collection_fee,
+            collection.creation_time,
+            collection.collection_name,
+            collection.nft_canister_id,
+            collection.nft_canister_standard,
+            collection.fungible_canister_id,
+            collection.fungible_canister_standard,
+            collection.fungible_volume,
+            CollectionCapabilities::default(),
+            CollectionStatus::Active,
+          ),
+        );
+      }
+    });
+
+    balances_mut(|balances| {
+      balances.balances = balances_stored.balances;
+      balances.failed_tx_log_entries = balances_stored.failed_tx_log_entries;
+    });
+
+    let offers: HashMap<Principal, HashMap<Nat, HashMap<Principal, crate::Offer>>> = marketplace_stored
+      .offers
+      .into_iter()
+      .map(|(nft_canister_id, offers)| {
+        let fee = collections(|collections| {
+          collections
+            .get(&nft_canister_id)
+            .map(collection_fees)
+            .unwrap_or_default()
+        });
+
+        let offers = offers
+          .into_iter()
+          .map(|(token_id, token_offers)| {
+            let token_offers = token_offers
+              .into_iter()
+              .map(|(buyer, offer)| {
+                let offer = crate::Offer::new(
+                  offer.nft_canister_id,
+                  offer.token_id,
+                  offer.price,
+                  offer.buyer,
+                  offer.token_owner,
+                  offer.status,
+                  offer.created,
+                  fee.clone(),
+                  None,
+                );
+                (buyer, offer)
+              })
+              .collect();
+            (token_id, token_offers)
+          })
+          .collect();
+        (nft_canister_id, offers)
+      })
+      .collect();
+
+    marketplace_mut(|marketplace| {
+      marketplace.listings = marketplace_stored.listings;
+      marketplace.offers = offers;
+      marketplace.user_offers = marketplace_stored.user_offers;
+    });
+    rebuild_user_indexes();
+  }
 }
@@ -1,27 +1,60 @@
-use cap_sdk::CapEnv;
+use cap_sdk::{CapEnv, DetailValue};
+use ic_certified_map::RbTree;
 use ic_kit::{
     candid::{CandidType, Deserialize, Int, Nat, Principal},
     ic::{stable_restore, stable_store, store},
     macros::*,
 };
 use num_bigint::Sign;
+use sha2::{Digest, Sha224};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 
+use crate::certification::*;
+use crate::order_book::*;
 use crate::types::*;
+use crate::vendor_types::{AccountIdentifier, Subaccount, TokenIdentifier, User};
 
 thread_local!(
     static MARKETPLACE: RefCell<Marketplace> = RefCell::new(Marketplace::new(
         HashMap::new(),
         HashMap::new(),
         HashMap::new(),
+        HashMap::new(),
+        HashMap::new(),
+        0,
     ));
     static COLLECTIONS: RefCell<Collections> = RefCell::new(HashMap::new());
-    static BALANCES: RefCell<Balances> = RefCell::new(Balances::new(HashMap::new(), Vec::new()));
+    static BALANCES: RefCell<Balances> = RefCell::new(Balances::new(HashMap::new(), Vec::new(), HashMap::new()));
     static INIT_DATA: RefCell<InitData> =
         RefCell::new(InitData::new(None, Principal::anonymous(), Nat::from(0)));
+    static FEE_LEDGER: RefCell<FeeLedger> = RefCell::new(FeeLedger::new(Vec::new()));
+    static SALES_LEDGER: RefCell<SalesLedger> = RefCell::new(SalesLedger::new(Vec::new()));
+    static REFERRALS: RefCell<Referrals> =
+        RefCell::new(Referrals::new(HashMap::new(), Nat::from(0)));
+    static TREASURY: RefCell<Treasury> = RefCell::new(Treasury::new(Vec::new()));
+    // account identifiers (default subaccount) of principals that have used marketplace
+    static ACCOUNTS: RefCell<HashMap<AccountIdentifier, Principal>> = RefCell::new(HashMap::new());
+    // subaccounts principals use on ICRC ledgers and ICRC7 collections, instead of their default
+    static ICRC_SUBACCOUNTS: RefCell<HashMap<Principal, Subaccount>> =
+        RefCell::new(HashMap::new());
+    static BREAKERS: RefCell<Breakers> = RefCell::new(Breakers::new(HashMap::new()));
+    static TOKEN_CACHE: RefCell<TokenCache> = RefCell::new(TokenCache::new(HashMap::new(), 0));
+    // derived from the listings and offers, rebuilt on upgrade
+    static ORDER_BOOKS: RefCell<OrderBooks> = RefCell::new(OrderBooks::default());
+    // derived from the sales and fee ledgers, rebuilt on upgrade
+    static SALES_INDEX: RefCell<SalesIndex> = RefCell::new(SalesIndex::default());
+    static STATS: RefCell<MarketStats> =
+        RefCell::new(MarketStats::new(HashMap::new(), HashMap::new()));
+    // derived from the listings and balances, rebuilt on upgrade, see `certification`
+    static CERTIFIED_TREE: RefCell<RbTree<Vec<u8>, Vec<u8>>> = RefCell::new(RbTree::new());
 );
 
+pub const PROTOCOL_FEE_LABEL: &str = "Protocol Fee";
+pub const COLLECTION_FEE_LABEL: &str = "Collection Fee";
+pub const REFERRAL_FEE_LABEL: &str = "Referral Fee";
+
 /// get mutable marketplace object from thread local
 pub(crate) fn marketplace_mut<T, F: FnOnce(&mut Marketplace) -> T>(f: F) -> T {
     MARKETPLACE.with(|marketplace| f(&mut marketplace.borrow_mut()))
@@ -56,12 +89,150 @@ pub(crate) fn init_data<T, F: FnOnce(&InitData) -> T>(f: F) -> T {
     INIT_DATA.with(|init_data| f(&init_data.borrow()))
 }
 
+pub(crate) fn fee_ledger_mut<T, F: FnOnce(&mut FeeLedger) -> T>(f: F) -> T {
+    FEE_LEDGER.with(|fee_ledger| f(&mut fee_ledger.borrow_mut()))
+}
+
+pub(crate) fn fee_ledger<T, F: FnOnce(&FeeLedger) -> T>(f: F) -> T {
+    FEE_LEDGER.with(|fee_ledger| f(&fee_ledger.borrow()))
+}
+
+pub(crate) fn sales_ledger_mut<T, F: FnOnce(&mut SalesLedger) -> T>(f: F) -> T {
+    SALES_LEDGER.with(|sales_ledger| f(&mut sales_ledger.borrow_mut()))
+}
+
+pub(crate) fn sales_ledger<T, F: FnOnce(&SalesLedger) -> T>(f: F) -> T {
+    SALES_LEDGER.with(|sales_ledger| f(&sales_ledger.borrow()))
+}
+
+pub(crate) fn sales_index_mut<T, F: FnOnce(&mut SalesIndex) -> T>(f: F) -> T {
+    SALES_INDEX.with(|sales_index| f(&mut sales_index.borrow_mut()))
+}
+
+pub(crate) fn sales_index<T, F: FnOnce(&SalesIndex) -> T>(f: F) -> T {
+    SALES_INDEX.with(|sales_index| f(&sales_index.borrow()))
+}
+
+pub(crate) fn referrals_mut<T, F: FnOnce(&mut Referrals) -> T>(f: F) -> T {
+    REFERRALS.with(|referrals| f(&mut referrals.borrow_mut()))
+}
+
+pub(crate) fn referrals<T, F: FnOnce(&Referrals) -> T>(f: F) -> T {
+    REFERRALS.with(|referrals| f(&referrals.borrow()))
+}
+
+pub(crate) fn treasury_mut<T, F: FnOnce(&mut Treasury) -> T>(f: F) -> T {
+    TREASURY.with(|treasury| f(&mut treasury.borrow_mut()))
+}
+
+pub(crate) fn treasury<T, F: FnOnce(&Treasury) -> T>(f: F) -> T {
+    TREASURY.with(|treasury| f(&treasury.borrow()))
+}
+
+/// the treasury split configuration currently in effect
+pub(crate) fn treasury_splits() -> Vec<(String, Principal, Nat)> {
+    treasury(|treasury| {
+        treasury
+            .history
+            .last()
+            .map(|config| config.splits.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// check that a referrer passed by `user` is registered, enabled and not the user themselves
+pub(crate) fn validate_referrer(referrer: &Option<Principal>, user: &Principal) -> MPApiResult {
+    match referrer {
+        Some(referrer) if referrer == user || active_referrer(Some(*referrer)).is_none() => {
+            Err(MPApiError::InvalidReferrer)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// filter out referrers that are not registered, or have been disabled
+pub(crate) fn active_referrer(referrer: Option<Principal>) -> Option<Principal> {
+    referrer.filter(|referrer| {
+        referrals(|referrals| {
+            referrals
+                .referrers
+                .get(referrer)
+                .map_or(false, |registration| registration.enabled)
+        })
+    })
+}
+
+/// what the fungible charges the payer on top of the price when marketplace takes a payment, as
+/// recorded when the collection was registered
+///
+/// DIP20 and ICRC2 take it out of the allowance with the price, the ICP ledger out of the deposit
+pub(crate) fn payment_fee(collection: &Collection) -> Nat {
+    match collection.fungible_canister_standard {
+        FungibleStandard::DIP20 | FungibleStandard::ICRC2 | FungibleStandard::ICP => {
+            collection.capabilities.transfer_fee.clone()
+        }
+        // ICRC1 ledgers are not registered, marketplace takes no payments through them
+        FungibleStandard::ICRC1 => Nat::from(0),
+    }
+}
+
+/// the transfer fee recorded for a fungible, the highest among the collections trading in it
+///
+/// `None` when no collection trading in it has been probed
+pub(crate) fn recorded_transfer_fee(fungible_canister_id: &Principal) -> Option<Nat> {
+    collections(|collections| {
+        collections
+            .values()
+            .filter(|collection| {
+                collection.fungible_canister_id == *fungible_canister_id
+                    && collection.capabilities.probed
+            })
+            .map(|collection| collection.capabilities.transfer_fee.clone())
+            .max()
+    })
+}
+
+/// snapshot of the fees currently charged on a collection's trades
+pub(crate) fn collection_fees(collection: &Collection) -> Vec<(String, Principal, Nat)> {
+    init_data(|init_data| {
+        [
+            (
+                PROTOCOL_FEE_LABEL.to_string(),
+                init_data.owner,
+                init_data.protocol_fee.clone(),
+            ),
+            (
+                COLLECTION_FEE_LABEL.to_string(),
+                collection.owner,
+                collection.collection_fee.clone(),
+            ),
+        ]
+        .to_vec()
+    })
+}
+
+/// allocate the id for a completed trade
+pub(crate) fn next_trade_id() -> u64 {
+    marketplace_mut(|mp| {
+        mp.trade_count += 1;
+        mp.trade_count
+    })
+}
+
 pub(crate) fn remove_offer(nft_canister_id: &Principal, token_id: &Nat, user: &Principal) {
     marketplace_mut(|mp| {
         let mut offers = mp.offers.entry(*nft_canister_id).or_default();
         let mut token_offers = offers.entry(token_id.clone()).or_default();
 
-        token_offers.remove(&user);
+        if let Some(offer) = token_offers.remove(&user) {
+            unindex_bid(nft_canister_id, token_id, &offer.price, user);
+            unindex_user_entry(
+                &mut mp.received_offers,
+                &offer.token_owner,
+                nft_canister_id,
+                &(token_id.clone(), *user),
+            );
+        }
 
         // save storage space
         if (token_offers.is_empty()) {
@@ -79,10 +250,120 @@ pub(crate) fn remove_offer(nft_canister_id: &Principal, token_id: &Nat, user: &P
     });
 }
 
+/// a token changed hands, the offers left on it are made to its new owner
+pub(crate) fn transfer_offers(nft_canister_id: &Principal, token_id: &Nat, owner: &Principal) {
+    marketplace_mut(|mp| {
+        let token_offers = match mp
+            .offers
+            .get_mut(nft_canister_id)
+            .and_then(|offers| offers.get_mut(token_id))
+        {
+            Some(token_offers) => token_offers,
+            None => return,
+        };
+
+        for (buyer, offer) in token_offers.iter_mut() {
+            let entry = (token_id.clone(), *buyer);
+            unindex_user_entry(
+                &mut mp.received_offers,
+                &offer.token_owner,
+                nft_canister_id,
+                &entry,
+            );
+            index_user_entry(&mut mp.received_offers, owner, nft_canister_id, entry);
+            offer.token_owner = *owner;
+        }
+    });
+}
+
 pub(crate) fn remove_listing(nft_canister_id: &Principal, token_id: &Nat) {
     marketplace_mut(|mp| {
         let listings = mp.listings.entry(*nft_canister_id).or_default();
-        listings.remove(token_id);
+        if let Some(listing) = listings.remove(token_id) {
+            unindex_ask(nft_canister_id, token_id, &listing.price, listing.created);
+            unindex_user_entry(
+                &mut mp.seller_listings,
+                &listing.seller,
+                nft_canister_id,
+                token_id,
+            );
+        }
+    });
+    certify_listing(nft_canister_id, token_id);
+}
+
+/// add an entry to a user's index, see `Marketplace.seller_listings` and `received_offers`
+pub(crate) fn index_user_entry<T: PartialEq>(
+    index: &mut HashMap<Principal, HashMap<Principal, Vec<T>>>,
+    user: &Principal,
+    nft_canister_id: &Principal,
+    entry: T,
+) {
+    let entries = index
+        .entry(*user)
+        .or_default()
+        .entry(*nft_canister_id)
+        .or_default();
+    if !entries.contains(&entry) {
+        entries.push(entry);
+    }
+}
+
+/// drop an entry from a user's index, and the user once they have none left
+pub(crate) fn unindex_user_entry<T: PartialEq>(
+    index: &mut HashMap<Principal, HashMap<Principal, Vec<T>>>,
+    user: &Principal,
+    nft_canister_id: &Principal,
+    entry: &T,
+) {
+    if let Some(collections) = index.get_mut(user) {
+        if let Some(entries) = collections.get_mut(nft_canister_id) {
+            entries.retain(|e| e != entry);
+
+            // save storage space
+            if entries.is_empty() {
+                collections.remove(nft_canister_id);
+            }
+        }
+        if collections.is_empty() {
+            index.remove(user);
+        }
+    }
+}
+
+/// rebuild `Marketplace.seller_listings` and `received_offers` from the listings and offers
+pub(crate) fn rebuild_user_indexes() {
+    marketplace_mut(|mp| {
+        let mut seller_listings = HashMap::new();
+        for (nft_canister_id, listings) in mp.listings.iter() {
+            for (token_id, listing) in listings.iter() {
+                if listing.status != ListingStatus::Uninitialized {
+                    index_user_entry(
+                        &mut seller_listings,
+                        &listing.seller,
+                        nft_canister_id,
+                        token_id.clone(),
+                    );
+                }
+            }
+        }
+
+        let mut received_offers = HashMap::new();
+        for (nft_canister_id, offers) in mp.offers.iter() {
+            for (token_id, token_offers) in offers.iter() {
+                for (buyer, offer) in token_offers.iter() {
+                    index_user_entry(
+                        &mut received_offers,
+                        &offer.token_owner,
+                        nft_canister_id,
+                        (token_id.clone(), *buyer),
+                    );
+                }
+            }
+        }
+
+        mp.seller_listings = seller_listings;
+        mp.received_offers = received_offers;
     });
 }
 
@@ -97,6 +378,510 @@ pub(crate) fn inc_volume(nft_canister_id: &Principal, amount: &Nat) {
     });
 }
 
+/// fee totals of `recipient` whose fee label matches `filter`, by collection, fungible and label
+pub(crate) fn recipient_revenue<F: Fn(&str) -> bool>(
+    recipient: &Principal,
+    filter: F,
+) -> Vec<FeeRevenue> {
+    sales_index(|sales_index| {
+        sales_index
+            .recipient_revenue
+            .get(recipient)
+            .into_iter()
+            .flatten()
+            .filter(|((_, _, fee_label), _)| filter(fee_label))
+            .map(
+                |((nft_canister_id, fungible_canister_id, fee_label), amount)| {
+                    FeeRevenue::new(
+                        *nft_canister_id,
+                        *fungible_canister_id,
+                        fee_label.clone(),
+                        *recipient,
+                        amount.clone(),
+                    )
+                },
+            )
+            .collect()
+    })
+}
+
+/// most listings returned per page
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+/// append a completed trade to the sales ledger
+pub(crate) fn log_sale(sale: Sale) {
+    let position = sales_ledger_mut(|sales_ledger| {
+        sales_ledger.sales.push(sale.clone());
+        sales_ledger.sales.len() as u64 - 1
+    });
+    sales_index_mut(|sales_index| index_sale(sales_index, &sale, position));
+}
+
+/// append a credited fee to the fee ledger
+pub(crate) fn log_accrual(accrual: FeeAccrual) {
+    let position = fee_ledger_mut(|fee_ledger| {
+        fee_ledger.accruals.push(accrual.clone());
+        fee_ledger.accruals.len() as u64 - 1
+    });
+    sales_index_mut(|sales_index| index_accrual(sales_index, &accrual, position));
+}
+
+fn index_sale(sales_index: &mut SalesIndex, sale: &Sale, position: u64) {
+    sales_index
+        .token_sales
+        .entry(sale.nft_canister_id)
+        .or_default()
+        .entry(sale.token_id.clone())
+        .or_default()
+        .push(position);
+    sales_index
+        .collection_sales
+        .entry(sale.nft_canister_id)
+        .or_default()
+        .push(position);
+
+    let user_sales = &mut sales_index.user_sales;
+    user_sales.entry(sale.buyer).or_default().push(position);
+    if sale.seller != sale.buyer {
+        user_sales.entry(sale.seller).or_default().push(position);
+    }
+}
+
+fn index_accrual(sales_index: &mut SalesIndex, accrual: &FeeAccrual, position: u64) {
+    sales_index
+        .trade_fees
+        .entry(accrual.trade_id)
+        .or_default()
+        .push(position);
+    sales_index
+        .collection_fees
+        .entry(accrual.nft_canister_id)
+        .or_default()
+        .push(position);
+    sales_index
+        .recipient_fees
+        .entry(accrual.recipient)
+        .or_default()
+        .push(position);
+
+    *sales_index
+        .collection_revenue
+        .entry(accrual.nft_canister_id)
+        .or_default()
+        .entry((
+            accrual.fungible_canister_id,
+            accrual.fee_label.clone(),
+            accrual.recipient,
+        ))
+        .or_insert_with(|| Nat::from(0)) += accrual.amount.clone();
+    *sales_index
+        .recipient_revenue
+        .entry(accrual.recipient)
+        .or_default()
+        .entry((
+            accrual.nft_canister_id,
+            accrual.fungible_canister_id,
+            accrual.fee_label.clone(),
+        ))
+        .or_insert_with(|| Nat::from(0)) += accrual.amount.clone();
+}
+
+/// rebuild the sales index from the sales and fee ledgers
+pub(crate) fn rebuild_sales_index() {
+    let mut sales_index = SalesIndex::default();
+
+    sales_ledger(|sales_ledger| {
+        for (position, sale) in sales_ledger.sales.iter().enumerate() {
+            index_sale(&mut sales_index, sale, position as u64);
+        }
+    });
+    fee_ledger(|fee_ledger| {
+        for (position, accrual) in fee_ledger.accruals.iter().enumerate() {
+            index_accrual(&mut sales_index, accrual, position as u64);
+        }
+    });
+
+    sales_index_mut(|current| *current = sales_index);
+}
+
+/// a page of the sales at `positions` of the sales ledger, newest first, from positions below
+/// `before`
+pub(crate) fn sales_page(positions: &[u64], before: Option<u64>, limit: u64) -> SalesPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    let end = before.map_or(positions.len(), |before| {
+        positions.partition_point(|position| *position < before)
+    });
+    let start = end.saturating_sub(limit);
+
+    sales_ledger(|sales_ledger| {
+        let sales = positions[start..end]
+            .iter()
+            .rev()
+            .map(|position| sales_ledger.sales[*position as usize].clone())
+            .collect();
+        let next = match start {
+            0 => None,
+            _ => Some(positions[start]),
+        };
+
+        SalesPage::new(sales, next)
+    })
+}
+
+/// a page of the revenue of the fee accruals at `positions` of the fee ledger, or of all of them,
+/// that match `filter`. Revenue is summed per fungible and `interval` nanoseconds bucket, newest
+/// bucket first, from buckets starting before `before`
+pub(crate) fn revenue_page<F: Fn(&FeeAccrual) -> bool>(
+    positions: Option<&[u64]>,
+    filter: F,
+    interval: u64,
+    before: Option<u64>,
+    limit: u64,
+) -> RevenuePage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    let before = before.unwrap_or(u64::MAX);
+
+    fee_ledger(|fee_ledger| {
+        let accruals = &fee_ledger.accruals;
+        // accruals are appended as fees are credited, in time order
+        let newest_first: Box<dyn Iterator<Item = &FeeAccrual>> = match positions {
+            Some(positions) => {
+                let end = positions
+                    .partition_point(|position| accruals[*position as usize].time < before);
+                Box::new(
+                    positions[..end]
+                        .iter()
+                        .rev()
+                        .map(|position| &accruals[*position as usize]),
+                )
+            }
+            None => {
+                let end = accruals.partition_point(|accrual| accrual.time < before);
+                Box::new(accruals[..end].iter().rev())
+            }
+        };
+
+        let mut buckets: BTreeMap<(Reverse<u64>, Principal), Nat> = BTreeMap::new();
+        let mut starts = 0;
+        let mut next = None;
+        for accrual in newest_first.filter(|accrual| filter(accrual)) {
+            let start = accrual.time - accrual.time % interval;
+            let oldest = buckets.keys().next_back().map(|(Reverse(start), _)| *start);
+            if oldest != Some(start) {
+                if starts == limit {
+                    next = oldest;
+                    break;
+                }
+                starts += 1;
+            }
+
+            *buckets
+                .entry((Reverse(start), accrual.fungible_canister_id))
+                .or_insert_with(|| Nat::from(0)) += accrual.amount.clone();
+        }
+
+        let buckets = buckets
+            .into_iter()
+            .map(|((Reverse(start), fungible_canister_id), amount)| {
+                RevenueBucket::new(start, fungible_canister_id, amount)
+            })
+            .collect();
+
+        RevenuePage::new(buckets, next)
+    })
+}
+
+/// a page of listings matching `filter`, of one collection or of all of them
+pub(crate) fn listings_page(
+    nft_canister_id: Option<Principal>,
+    filter: &ListingFilter,
+    sort: ListingSort,
+    cursor: Option<ListingCursor>,
+    limit: u64,
+) -> ListingsPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+
+    // one listing past the page tells whether there is a next one
+    let mut listings: Vec<ListingEntry> = marketplace(|mp| {
+        let listing = |collection: &Principal, token_id: &Nat| {
+            mp.listings
+                .get(collection)
+                .and_then(|listings| listings.get(token_id))
+        };
+
+        let matching = |collection: &Principal, token_id: &Nat| {
+            listing(collection, token_id).map_or(false, |listing| {
+                listing.status != ListingStatus::Uninitialized && filter.matches(listing)
+            })
+        };
+
+        ordered_listings(nft_canister_id, sort, cursor.as_ref(), limit + 1, matching)
+            .into_iter()
+            .filter_map(|(collection, token_id)| {
+                let listing = listing(&collection, &token_id)?.clone();
+                Some(ListingEntry::new(collection, token_id, listing))
+            })
+            .collect()
+    });
+
+    let next = match listings.len() > limit {
+        true => {
+            listings.truncate(limit);
+            listings.last().map(|entry| {
+                ListingCursor::new(
+                    entry.listing.price.clone(),
+                    entry.listing.created,
+                    entry.nft_canister_id,
+                    entry.token_id.clone(),
+                )
+            })
+        }
+        false => None,
+    };
+
+    ListingsPage::new(listings, next)
+}
+
+pub(crate) fn accounts_mut<T, F: FnOnce(&mut HashMap<AccountIdentifier, Principal>) -> T>(
+    f: F,
+) -> T {
+    ACCOUNTS.with(|accounts| f(&mut accounts.borrow_mut()))
+}
+
+pub(crate) fn accounts<T, F: FnOnce(&HashMap<AccountIdentifier, Principal>) -> T>(f: F) -> T {
+    ACCOUNTS.with(|accounts| f(&accounts.borrow()))
+}
+
+pub(crate) fn icrc_subaccounts_mut<T, F: FnOnce(&mut HashMap<Principal, Subaccount>) -> T>(
+    f: F,
+) -> T {
+    ICRC_SUBACCOUNTS.with(|icrc_subaccounts| f(&mut icrc_subaccounts.borrow_mut()))
+}
+
+pub(crate) fn icrc_subaccounts<T, F: FnOnce(&HashMap<Principal, Subaccount>) -> T>(f: F) -> T {
+    ICRC_SUBACCOUNTS.with(|icrc_subaccounts| f(&icrc_subaccounts.borrow()))
+}
+
+pub(crate) fn breakers_mut<T, F: FnOnce(&mut Breakers) -> T>(f: F) -> T {
+    BREAKERS.with(|breakers| f(&mut breakers.borrow_mut()))
+}
+
+pub(crate) fn breakers<T, F: FnOnce(&Breakers) -> T>(f: F) -> T {
+    BREAKERS.with(|breakers| f(&breakers.borrow()))
+}
+
+pub(crate) fn token_cache_mut<T, F: FnOnce(&mut TokenCache) -> T>(f: F) -> T {
+    TOKEN_CACHE.with(|token_cache| f(&mut token_cache.borrow_mut()))
+}
+
+pub(crate) fn token_cache<T, F: FnOnce(&TokenCache) -> T>(f: F) -> T {
+    TOKEN_CACHE.with(|token_cache| f(&token_cache.borrow()))
+}
+
+pub(crate) fn order_books_mut<T, F: FnOnce(&mut OrderBooks) -> T>(f: F) -> T {
+    ORDER_BOOKS.with(|order_books| f(&mut order_books.borrow_mut()))
+}
+
+pub(crate) fn order_books<T, F: FnOnce(&OrderBooks) -> T>(f: F) -> T {
+    ORDER_BOOKS.with(|order_books| f(&order_books.borrow()))
+}
+
+pub(crate) fn stats_mut<T, F: FnOnce(&mut MarketStats) -> T>(f: F) -> T {
+    STATS.with(|stats| f(&mut stats.borrow_mut()))
+}
+
+pub(crate) fn stats<T, F: FnOnce(&MarketStats) -> T>(f: F) -> T {
+    STATS.with(|stats| f(&stats.borrow()))
+}
+
+pub(crate) fn certified_tree_mut<T, F: FnOnce(&mut RbTree<Vec<u8>, Vec<u8>>) -> T>(f: F) -> T {
+    CERTIFIED_TREE.with(|certified_tree| f(&mut certified_tree.borrow_mut()))
+}
+
+pub(crate) fn certified_tree<T, F: FnOnce(&RbTree<Vec<u8>, Vec<u8>>) -> T>(f: F) -> T {
+    CERTIFIED_TREE.with(|certified_tree| f(&certified_tree.borrow()))
+}
+
+/// remember a principal's default account identifier, so EXT owners can be mapped back to it
+///
+/// only called once an update has changed state, so failed calls do not grow the map
+pub(crate) fn remember_account(principal: &Principal) {
+    let account = account_identifier(principal, None);
+    accounts_mut(|accounts| {
+        accounts.entry(account).or_insert(*principal);
+    });
+}
+
+/// map an account identifier back to the principal it belongs to, if marketplace has seen it
+pub(crate) fn principal_of_account(account: &AccountIdentifier) -> Option<Principal> {
+    // normalize, and skip the lookup for malformed identifiers
+    let account = encode_hex(&decode_account_identifier(account).ok()?);
+    accounts(|accounts| accounts.get(&account).cloned())
+}
+
+/// resolve an EXT user to a principal, where possible
+pub(crate) fn principal_of_user(user: &User) -> Option<Principal> {
+    match user {
+        User::principal(principal) => Some(*principal),
+        User::address(account) => principal_of_account(account),
+    }
+}
+
+/// hex account identifier of a principal and subaccount, as used by EXT and the ICP ledger
+pub fn account_identifier(principal: &Principal, subaccount: Option<[u8; 32]>) -> AccountIdentifier {
+    encode_hex(&account_identifier_bytes(principal, subaccount))
+}
+
+/// sha224("\x0Aaccount-id" + principal + subaccount), prefixed with its big endian crc32
+pub fn account_identifier_bytes(principal: &Principal, subaccount: Option<[u8; 32]>) -> [u8; 32] {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(principal.as_slice());
+    hasher.update(&subaccount.unwrap_or([0; 32]));
+    let hash = hasher.finalize();
+
+    let mut bytes = [0; 32];
+    bytes[..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+    bytes[4..].copy_from_slice(&hash);
+    bytes
+}
+
+/// subaccount of marketplace a user deposits ICP into before buying or making offers
+///
+/// length prefixed principal bytes, zero padded to 32 bytes
+pub fn deposit_subaccount(principal: &Principal) -> [u8; 32] {
+    let bytes = principal.as_slice();
+
+    let mut subaccount = [0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+/// decode a hex account identifier into its 32 bytes, verifying the crc32 checksum
+pub fn decode_account_identifier(account: &str) -> Result<[u8; 32], MPApiError> {
+    let bytes = decode_hex(account)
+        .filter(|bytes| bytes.len() == 32)
+        .ok_or_else(|| MPApiError::Other(format!("invalid account identifier {}", account)))?;
+
+    if crc32fast::hash(&bytes[4..]).to_be_bytes() != bytes[..4] {
+        return Err(MPApiError::Other(format!(
+            "invalid account identifier checksum {}",
+            account
+        )));
+    }
+
+    let mut decoded = [0; 32];
+    decoded.copy_from_slice(&bytes);
+    Ok(decoded)
+}
+
+/// EXT token identifier for a token index of a collection
+///
+/// principal text encoding of "\x0Atid" + nft canister id + big endian u32 index
+pub fn ext_token_identifier(
+    nft_canister_id: &Principal,
+    token_id: &Nat,
+) -> Result<TokenIdentifier, MPApiError> {
+    let index = convert_nat_to_u32(token_id.clone()).map_err(MPApiError::Other)?;
+
+    let mut bytes = b"\x0Atid".to_vec();
+    bytes.extend_from_slice(nft_canister_id.as_slice());
+    bytes.extend_from_slice(&index.to_be_bytes());
+
+    Ok(Principal::from_slice(&bytes).to_text())
+}
+
+/// decode an EXT token identifier into its nft canister id and token index
+///
+/// the checksum is verified by the principal text decoding
+pub fn decode_ext_token_identifier(token: &TokenIdentifier) -> Result<(Principal, Nat), MPApiError> {
+    let invalid = || MPApiError::Other(format!("invalid token identifier {}", token));
+
+    let principal = Principal::from_text(token).map_err(|_| invalid())?;
+    let bytes = principal.as_slice();
+
+    if bytes.len() < 8 || !bytes.starts_with(b"\x0Atid") {
+        return Err(invalid());
+    }
+
+    let (canister, index) = bytes[4..].split_at(bytes.len() - 8);
+    let mut index_bytes = [0; 4];
+    index_bytes.copy_from_slice(index);
+
+    Ok((
+        Principal::from_slice(canister),
+        Nat::from(u32::from_be_bytes(index_bytes)),
+    ))
+}
+
+/// token index of an EXT token identifier, checking it belongs to the given collection
+pub fn ext_token_index(
+    nft_canister_id: &Principal,
+    token: &TokenIdentifier,
+) -> Result<Nat, MPApiError> {
+    let (canister, index) = decode_ext_token_identifier(token)?;
+
+    if &canister != nft_canister_id {
+        return Err(MPApiError::Other(format!(
+            "token identifier {} does not belong to {}",
+            token, nft_canister_id
+        )));
+    }
+
+    Ok(index)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// a call to `method` of `canister` failed, either rejected or answered with an error
+pub(crate) fn call_error(
+    canister: &Principal,
+    method: &str,
+    error: impl Into<CanisterError>,
+) -> MPApiError {
+    MPApiError::CanisterCallError {
+        canister: *canister,
+        method: method.to_string(),
+        error: error.into(),
+    }
+}
+
+/// a canister did not answer a probe the way its registered standard should
+pub(crate) fn standard_mismatch(
+    contract: &Principal,
+    standard: impl Into<TokenStandard>,
+    reason: impl Into<MismatchReason>,
+) -> MPApiError {
+    MPApiError::StandardMismatch {
+        canister: *contract,
+        standard: standard.into(),
+        reason: reason.into(),
+    }
+}
+
+/// CAP detail of a nat, eg; a token id or price. CAP has no nat type, so values that do not fit
+/// a u64 are recorded as decimal text instead
+pub fn nat_detail(num: Nat) -> DetailValue {
+    match convert_nat_to_u64(num.clone()) {
+        Ok(num) => DetailValue::U64(num),
+        Err(_) => DetailValue::Text(num.0.to_string()),
+    }
+}
+
 pub fn convert_nat_to_u64(num: Nat) -> Result<u64, String> {
     let u64_digits = num.0.to_u64_digits();
 
@@ -129,3 +914,103 @@ pub fn convert_int_to_u64(num: Int) -> Result<u64, String> {
         _ => Err("Int -> Nat64 conversion failed".to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(text: &str) -> Principal {
+        Principal::from_text(text).unwrap()
+    }
+
+    #[test]
+    fn account_identifier_known_vectors() {
+        assert_eq!(
+            account_identifier(&Principal::anonymous(), None),
+            "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        );
+        assert_eq!(
+            account_identifier(&principal("ryjl3-tyaaa-aaaaa-aaaba-cai"), None),
+            "883eef7c44be51afe4a4420d4df4beff708f3cf2f5de5efcc9f58680bb0f3690"
+        );
+
+        let mut subaccount = [0; 32];
+        subaccount[0] = 1;
+        assert_eq!(
+            account_identifier(&Principal::anonymous(), Some(subaccount)),
+            "1fba27d8ddf404c7ddc16c083871679913fa835443e15090e17ee382e7d8bbbe"
+        );
+    }
+
+    #[test]
+    fn account_identifiers_are_decoded_with_their_checksum() {
+        let account = "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79";
+        assert_eq!(
+            decode_account_identifier(account).unwrap(),
+            account_identifier_bytes(&Principal::anonymous(), None)
+        );
+        assert_eq!(
+            decode_account_identifier(&account.to_uppercase()).unwrap(),
+            account_identifier_bytes(&Principal::anonymous(), None)
+        );
+
+        // flipped checksum, truncated, and not hex
+        assert!(decode_account_identifier(
+            "2c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        )
+        .is_err());
+        assert!(decode_account_identifier(&account[..62]).is_err());
+        assert!(decode_account_identifier(&account.replace('c', "z")).is_err());
+    }
+
+    #[test]
+    fn ext_token_identifier_known_vectors() {
+        let collection = principal("bxdf4-baaaa-aaaah-qaruq-cai");
+        let vectors = [
+            (0, "wt2h5-zakor-uwiaa-aaaaa-b4aen-eaqca-aaaaa-a"),
+            (1, "ypzu4-4qkor-uwiaa-aaaaa-b4aen-eaqca-aaaaa-q"),
+            (u32::MAX, "njhv4-bykor-uwiaa-aaaaa-b4aen-eaqd7-77777-q"),
+        ];
+
+        for (index, token) in vectors.iter() {
+            assert_eq!(
+                ext_token_identifier(&collection, &Nat::from(*index)).unwrap(),
+                *token
+            );
+            assert_eq!(
+                decode_ext_token_identifier(&token.to_string()).unwrap(),
+                (collection, Nat::from(*index))
+            );
+        }
+
+        // EXT indexes are u32
+        assert!(ext_token_identifier(&collection, &Nat::from(u32::MAX as u64 + 1)).is_err());
+    }
+
+    #[test]
+    fn malformed_ext_token_identifiers_are_rejected() {
+        let malformed = [
+            // checksum does not match
+            "wt2h5-zakor-uwiaa-aaaaa-b4aen-eaqca-aaaaa-q",
+            // a canister id, not a token identifier
+            "bxdf4-baaaa-aaaah-qaruq-cai",
+            "",
+            "not a token",
+        ];
+
+        for token in malformed.iter() {
+            assert!(decode_ext_token_identifier(&token.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn ext_tokens_of_foreign_canisters_are_rejected() {
+        let token = "wt2h5-zakor-uwiaa-aaaaa-b4aen-eaqca-aaaaa-a".to_string();
+
+        assert_eq!(
+            ext_token_index(&principal("bxdf4-baaaa-aaaah-qaruq-cai"), &token).unwrap(),
+            Nat::from(0)
+        );
+        assert!(ext_token_index(&principal("rrkah-fqaaa-aaaaa-aaaaq-cai"), &token).is_err());
+    }
+}
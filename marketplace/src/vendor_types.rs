@@ -40,7 +40,17 @@ pub struct TokenMetadata {
     pub burned_by: Option<Principal>,
 }
 
-#[derive(CandidType, Debug, Deserialize)]
+#[derive(CandidType, Clone, Deserialize, Debug)]
+pub struct Dip721Metadata {
+    pub logo: Option<String>,
+    pub name: Option<String>,
+    pub created_at: u64,
+    pub upgraded_at: u64,
+    pub custodians: Vec<Principal>,
+    pub symbol: Option<String>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
 pub enum ApiError {
     Unauthorized,
     InvalidTokenId,
@@ -48,7 +58,7 @@ pub enum ApiError {
     Other,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Clone, Deserialize, Debug)]
 pub enum NftError {
     UnauthorizedOwner,
     UnauthorizedOperator,
@@ -65,8 +75,66 @@ pub enum NftError {
 pub type TxReceiptDIP721v2 = Result<Nat, ApiError>;
 pub type OwnerResult = Result<Principal, NftError>;
 
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum SupportedInterface {
+    Approval,
+    Mint,
+    Burn,
+    TransactionHistory,
+}
+
 // END DIP721v2 //
 
+// BEGIN DIP721v1 //
+
+// v1 shares `ApiError` with the receipts above, token ids are nat64
+pub type TxReceiptDIP721v1 = Result<Nat, ApiError>;
+pub type OwnerResultDIP721v1 = Result<Principal, ApiError>;
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum InterfaceId {
+    Approval,
+    TransactionHistory,
+    Mint,
+    Burn,
+    TransferNotification,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum MetadataPurpose {
+    Preview,
+    Rendered,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum MetadataVal {
+    TextContent(String),
+    BlobContent(Vec<u8>),
+    NatContent(Nat),
+    Nat8Content(u8),
+    Nat16Content(u16),
+    Nat32Content(u32),
+    Nat64Content(u64),
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct MetadataKeyVal {
+    pub key: String,
+    pub val: MetadataVal,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct MetadataPart {
+    pub purpose: MetadataPurpose,
+    pub key_val_data: Vec<MetadataKeyVal>,
+    pub data: Vec<u8>,
+}
+
+pub type MetadataDesc = Vec<MetadataPart>;
+pub type MetadataResultDIP721v1 = Result<MetadataDesc, ApiError>;
+
+// END DIP721v1 //
+
 #[derive(CandidType, Clone, Deserialize)]
 pub struct BalanceMetadata {
     pub owner: Principal,
@@ -80,8 +148,10 @@ pub struct BalanceMetadata {
 
 // BEGIN EXT //
 
+// hex encoded crc32 + sha224 of a principal and subaccount
 pub type AccountIdentifier = String;
-pub type TokenIdentifier = u64;
+// principal text encoding of the nft canister id and the token index
+pub type TokenIdentifier = String;
 pub type Balance = Nat;
 pub type Blob = Vec<u8>;
 pub type Memo = Blob;
@@ -104,7 +174,7 @@ pub struct TransferRequest {
     pub subaccount: Option<SubAccount>,
 }
 
-#[derive(CandidType, Debug, Deserialize, PartialEq)]
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
 pub enum TransferResponseErrors {
     Unauthorized(AccountIdentifier),
     InsufficientBalance,
@@ -116,11 +186,45 @@ pub enum TransferResponseErrors {
 
 pub type TransferResponse = Result<Balance, TransferResponseErrors>;
 
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum CommonError {
+    InvalidToken(TokenIdentifier),
+    Other(String),
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub struct AllowanceRequest {
+    pub owner: User,
+    pub spender: Principal,
+    pub token: TokenIdentifier,
+}
+
+pub type BearerResponse = Result<AccountIdentifier, CommonError>;
+pub type AllowanceResponse = Result<Balance, CommonError>;
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum ExtMetadata {
+    fungible {
+        name: String,
+        symbol: String,
+        decimals: u8,
+        metadata: Option<Blob>,
+    },
+    nonfungible {
+        metadata: Option<Blob>,
+    },
+}
+
+pub type MetadataResponse = Result<ExtMetadata, CommonError>;
+
+// eg; `@ext/common`, `@ext/allowance`, `@ext/nonfungible`
+pub type Extension = String;
+
 // END EXT //
 
 // BEGIN DIP20 //
 
-#[derive(CandidType, Debug, Deserialize, Eq, PartialEq)]
+#[derive(CandidType, Clone, Debug, Deserialize, Eq, PartialEq)]
 pub enum TxError {
     InsufficientAllowance,
     InsufficientBalance,
@@ -134,4 +238,232 @@ pub enum TxError {
 }
 pub type TxReceipt = Result<Nat, TxError>;
 
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Dip20Metadata {
+    pub logo: String,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub totalSupply: Nat,
+    pub owner: Principal,
+    pub fee: Nat,
+}
+
 // END DIP20 //
+
+// BEGIN ICRC1 / ICRC2 //
+
+pub type Subaccount = Vec<u8>;
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Blob>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Blob>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+pub type TransferResult = Result<Nat, TransferError>;
+pub type TransferFromResult = Result<Nat, TransferFromError>;
+
+// also answered by ICRC10 `icrc10_supported_standards`, eg; for ICRC7 collections
+#[derive(CandidType, Debug, Deserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+// END ICRC1 / ICRC2 //
+
+// BEGIN ICP ledger //
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct IcpTransferArgs {
+    pub memo: u64,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<Blob>,
+    // 32 byte account identifier
+    pub to: Blob,
+    pub created_at_time: Option<TimeStamp>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum IcpTransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct AccountBalanceArgs {
+    pub account: Blob,
+}
+
+pub type IcpTransferResult = Result<u64, IcpTransferError>;
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferFeeArg {}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferFee {
+    pub transfer_fee: Tokens,
+}
+
+// END ICP ledger //
+
+// BEGIN ICRC7 / ICRC37 //
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Icrc7TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Blob>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Icrc37TransferFromArg {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Blob>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum Icrc37TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum Icrc7Value {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Blob),
+    Array(Vec<Icrc7Value>),
+    Map(Vec<(String, Icrc7Value)>),
+}
+
+// batch endpoints answer one entry per request entry, `None` when it was not processed
+pub type Icrc7TransferResult = Vec<Option<Result<Nat, Icrc7TransferError>>>;
+pub type Icrc37TransferFromResult = Vec<Option<Result<Nat, Icrc37TransferFromError>>>;
+pub type Icrc7TokenMetadataResult = Vec<Option<Vec<(String, Icrc7Value)>>>;
+
+// END ICRC7 / ICRC37 //
+
+// BEGIN http gateway //
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// END http gateway //